@@ -20,7 +20,34 @@ CREATE TABLE IF NOT EXISTS cookie_traffic (
     domain TEXT,
     cookie TEXT,
     timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
-    blocked BOOLEAN
+    blocked BOOLEAN,
+    name TEXT,
+    direction TEXT,
+    site TEXT,
+    vendor TEXT,
+    category TEXT,
+    username TEXT
+);
+
+CREATE TABLE IF NOT EXISTS cookie_rewrites (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    domain TEXT,
+    name TEXT,
+    changes TEXT,
+    timestamp DATETIME DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS cookie_jar (
+    site TEXT,
+    domain TEXT,
+    name TEXT,
+    path TEXT,
+    value TEXT,
+    host_only BOOLEAN,
+    secure BOOLEAN,
+    expires INTEGER,
+    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (site, domain, name, path)
 );
 
 CREATE TABLE IF NOT EXISTS fingerprint_rotations (
@@ -28,7 +55,8 @@ CREATE TABLE IF NOT EXISTS fingerprint_rotations (
     timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
     user_agent TEXT,
     accept_language TEXT,
-    mode TEXT
+    mode TEXT,
+    username TEXT
 );
 
 CREATE TABLE IF NOT EXISTS request_log (
@@ -37,10 +65,70 @@ CREATE TABLE IF NOT EXISTS request_log (
     path TEXT,
     timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
     user_agent TEXT,
-    client_ip TEXT
+    client_ip TEXT,
+    client_port INTEGER,
+    method TEXT,
+    scheme TEXT,
+    via_connect INTEGER DEFAULT 0,
+    blocked INTEGER DEFAULT 0,
+    status INTEGER,
+    content_type TEXT,
+    response_bytes INTEGER,
+    latency_ms INTEGER,
+    username TEXT,
+    route TEXT
+);
+
+CREATE TABLE IF NOT EXISTS tunnel_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    domain TEXT,
+    port INTEGER,
+    timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
+    client_ip TEXT,
+    client_port INTEGER,
+    username TEXT,
+    route TEXT,
+    reason TEXT,
+    blocked INTEGER DEFAULT 0,
+    bytes_sent INTEGER,
+    bytes_received INTEGER,
+    duration_ms INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS learned_passthrough (
+    host TEXT PRIMARY KEY,
+    status TEXT,
+    failures INTEGER DEFAULT 0,
+    learned_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    reviewed_at DATETIME
+);
+
+CREATE TABLE IF NOT EXISTS stripped_params (
+    param TEXT,
+    domain TEXT,
+    hit_count INTEGER DEFAULT 0,
+    last_seen DATETIME DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (param, domain)
+);
+
+CREATE TABLE IF NOT EXISTS cache_validators (
+    domain TEXT,
+    header TEXT,
+    hit_count INTEGER DEFAULT 0,
+    last_seen DATETIME DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (domain, header)
+);
+
+CREATE TABLE IF NOT EXISTS rejected_clients (
+    client_ip TEXT,
+    listener TEXT,
+    hit_count INTEGER DEFAULT 0,
+    last_seen DATETIME DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (client_ip, listener)
 );
 
 CREATE TABLE IF NOT EXISTS whitelist (
     domain TEXT PRIMARY KEY,
-    reason TEXT
+    reason TEXT,
+    added_at DATETIME DEFAULT CURRENT_TIMESTAMP
 );
@@ -0,0 +1,69 @@
+#!/bin/bash
+set -e
+
+# Creates a client network namespace whose web traffic is redirected to
+# BlankTrace's transparent listener, to try transparent mode on one Linux box.
+# Needs root, iproute2 and iptables.
+#
+#   sudo ./scripts/transparent-netns.sh up
+#   blanktrace ca export --output blanktrace-ca.pem
+#   sudo ip netns exec bt-client curl --cacert blanktrace-ca.pem https://example.com/
+#   sudo ./scripts/transparent-netns.sh down
+#
+# BlankTrace must listen on the host end of the veth pair:
+#
+#   listen:
+#     transparent_addresses: ["10.200.0.1:8443"]
+#     allow_clients: ["10.200.0.0/24"]
+#     allow_remote: true
+
+NS=bt-client
+HOST_IF=bt-host
+NS_IF=bt-ns
+HOST_IP=10.200.0.1
+NS_IP=10.200.0.2
+PORT=${PORT:-8443}
+DNS=${DNS:-1.1.1.1}
+# ip_forward before `up`, restored by `down`
+FORWARD_SAVED=/run/$NS.ip_forward
+
+# Web traffic from the namespace goes to the transparent listener
+REDIRECT_RULE=(PREROUTING -i "$HOST_IF" -p tcp -m multiport --dports 80,443 -j REDIRECT --to-ports "$PORT")
+# Everything else, DNS included, is masqueraded out
+MASQUERADE_RULE=(POSTROUTING -s "$NS_IP/32" ! -o "$HOST_IF" -j MASQUERADE)
+
+case "$1" in
+    up)
+        ip netns add "$NS"
+        ip link add "$HOST_IF" type veth peer name "$NS_IF"
+        ip link set "$NS_IF" netns "$NS"
+        ip addr add "$HOST_IP/24" dev "$HOST_IF"
+        ip link set "$HOST_IF" up
+        ip netns exec "$NS" ip addr add "$NS_IP/24" dev "$NS_IF"
+        ip netns exec "$NS" ip link set "$NS_IF" up
+        ip netns exec "$NS" ip link set lo up
+        ip netns exec "$NS" ip route add default via "$HOST_IP"
+        mkdir -p "/etc/netns/$NS"
+        echo "nameserver $DNS" > "/etc/netns/$NS/resolv.conf"
+        sysctl -n net.ipv4.ip_forward > "$FORWARD_SAVED"
+        sysctl -qw net.ipv4.ip_forward=1
+        iptables -t nat -A "${REDIRECT_RULE[@]}"
+        iptables -t nat -A "${MASQUERADE_RULE[@]}"
+        echo "Namespace $NS ready; run clients with: ip netns exec $NS <command>"
+        ;;
+    down)
+        iptables -t nat -D "${REDIRECT_RULE[@]}" 2>/dev/null || true
+        iptables -t nat -D "${MASQUERADE_RULE[@]}" 2>/dev/null || true
+        ip link del "$HOST_IF" 2>/dev/null || true
+        ip netns del "$NS" 2>/dev/null || true
+        rm -rf "/etc/netns/$NS"
+        if [ -f "$FORWARD_SAVED" ]; then
+            sysctl -qw net.ipv4.ip_forward="$(cat "$FORWARD_SAVED")"
+            rm -f "$FORWARD_SAVED"
+        fi
+        ;;
+    *)
+        echo "Usage: $0 up|down"
+        exit 1
+        ;;
+esac
@@ -0,0 +1,317 @@
+use crate::config::Config;
+use anyhow::{bail, Context};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+
+/// Failed attempts a client gets before it has to back off.
+const FREE_ATTEMPTS: u32 = 3;
+/// Longest backoff; failures older than this are forgotten.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Checked instead of a missing user's hash when no bcrypt user is configured.
+const DUMMY_SHA_HASH: &str = "{SHA}AAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+/// `Proxy-Authorization: Basic` authentication against hashed passwords.
+///
+/// Supported hashes are bcrypt (`$2y$...`, as written by `htpasswd -B`) and
+/// `{SHA}` (`htpasswd -s`).
+pub struct ProxyAuth {
+    enabled: bool,
+    realm: String,
+    /// Password hash by username.
+    users: HashMap<String, String>,
+    /// Users of already verified credentials, keyed by a digest of the header,
+    /// so bcrypt only runs once per credential.
+    verified: Mutex<HashMap<Vec<u8>, String>>,
+    /// Hash checked for unknown users, as costly as the most expensive real
+    /// one, so the time taken does not reveal which users exist.
+    dummy_hash: String,
+    /// Recent failed attempts and the time of the last one, by client.
+    failures: Mutex<HashMap<IpAddr, (u32, Instant)>>,
+}
+
+impl ProxyAuth {
+    /// Creates a new ProxyAuth from the configured users and htpasswd file.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg` - Application configuration.
+    pub fn new(cfg: &Config) -> anyhow::Result<Self> {
+        let mut users: Vec<(String, String)> = cfg
+            .auth
+            .users
+            .iter()
+            .map(|u| (u.name.clone(), u.password_hash.clone()))
+            .collect();
+        if let Some(path) = &cfg.auth.htpasswd_file {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read htpasswd file '{}'", path))?;
+            users.extend(parse_htpasswd(&content));
+        }
+        if cfg.auth.enabled && users.is_empty() {
+            bail!("proxy authentication is enabled but no users are configured");
+        }
+        Self::from_users(cfg.auth.enabled, &cfg.auth.realm, users)
+    }
+
+    /// Creates a ProxyAuth from (username, password hash) pairs.
+    pub fn from_users(
+        enabled: bool,
+        realm: &str,
+        users: Vec<(String, String)>,
+    ) -> anyhow::Result<Self> {
+        for (name, hash) in &users {
+            if !is_supported_hash(hash) {
+                bail!(
+                    "unsupported password hash for user '{}': use bcrypt or {{SHA}}",
+                    name
+                );
+            }
+        }
+        let cost = users
+            .iter()
+            .filter_map(|(_, hash)| hash.parse::<bcrypt::HashParts>().ok())
+            .map(|parts| parts.get_cost())
+            .max();
+        let dummy_hash = match cost {
+            Some(cost) => bcrypt::hash_with_salt("", cost, [0; 16])
+                .context("failed to create the dummy password hash")?
+                .to_string(),
+            None => DUMMY_SHA_HASH.to_string(),
+        };
+        Ok(Self {
+            enabled,
+            realm: realm.to_string(),
+            users: users.into_iter().collect(),
+            verified: Mutex::new(HashMap::new()),
+            dummy_hash,
+            failures: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Whether clients have to authenticate.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Value of the `Proxy-Authenticate` header sent with `407` responses.
+    pub fn challenge(&self) -> String {
+        format!("Basic realm=\"{}\"", self.realm.replace('"', ""))
+    }
+
+    /// Checks a `Proxy-Authorization` header value sent by `client`.
+    ///
+    /// Returns the authenticated username, or None for missing or invalid
+    /// credentials.
+    pub async fn authenticate(&self, client: IpAddr, header: Option<&str>) -> Option<String> {
+        let header = header?.trim();
+        let key = Sha256::digest(header.as_bytes()).to_vec();
+        if let Some(user) = self.verified.lock().unwrap().get(&key) {
+            return Some(user.clone());
+        }
+
+        let (scheme, encoded) = header.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+        let (name, password) = decoded.split_once(':')?;
+        self.check(client, key, name, password).await
+    }
+
+    /// Checks a username and password, as sent by SOCKS5 clients.
+    ///
+    /// Returns the authenticated username, or None for invalid credentials.
+    pub async fn authenticate_password(
+        &self,
+        client: IpAddr,
+        name: &str,
+        password: &str,
+    ) -> Option<String> {
+        let mut hasher = Sha256::new();
+        hasher.update(name.as_bytes());
+        hasher.update([0]);
+        hasher.update(password.as_bytes());
+        let key = hasher.finalize().to_vec();
+        if let Some(user) = self.verified.lock().unwrap().get(&key) {
+            return Some(user.clone());
+        }
+        self.check(client, key, name, password).await
+    }
+
+    /// Verifies credentials off the async workers, refusing clients that are
+    /// backing off after repeated failures.
+    async fn check(
+        &self,
+        client: IpAddr,
+        key: Vec<u8>,
+        name: &str,
+        password: &str,
+    ) -> Option<String> {
+        if self.backing_off(client) {
+            return None;
+        }
+        let known = self.users.get(name);
+        let hash = known.unwrap_or(&self.dummy_hash).clone();
+        let password = password.to_string();
+        let valid = tokio::task::spawn_blocking(move || verify(&hash, &password))
+            .await
+            .unwrap_or(false);
+        if !valid || known.is_none() {
+            self.record_failure(client);
+            return None;
+        }
+        self.failures.lock().unwrap().remove(&client);
+        self.verified.lock().unwrap().insert(key, name.to_string());
+        Some(name.to_string())
+    }
+
+    /// Whether `client` failed too often to be checked yet. Each failure past
+    /// the free ones doubles the wait, up to `MAX_BACKOFF`.
+    fn backing_off(&self, client: IpAddr) -> bool {
+        let failures = self.failures.lock().unwrap();
+        let Some(&(count, last)) = failures.get(&client) else {
+            return false;
+        };
+        if count < FREE_ATTEMPTS {
+            return false;
+        }
+        let wait = Duration::from_secs(1 << (count - FREE_ATTEMPTS).min(6)).min(MAX_BACKOFF);
+        last.elapsed() < wait
+    }
+
+    fn record_failure(&self, client: IpAddr) {
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, (_, last)| last.elapsed() < MAX_BACKOFF);
+        let entry = failures.entry(client).or_insert((0, Instant::now()));
+        entry.0 += 1;
+        entry.1 = Instant::now();
+    }
+}
+
+/// Parses `user:hash` lines of an htpasswd file, skipping blanks and comments.
+fn parse_htpasswd(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| l.split_once(':'))
+        .map(|(user, hash)| (user.to_string(), hash.to_string()))
+        .collect()
+}
+
+fn is_supported_hash(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$", "{SHA}"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+}
+
+fn verify(hash: &str, password: &str) -> bool {
+    if let Some(digest) = hash.strip_prefix("{SHA}") {
+        let Ok(digest) = STANDARD.decode(digest) else {
+            return false;
+        };
+        Sha1::digest(password.as_bytes()).ct_eq(&digest).into()
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", STANDARD.encode(credentials))
+    }
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let htpasswd = format!(
+            "# lab users\nalice:{}\nbob:{{SHA}}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n",
+            bcrypt::hash("wonderland", 4).unwrap()
+        );
+        let auth = ProxyAuth::from_users(true, "Lab", parse_htpasswd(&htpasswd)).unwrap();
+
+        assert_eq!(
+            auth.authenticate(CLIENT, Some(&basic("alice:wonderland")))
+                .await,
+            Some("alice".to_string())
+        );
+        // Served from the cache the second time
+        assert_eq!(
+            auth.authenticate(CLIENT, Some(&basic("alice:wonderland")))
+                .await,
+            Some("alice".to_string())
+        );
+        assert_eq!(
+            auth.authenticate(CLIENT, Some(&basic("bob:password")))
+                .await,
+            Some("bob".to_string())
+        );
+        assert_eq!(
+            auth.authenticate(CLIENT, Some(&basic("alice:wrong"))).await,
+            None
+        );
+        assert_eq!(
+            auth.authenticate(CLIENT, Some(&basic("carol:x"))).await,
+            None
+        );
+        assert_eq!(auth.authenticate(CLIENT, Some("Bearer abc")).await, None);
+        assert_eq!(auth.authenticate(CLIENT, None).await, None);
+        assert_eq!(auth.challenge(), "Basic realm=\"Lab\"");
+
+        assert_eq!(
+            auth.authenticate_password(CLIENT, "bob", "password").await,
+            Some("bob".to_string())
+        );
+        assert_eq!(
+            auth.authenticate_password(CLIENT, "bob", "wonderland")
+                .await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failure_backoff() {
+        let users = vec![("bob".to_string(), bcrypt::hash("password", 4).unwrap())];
+        let auth = ProxyAuth::from_users(true, "Lab", users).unwrap();
+        assert!(auth.dummy_hash.starts_with("$2y$04$"));
+
+        for _ in 0..FREE_ATTEMPTS {
+            assert_eq!(
+                auth.authenticate_password(CLIENT, "bob", "guess").await,
+                None
+            );
+        }
+        // Backing off: even the right password is refused for now
+        assert_eq!(
+            auth.authenticate_password(CLIENT, "bob", "password").await,
+            None
+        );
+        // Other clients are not affected
+        let other = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(
+            auth.authenticate_password(other, "bob", "password").await,
+            Some("bob".to_string())
+        );
+        // Unknown users count as failures too
+        for _ in 0..FREE_ATTEMPTS {
+            assert_eq!(auth.authenticate_password(other, "carol", "x").await, None);
+        }
+        assert!(auth.backing_off(other));
+    }
+
+    #[test]
+    fn test_plaintext_password_rejected() {
+        let users = vec![("alice".to_string(), "secret".to_string())];
+        assert!(ProxyAuth::from_users(true, "Lab", users).is_err());
+    }
+}
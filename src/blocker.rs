@@ -1,5 +1,5 @@
 // src/blocker.rs
-use crate::config::Config;
+use crate::config::{BlockingConfig, Config};
 use crate::db::Database;
 use crate::db::LogEvent;
 use regex::RegexSet;
@@ -26,16 +26,44 @@ impl Blocker {
     /// * `db` - Shared database connection.
     /// * `tx` - Channel for logging events.
     pub fn new(cfg: &Config, db: Arc<Mutex<Database>>, tx: Sender<LogEvent>) -> Self {
-        let patterns = RegexSet::new(&cfg.blocking.block_patterns).unwrap();
+        Self::from_blocking(&cfg.blocking, db, tx)
+    }
+
+    /// Creates a Blocker from blocking settings alone.
+    pub fn from_blocking(
+        blocking: &BlockingConfig,
+        db: Arc<Mutex<Database>>,
+        tx: Sender<LogEvent>,
+    ) -> Self {
+        let patterns = RegexSet::new(&blocking.block_patterns).unwrap();
         Self {
             patterns,
             db,
             tx,
-            auto_block: cfg.blocking.auto_block,
-            auto_block_threshold: cfg.blocking.auto_block_threshold,
+            auto_block: blocking.auto_block,
+            auto_block_threshold: blocking.auto_block_threshold,
         }
     }
 
+    /// Checks whether a host matches one of the tracker patterns, without tracking it.
+    pub fn is_tracker(&self, host: &str) -> bool {
+        self.patterns.is_match(host)
+    }
+
+    /// Checks whether the given host is blocked, like `check_and_track` but
+    /// without counting a hit.
+    ///
+    /// Front-ends use this to refuse connections early; the hit is then
+    /// counted once, by whatever goes on to handle the connection.
+    pub async fn is_blocked(&self, host: &str) -> bool {
+        let db = self.db.lock().await;
+        if db.is_whitelisted(host).await.unwrap_or(false) || !self.patterns.is_match(host) {
+            return false;
+        }
+        // If DB fails, default to blocking as check_and_track does
+        db.is_blocked(host).await.unwrap_or(true)
+    }
+
     /// Checks if a request to the given host should be blocked.
     ///
     /// This method also handles:
@@ -74,10 +102,12 @@ impl Blocker {
                     .await;
 
                 // Auto-block logic
-                if !blocked && self.auto_block && hit_count as usize >= self.auto_block_threshold {
-                    if let Ok(_) = db.set_blocked(host, true).await {
-                        blocked = true;
-                    }
+                if !blocked
+                    && self.auto_block
+                    && hit_count as usize >= self.auto_block_threshold
+                    && db.set_blocked(host, true).await.is_ok()
+                {
+                    blocked = true;
                 }
 
                 return blocked;
@@ -117,7 +147,12 @@ impl Blocker {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::{BlockingConfig, CleanupConfig, Config, CookiesConfig, FingerprintConfig};
+    use crate::config::{
+        AuthConfig, BlockingConfig, CaConfig, CacheTrackingConfig, CleanupConfig, Config,
+        CookieHardening, CookieJarConfig, CookiesConfig, EphemeralConfig, FingerprintConfig,
+        ListenConfig, LoggingConfig, PacConfig, QueryParamsConfig, RedirectsConfig, RefererConfig,
+        TlsFallbackConfig, UpstreamConfig,
+    };
 
     fn create_test_config(block_patterns: Vec<String>, auto_block: bool) -> Config {
         Config {
@@ -129,17 +164,38 @@ mod tests {
                 strip_referer: false,
                 accept_languages: vec![],
             },
+            referer: RefererConfig::default(),
             cookies: CookiesConfig {
                 block_all: false,
                 log_attempts: false,
                 allow_list: vec![],
                 block_list: vec![],
+                allow_names: vec![],
+                block_names: vec![],
+                rules: vec![],
+                block_categories: vec![],
+                catalogue_file: None,
+                harden: CookieHardening::default(),
+                jar: CookieJarConfig::default(),
+                ephemeral: EphemeralConfig::default(),
             },
             blocking: BlockingConfig {
                 auto_block,
                 auto_block_threshold: 2,
                 block_patterns,
             },
+            query_params: QueryParamsConfig::default(),
+            redirects: RedirectsConfig::default(),
+            cache_tracking: CacheTrackingConfig::default(),
+            logging: LoggingConfig::default(),
+            listen: ListenConfig::default(),
+            auth: AuthConfig::default(),
+            upstream: UpstreamConfig::default(),
+            pac: PacConfig::default(),
+            no_intercept: vec![],
+            tls_fallback: TlsFallbackConfig::default(),
+            ca: CaConfig::default(),
+            header_rules: vec![],
             cleanup: CleanupConfig::default(),
             port: None,
             db_path: ":memory:".to_string(),
@@ -154,7 +210,7 @@ mod tests {
         let (tx, mut rx) = tokio::sync::mpsc::channel(10);
         
         tokio::spawn(async move {
-            while let Some(_) = rx.recv().await {}
+            while rx.recv().await.is_some() {}
         });
 
         let blocker = Blocker::new(&config, db.clone(), tx.clone());
@@ -172,7 +228,27 @@ mod tests {
         let blocker2 = Blocker::new(&config2, db, tx);
 
         // Should block immediately (threshold 1)
+        assert!(!blocker2.is_blocked("bad.com").await);
         assert!(blocker2.check_and_track("bad.com").await);
+        assert!(blocker2.is_blocked("bad.com").await);
+    }
+
+    #[tokio::test]
+    async fn test_is_blocked_does_not_count() {
+        let mut config = create_test_config(vec![".*tracker.*".to_string()], true);
+        config.blocking.auto_block_threshold = 2;
+        let db = Arc::new(Mutex::new(Database::new(":memory:").unwrap()));
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(async move { while rx.recv().await.is_some() {} });
+        let blocker = Blocker::new(&config, db, tx);
+
+        for _ in 0..3 {
+            assert!(!blocker.is_blocked("tracker.com").await);
+        }
+        // The threshold counts check_and_track hits only
+        assert!(!blocker.check_and_track("tracker.com").await);
+        assert!(blocker.check_and_track("tracker.com").await);
+        assert!(blocker.is_blocked("tracker.com").await);
     }
 
     #[tokio::test]
@@ -182,7 +258,7 @@ mod tests {
         let (tx, mut rx) = tokio::sync::mpsc::channel(10);
         
         tokio::spawn(async move {
-            while let Some(_) = rx.recv().await {}
+            while rx.recv().await.is_some() {}
         });
 
         // Add to whitelist
@@ -0,0 +1,627 @@
+use crate::config::{CaConfig, Config};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use hudsucker::CertificateAuthority;
+use log::{info, warn};
+use p12_keystore::{Certificate, KeyStore, KeyStoreEntry};
+use pkcs8::{pkcs5::pbes2, EncryptedPrivateKeyInfo, PrivateKeyInfo};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+/// File name of the CA certificate inside the CA directory.
+pub const CERT_FILE: &str = "ca_cert.pem";
+/// File name of the CA private key inside the CA directory.
+pub const KEY_FILE: &str = "ca_key.pem";
+/// File name of the key that logged cookie values are hashed with.
+const LOG_KEY_FILE: &str = "log_key";
+/// Length of the log hashing key in bytes.
+const LOG_KEY_LEN: usize = 32;
+
+/// PEM label of a plaintext PKCS#8 key.
+const KEY_LABEL: &str = "PRIVATE KEY";
+/// PEM label of a passphrase-encrypted PKCS#8 key.
+const ENCRYPTED_KEY_LABEL: &str = "ENCRYPTED PRIVATE KEY";
+
+/// PBKDF2-HMAC-SHA256 rounds deriving the key encryption key.
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// Number of per-host certificates hudsucker keeps signed.
+const CACHE_SIZE: usize = 1000;
+
+/// Alias of the certificate in exported PKCS#12 files.
+const P12_ALIAS: &str = "blanktrace ca";
+
+/// The directory holding the interception CA.
+///
+/// The key is written readable by its owner only, and a key other users can
+/// read is refused rather than used. With `ca.encrypt_key` it is stored as
+/// passphrase-encrypted PKCS#8; the passphrase comes from `ca.passphrase_env`,
+/// `ca.passphrase_file` or the terminal, and an encrypted key without one
+/// stops startup instead of being replaced.
+pub struct CaStore {
+    dir: PathBuf,
+    encrypt_key: bool,
+    passphrase_env: String,
+    passphrase_file: Option<PathBuf>,
+    /// Whether the passphrase may be asked for on the terminal.
+    interactive: bool,
+}
+
+impl CaStore {
+    /// Creates a CaStore for `ca.dir`, or the platform data directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg` - Application configuration.
+    pub fn new(cfg: &Config) -> Result<Self> {
+        Self::from_ca(&cfg.ca, std::io::stdin().is_terminal())
+    }
+
+    /// Creates a CaStore from `ca` settings alone.
+    pub fn from_ca(ca: &CaConfig, interactive: bool) -> Result<Self> {
+        let dir = match &ca.dir {
+            Some(dir) => PathBuf::from(dir),
+            None => dirs::data_dir()
+                .context("No data directory on this platform; set ca.dir in config.yaml")?
+                .join("blanktrace"),
+        };
+        Ok(Self {
+            dir,
+            encrypt_key: ca.encrypt_key,
+            passphrase_env: ca.passphrase_env.clone(),
+            passphrase_file: ca.passphrase_file.as_ref().map(PathBuf::from),
+            interactive,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn cert_path(&self) -> PathBuf {
+        self.dir.join(CERT_FILE)
+    }
+
+    pub fn key_path(&self) -> PathBuf {
+        self.dir.join(KEY_FILE)
+    }
+
+    /// Reads the per-install key that logged cookie values are hashed with,
+    /// creating it on first use. It is kept beside the CA key, with the same
+    /// permission checks.
+    pub fn log_key(&self) -> Result<Vec<u8>> {
+        let path = self.dir.join(LOG_KEY_FILE);
+        if path.exists() {
+            check_private_permissions("Log hashing key", &path)?;
+            let key =
+                fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+            if key.len() != LOG_KEY_LEN {
+                bail!(
+                    "{} is not a {}-byte key; remove it to create a new one",
+                    path.display(),
+                    LOG_KEY_LEN
+                );
+            }
+            return Ok(key);
+        }
+        create_private_dir(&self.dir)?;
+        let mut key = vec![0; LOG_KEY_LEN];
+        rand::thread_rng().fill_bytes(&mut key);
+        write_private(&path, &key)?;
+        info!("Created the log hashing key {}", path.display());
+        Ok(key)
+    }
+
+    /// Whether the directory holds a CA.
+    pub fn exists(&self) -> bool {
+        self.cert_path().exists() && self.key_path().exists()
+    }
+
+    /// Loads the CA, creating it on first start.
+    ///
+    /// A CA left in the working directory by earlier versions is moved in,
+    /// so browsers that trust it keep working. Finding only one of the two
+    /// files is an error rather than a reason to overwrite it.
+    pub fn load_or_generate(&self) -> Result<StoredCa> {
+        if self.exists() {
+            info!("Loading CA certificate from {}", self.dir.display());
+            return self.load();
+        }
+        check_pair(&self.cert_path(), &self.key_path())?;
+
+        let (legacy_cert, legacy_key) = (Path::new(CERT_FILE), Path::new(KEY_FILE));
+        check_pair(legacy_cert, legacy_key)?;
+        let ca = if legacy_cert.exists() && legacy_key.exists() {
+            let ca = StoredCa {
+                cert: CaCertificate::read(legacy_cert)?,
+                key_der: self.read_key(legacy_key)?.0,
+            };
+            self.save(&ca, self.encrypt_key)?;
+            fs::remove_file(legacy_key)
+                .with_context(|| format!("Failed to remove {}", legacy_key.display()))?;
+            fs::remove_file(legacy_cert)
+                .with_context(|| format!("Failed to remove {}", legacy_cert.display()))?;
+            warn!(
+                "Moved the CA from the working directory to {}",
+                self.dir.display()
+            );
+            ca
+        } else {
+            info!("Generating new CA certificate");
+            let ca = StoredCa::generate()?;
+            self.save(&ca, self.encrypt_key)?;
+            info!(
+                "Install {} in your browser to trust HTTPS connections",
+                self.cert_path().display()
+            );
+            ca
+        };
+        Ok(ca)
+    }
+
+    /// Loads an existing CA, encrypting a plaintext key if `ca.encrypt_key`
+    /// asks for it.
+    pub fn load(&self) -> Result<StoredCa> {
+        let cert = self.certificate()?;
+        check_private_permissions("CA private key", &self.key_path())?;
+        let (key_der, encrypted) = self.read_key(&self.key_path())?;
+        if self.encrypt_key && !encrypted {
+            self.save_key(&key_der, true)?;
+            warn!("Encrypted the CA key in {}", self.key_path().display());
+        }
+        Ok(StoredCa { cert, key_der })
+    }
+
+    /// Loads the CA certificate alone, without needing the key passphrase.
+    pub fn certificate(&self) -> Result<CaCertificate> {
+        if !self.exists() {
+            bail!(
+                "No CA in {}; start the proxy or run `blanktrace ca rotate` to create one",
+                self.dir.display()
+            );
+        }
+        CaCertificate::read(&self.cert_path())
+    }
+
+    /// Replaces the CA with a newly generated one, encrypting the new key
+    /// if `ca.encrypt_key` is set or the old key was encrypted.
+    pub fn rotate(&self) -> Result<StoredCa> {
+        let encrypt = self.encrypt_key
+            || (self.key_path().exists()
+                && read_pem(&self.key_path())?.tag() == ENCRYPTED_KEY_LABEL);
+        let ca = StoredCa::generate()?;
+        self.save(&ca, encrypt)?;
+        Ok(ca)
+    }
+
+    fn save(&self, ca: &StoredCa, encrypt: bool) -> Result<()> {
+        create_private_dir(&self.dir)?;
+        // Replace the key first: a certificate without its key is refused
+        self.save_key(&ca.key_der, encrypt)?;
+        write_private(&self.cert_path(), ca.cert.pem().as_bytes())?;
+        info!(
+            "CA certificate saved to {} and {}",
+            self.cert_path().display(),
+            self.key_path().display()
+        );
+        Ok(())
+    }
+
+    fn save_key(&self, key_der: &[u8], encrypt: bool) -> Result<()> {
+        let pem = if encrypt {
+            let passphrase = self
+                .passphrase(true)?
+                .ok_or_else(|| self.no_passphrase("The CA key is to be encrypted"))?;
+            pem::Pem::new(ENCRYPTED_KEY_LABEL, encrypt_key(key_der, &passphrase)?)
+        } else {
+            pem::Pem::new(KEY_LABEL, key_der.to_vec())
+        };
+        write_private(&self.key_path(), pem::encode(&pem).as_bytes())
+    }
+
+    /// Reads a key, returning its DER and whether it was encrypted.
+    fn read_key(&self, path: &Path) -> Result<(Vec<u8>, bool)> {
+        let pem = read_pem(path)?;
+        match pem.tag() {
+            KEY_LABEL => Ok((pem.into_contents(), false)),
+            ENCRYPTED_KEY_LABEL => {
+                let passphrase = self.passphrase(false)?.ok_or_else(|| {
+                    self.no_passphrase(&format!("CA key {} is encrypted", path.display()))
+                })?;
+                let key_der = decrypt_key(pem.contents(), &passphrase).with_context(|| {
+                    format!(
+                        "Failed to decrypt CA key {}; wrong passphrase?",
+                        path.display()
+                    )
+                })?;
+                Ok((key_der, true))
+            }
+            tag => bail!("Unexpected {} block in {}", tag, path.display()),
+        }
+    }
+
+    /// The key passphrase from the environment, the passphrase file or the
+    /// terminal, in that order.
+    fn passphrase(&self, confirm: bool) -> Result<Option<String>> {
+        if let Some(passphrase) = std::env::var(&self.passphrase_env)
+            .ok()
+            .filter(|p| !p.is_empty())
+        {
+            return Ok(Some(passphrase));
+        }
+        if let Some(file) = &self.passphrase_file {
+            check_private_permissions("CA passphrase file", file)?;
+            let passphrase = fs::read_to_string(file)
+                .with_context(|| format!("Failed to read {}", file.display()))?;
+            let passphrase = passphrase.trim_end_matches(['\r', '\n']);
+            if passphrase.is_empty() {
+                bail!("CA passphrase file {} is empty", file.display());
+            }
+            return Ok(Some(passphrase.to_string()));
+        }
+        if self.interactive {
+            let passphrase = rpassword::prompt_password("CA key passphrase: ")?;
+            if passphrase.is_empty() {
+                bail!("Empty CA key passphrase");
+            }
+            if confirm && rpassword::prompt_password("Repeat CA key passphrase: ")? != passphrase {
+                bail!("CA key passphrases do not match");
+            }
+            return Ok(Some(passphrase));
+        }
+        Ok(None)
+    }
+
+    fn no_passphrase(&self, reason: &str) -> anyhow::Error {
+        anyhow!(
+            "{} but no passphrase was supplied; set {}, set ca.passphrase_file or run from a terminal",
+            reason,
+            self.passphrase_env
+        )
+    }
+}
+
+/// A CA certificate, DER.
+pub struct CaCertificate {
+    der: Vec<u8>,
+}
+
+/// Certificate details shown by `blanktrace ca show`.
+pub struct CaDetails {
+    pub subject: String,
+    pub not_before: DateTime<Utc>,
+    pub not_after: DateTime<Utc>,
+}
+
+impl CaCertificate {
+    fn read(path: &Path) -> Result<Self> {
+        Ok(Self {
+            der: read_pem(path)?.into_contents(),
+        })
+    }
+
+    pub fn der(&self) -> &[u8] {
+        &self.der
+    }
+
+    pub fn pem(&self) -> String {
+        pem::encode(&pem::Pem::new("CERTIFICATE", self.der.clone()))
+    }
+
+    /// SHA-256 fingerprint of the certificate, as browsers show it.
+    pub fn fingerprint(&self) -> String {
+        Sha256::digest(&self.der)
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+
+    pub fn details(&self) -> Result<CaDetails> {
+        let (_, cert) = x509_parser::parse_x509_certificate(&self.der)
+            .map_err(|e| anyhow!("Invalid CA certificate: {}", e))?;
+        let validity = cert.validity();
+        Ok(CaDetails {
+            subject: cert.subject().to_string(),
+            not_before: timestamp(validity.not_before.timestamp())?,
+            not_after: timestamp(validity.not_after.timestamp())?,
+        })
+    }
+
+    /// Encodes the certificate, without the key, as a PKCS#12 trust store.
+    pub fn to_pkcs12(&self, password: &str) -> Result<Vec<u8>> {
+        let mut store = KeyStore::new();
+        store.add_entry(
+            P12_ALIAS,
+            KeyStoreEntry::Certificate(Certificate::from_der(&self.der)?),
+        );
+        Ok(store.writer(password).write()?)
+    }
+}
+
+/// A CA certificate and its private key.
+pub struct StoredCa {
+    cert: CaCertificate,
+    key_der: Vec<u8>,
+}
+
+impl StoredCa {
+    fn generate() -> Result<Self> {
+        let mut params = rcgen::CertificateParams::new(vec!["BlankTrace CA".to_string()]);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "BlankTrace CA");
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            rcgen::KeyUsagePurpose::KeyCertSign,
+            rcgen::KeyUsagePurpose::CrlSign,
+        ];
+        params.key_pair = Some(rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256)?);
+
+        let cert = rcgen::Certificate::from_params(params)?;
+        Ok(Self {
+            cert: CaCertificate {
+                der: cert.serialize_der()?,
+            },
+            key_der: cert.serialize_private_key_der(),
+        })
+    }
+
+    pub fn certificate(&self) -> &CaCertificate {
+        &self.cert
+    }
+
+    /// Builds the authority signing per-host certificates.
+    pub fn authority(&self) -> Result<CertificateAuthority> {
+        Ok(CertificateAuthority::new(
+            hudsucker::rustls::PrivateKey(self.key_der.clone()),
+            hudsucker::rustls::Certificate(self.cert.der.clone()),
+            CACHE_SIZE,
+        )?)
+    }
+}
+
+fn timestamp(secs: i64) -> Result<DateTime<Utc>> {
+    DateTime::from_timestamp(secs, 0).context("CA certificate validity out of range")
+}
+
+fn read_pem(path: &Path) -> Result<pem::Pem> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    pem::parse(&contents).with_context(|| format!("Invalid PEM file {}", path.display()))
+}
+
+/// Encrypts a PKCS#8 key with PBES2 (PBKDF2-HMAC-SHA256 and AES-256-CBC).
+fn encrypt_key(key_der: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    let mut salt = [0u8; 16];
+    let mut iv = [0u8; 16];
+    rand::rngs::OsRng.fill_bytes(&mut salt);
+    rand::rngs::OsRng.fill_bytes(&mut iv);
+    let params = pbes2::Parameters::pbkdf2_sha256_aes256cbc(PBKDF2_ITERATIONS, &salt, &iv)
+        .map_err(pkcs8::Error::from)?;
+    let document = PrivateKeyInfo::try_from(key_der)?.encrypt_with_params(params, passphrase)?;
+    Ok(document.as_bytes().to_vec())
+}
+
+fn decrypt_key(der: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    let document = EncryptedPrivateKeyInfo::try_from(der)?.decrypt(passphrase)?;
+    Ok(document.as_bytes().to_vec())
+}
+
+/// Fails when only one of a CA certificate and its key exists.
+fn check_pair(cert: &Path, key: &Path) -> Result<()> {
+    for (present, missing) in [(cert, key), (key, cert)] {
+        if present.exists() && !missing.exists() {
+            bail!(
+                "Found {} but not {}; restore it, or remove {} to generate a new CA",
+                present.display(),
+                missing.display(),
+                present.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn check_private_permissions(what: &str, path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .permissions()
+        .mode();
+    if mode & 0o077 != 0 {
+        bail!(
+            "{} {} is accessible by other users (mode {:03o}); restrict it with `chmod 600 {}`",
+            what,
+            path.display(),
+            mode & 0o777,
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_private_permissions(_what: &str, _path: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn create_private_dir(dir: &Path) -> Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder
+        .create(dir)
+        .with_context(|| format!("Failed to create {}", dir.display()))
+}
+
+/// Writes a file readable by its owner only, replacing it atomically.
+fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(&tmp)
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(dir: &Path, encrypt_key: bool, passphrase: Option<&str>) -> CaStore {
+        let passphrase_file = passphrase.map(|passphrase| {
+            let file = dir.join(format!("passphrase-{}", passphrase));
+            write_private(&file, format!("{}\n", passphrase).as_bytes()).unwrap();
+            file.to_string_lossy().into_owned()
+        });
+        let ca = CaConfig {
+            dir: Some(dir.join("ca").to_string_lossy().into_owned()),
+            encrypt_key,
+            passphrase_env: "BLANKTRACE_TEST_UNSET_PASSPHRASE".to_string(),
+            passphrase_file,
+        };
+        CaStore::from_ca(&ca, false).unwrap()
+    }
+
+    fn key_label(store: &CaStore) -> String {
+        read_pem(&store.key_path()).unwrap().tag().to_string()
+    }
+
+    #[test]
+    fn test_generate_load_and_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(dir.path(), false, None);
+        assert!(store.load().is_err());
+
+        let ca = store.load_or_generate().unwrap();
+        ca.authority().unwrap();
+        let fingerprint = ca.certificate().fingerprint();
+        assert_eq!(fingerprint.len(), 32 * 3 - 1);
+        assert_eq!(store.certificate().unwrap().fingerprint(), fingerprint);
+        assert_eq!(
+            store
+                .load_or_generate()
+                .unwrap()
+                .certificate()
+                .fingerprint(),
+            fingerprint
+        );
+        assert_eq!(key_label(&store), KEY_LABEL);
+
+        let details = ca.certificate().details().unwrap();
+        assert!(details.subject.contains("BlankTrace CA"));
+        assert!(details.not_before < Utc::now() && Utc::now() < details.not_after);
+
+        let rotated = store.rotate().unwrap();
+        assert_ne!(rotated.certificate().fingerprint(), fingerprint);
+        assert_eq!(
+            store.load().unwrap().certificate().fingerprint(),
+            rotated.certificate().fingerprint()
+        );
+
+        // A lone certificate is neither reused nor overwritten
+        fs::remove_file(store.key_path()).unwrap();
+        let cert = fs::read(store.cert_path()).unwrap();
+        assert!(store.load_or_generate().is_err());
+        assert_eq!(fs::read(store.cert_path()).unwrap(), cert);
+        assert!(!store.key_path().exists());
+    }
+
+    #[test]
+    fn test_log_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(dir.path(), false, None);
+        let key = store.log_key().unwrap();
+        assert_eq!(key.len(), LOG_KEY_LEN);
+        assert_eq!(store.log_key().unwrap(), key);
+
+        fs::write(store.dir().join(LOG_KEY_FILE), b"short").unwrap();
+        assert!(store.log_key().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_refuses_readable_key() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(dir.path(), false, None);
+        store.load_or_generate().unwrap();
+        let mode = |path: PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(store.key_path()), 0o600);
+
+        fs::set_permissions(store.key_path(), fs::Permissions::from_mode(0o644)).unwrap();
+        let err = store.load().err().unwrap().to_string();
+        assert!(err.contains("chmod 600"), "{}", err);
+        assert!(store.load_or_generate().is_err());
+    }
+
+    #[test]
+    fn test_encrypted_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let encrypted = store(dir.path(), true, Some("correct horse"));
+        let ca = encrypted.load_or_generate().unwrap();
+        assert_eq!(key_label(&encrypted), ENCRYPTED_KEY_LABEL);
+        assert_eq!(encrypted.load().unwrap().key_der, ca.key_der);
+
+        // No passphrase or a wrong one stops loading rather than regenerating
+        let key = fs::read_to_string(encrypted.key_path()).unwrap();
+        let err = store(dir.path(), false, None)
+            .load_or_generate()
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("is encrypted"), "{}", err);
+        assert!(store(dir.path(), false, Some("wrong"))
+            .load_or_generate()
+            .is_err());
+        assert_eq!(fs::read_to_string(encrypted.key_path()).unwrap(), key);
+
+        // Rotating keeps the key encrypted
+        let rotating = store(dir.path(), false, Some("battery staple"));
+        let rotated = rotating.rotate().unwrap();
+        assert_eq!(key_label(&rotating), ENCRYPTED_KEY_LABEL);
+        assert_eq!(rotating.load().unwrap().key_der, rotated.key_der);
+        assert!(encrypted.load().is_err());
+    }
+
+    #[test]
+    fn test_encrypts_plaintext_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = store(dir.path(), false, None).load_or_generate().unwrap();
+
+        let encrypted = store(dir.path(), true, Some("correct horse"));
+        assert_eq!(encrypted.load().unwrap().key_der, ca.key_der);
+        assert_eq!(key_label(&encrypted), ENCRYPTED_KEY_LABEL);
+
+        // Asked to encrypt without a passphrase
+        let dir = tempfile::tempdir().unwrap();
+        assert!(store(dir.path(), true, None).load_or_generate().is_err());
+    }
+
+    #[test]
+    fn test_pkcs12_export() {
+        let ca = StoredCa::generate().unwrap();
+        let p12 = ca.certificate().to_pkcs12("secret").unwrap();
+        let store = KeyStore::from_pkcs12(&p12, "secret").unwrap();
+        match store.entry(P12_ALIAS) {
+            Some(KeyStoreEntry::Certificate(cert)) => {
+                assert_eq!(cert.as_der(), ca.certificate().der())
+            }
+            _ => panic!("certificate missing from the PKCS#12 file"),
+        }
+        assert!(KeyStore::from_pkcs12(&p12, "wrong").is_err());
+        assert!(store.private_key_chain().is_none());
+    }
+}
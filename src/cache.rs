@@ -0,0 +1,109 @@
+use crate::config::Config;
+use crate::pattern::registrable_domain;
+use hyper::header::{HeaderName, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED};
+use hyper::{Body, Request, Response};
+
+/// Conditional request headers that echo a stored validator back to the server.
+const REQUEST_HEADERS: [HeaderName; 3] = [IF_NONE_MATCH, IF_MODIFIED_SINCE, IF_RANGE];
+/// Response validators a tracker can use to store an identifier in the cache.
+const RESPONSE_HEADERS: [HeaderName; 2] = [ETAG, LAST_MODIFIED];
+
+/// Neutralises ETag and Last-Modified round-trips used as cache supercookies.
+#[derive(Clone)]
+pub struct CacheDefence {
+    enabled: bool,
+    all_hosts: bool,
+}
+
+impl CacheDefence {
+    /// Creates a new CacheDefence.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg` - Application configuration.
+    pub fn new(cfg: &Config) -> Self {
+        Self {
+            enabled: cfg.cache_tracking.enabled,
+            all_hosts: cfg.cache_tracking.all_hosts,
+        }
+    }
+
+    /// Whether validators exchanged with `host` under the first-party `site`
+    /// should be neutralised.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - The hostname of the request.
+    /// * `site` - The first-party site of the request.
+    /// * `tracker` - Whether the host is classified as a tracker.
+    pub fn applies(&self, host: &str, site: &str, tracker: bool) -> bool {
+        self.enabled
+            && (self.all_hosts || tracker || registrable_domain(host) != registrable_domain(site))
+    }
+
+    /// Removes conditional headers from a request.
+    ///
+    /// Returns the names of the removed headers.
+    pub fn strip_request(&self, req: &mut Request<Body>) -> Vec<String> {
+        strip(req.headers_mut(), &REQUEST_HEADERS)
+    }
+
+    /// Removes validator headers from a response.
+    ///
+    /// Returns the names of the removed headers.
+    pub fn strip_response(&self, res: &mut Response<Body>) -> Vec<String> {
+        strip(res.headers_mut(), &RESPONSE_HEADERS)
+    }
+}
+
+fn strip(headers: &mut hyper::HeaderMap, names: &[HeaderName]) -> Vec<String> {
+    names
+        .iter()
+        .filter(|name| headers.remove(*name).is_some())
+        .map(|name| name.as_str().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defence(all_hosts: bool) -> CacheDefence {
+        CacheDefence {
+            enabled: true,
+            all_hosts,
+        }
+    }
+
+    #[test]
+    fn test_scope() {
+        let d = defence(false);
+        assert!(d.applies("cdn.tracker.net", "news.example.com", false));
+        assert!(!d.applies("static.example.com", "www.example.com", false));
+        assert!(d.applies("metrics.example.com", "www.example.com", true));
+        assert!(defence(true).applies("www.example.com", "www.example.com", false));
+    }
+
+    #[test]
+    fn test_strip_validators() {
+        let d = defence(false);
+        let mut req = Request::builder()
+            .header(IF_NONE_MATCH, "\"uid-1234\"")
+            .header(IF_MODIFIED_SINCE, "Thu, 01 Jan 2015 00:00:00 GMT")
+            .header("accept", "*/*")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            d.strip_request(&mut req),
+            vec!["if-none-match", "if-modified-since"]
+        );
+        assert!(req.headers().contains_key("accept"));
+
+        let mut res = Response::builder()
+            .header(ETAG, "\"uid-1234\"")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(d.strip_response(&mut res), vec!["etag"]);
+        assert!(d.strip_response(&mut res).is_empty());
+    }
+}
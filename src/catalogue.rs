@@ -0,0 +1,117 @@
+use crate::config::{Config, CookieCategory};
+use crate::pattern::glob;
+use anyhow::Context;
+use regex::Regex;
+use serde::Deserialize;
+
+/// The bundled cookie catalogue.
+const BUILTIN_CATALOGUE: &str = include_str!("../cookie_catalogue.yaml");
+
+/// A cookie catalogue file.
+#[derive(Debug, Deserialize)]
+pub struct CatalogueFile {
+    #[serde(default)]
+    pub cookies: Vec<CatalogueEntry>,
+}
+
+/// A known cookie name pattern.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CatalogueEntry {
+    /// Glob matched against the cookie name.
+    pub pattern: String,
+    /// Company or product that sets the cookie.
+    pub vendor: String,
+    /// What the cookie is used for.
+    pub category: CookieCategory,
+}
+
+/// Classifies cookies by name using the bundled and user catalogues.
+#[derive(Clone)]
+pub struct CookieCatalogue {
+    entries: Vec<(Regex, CatalogueEntry)>,
+}
+
+impl CookieCatalogue {
+    /// Creates a new CookieCatalogue.
+    ///
+    /// Entries from `cookies.catalogue_file` are checked before the bundled ones.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg` - Application configuration.
+    pub fn new(cfg: &Config) -> anyhow::Result<Self> {
+        let mut files = Vec::new();
+        if let Some(path) = &cfg.cookies.catalogue_file {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read cookie catalogue '{}'", path))?;
+            files.push(
+                serde_yaml::from_str(&content)
+                    .with_context(|| format!("invalid cookie catalogue '{}'", path))?,
+            );
+        }
+        files.push(serde_yaml::from_str(BUILTIN_CATALOGUE).context("bundled cookie catalogue")?);
+        Self::from_files(files)
+    }
+
+    /// Creates a new CookieCatalogue from parsed files, in priority order.
+    ///
+    /// # Arguments
+    ///
+    /// * `files` - Parsed catalogue files; earlier entries win.
+    pub fn from_files(files: Vec<CatalogueFile>) -> anyhow::Result<Self> {
+        let mut entries = Vec::new();
+        for file in files {
+            for entry in file.cookies {
+                entries.push((glob(&entry.pattern)?, entry));
+            }
+        }
+        Ok(Self { entries })
+    }
+
+    /// Returns the catalogue entry for a cookie name, if it is known.
+    pub fn classify(&self, name: &str) -> Option<&CatalogueEntry> {
+        self.entries
+            .iter()
+            .find(|(re, _)| re.is_match(name))
+            .map(|(_, entry)| entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_catalogue() {
+        let file = serde_yaml::from_str(BUILTIN_CATALOGUE).unwrap();
+        let catalogue = CookieCatalogue::from_files(vec![file]).unwrap();
+
+        let ga = catalogue.classify("_ga_ABC123").unwrap();
+        assert_eq!(ga.vendor, "Google Analytics");
+        assert_eq!(ga.category, CookieCategory::Analytics);
+        assert_eq!(
+            catalogue.classify("_fbp").unwrap().category,
+            CookieCategory::Advertising
+        );
+        assert_eq!(
+            catalogue.classify("__cf_bm").unwrap().category,
+            CookieCategory::Essential
+        );
+        assert!(catalogue.classify("session_id").is_none());
+    }
+
+    #[test]
+    fn test_user_entries_take_precedence() {
+        let user: CatalogueFile = serde_yaml::from_str(
+            "cookies:\n  - { pattern: \"_ga\", vendor: \"Self-hosted\", category: functional }\n",
+        )
+        .unwrap();
+        let builtin = serde_yaml::from_str(BUILTIN_CATALOGUE).unwrap();
+        let catalogue = CookieCatalogue::from_files(vec![user, builtin]).unwrap();
+        assert_eq!(catalogue.classify("_ga").unwrap().vendor, "Self-hosted");
+        assert_eq!(
+            catalogue.classify("_gid").unwrap().vendor,
+            "Google Analytics"
+        );
+    }
+}
@@ -1,7 +1,12 @@
 // src/cli.rs
+use crate::ca::CaStore;
+use crate::config::Config;
 use crate::db::Database;
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use crate::learned::{PROMOTED, REVOKED};
+use crate::pac::PacGenerator;
+use crate::pattern::registrable_domain;
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand, ValueEnum};
 
 #[derive(Parser)]
 #[command(name = "blanktrace")]
@@ -26,6 +31,9 @@ pub enum Commands {
     Export {
         #[arg(short, long)]
         file: String,
+        /// Only export requests made by this client IP
+        #[arg(short, long)]
+        client: Option<String>,
     },
     /// Add a domain to the whitelist
     Whitelist {
@@ -39,11 +47,94 @@ pub enum Commands {
         #[arg(short, long)]
         domain: String,
     },
+    /// Inspect or purge the partitioned cookie jar
+    Jar {
+        #[command(subcommand)]
+        action: JarAction,
+    },
+    /// Review hosts learned from failed TLS handshakes
+    Learned {
+        #[command(subcommand)]
+        action: LearnedAction,
+    },
+    /// Write the proxy auto-config file to disk
+    Pac {
+        #[arg(short, long, default_value = "proxy.pac")]
+        output: String,
+        /// Render this pac.profiles variant instead of the default
+        #[arg(short, long)]
+        profile: Option<String>,
+    },
+    /// Manage the CA certificate used for HTTPS interception
+    Ca {
+        #[command(subcommand)]
+        action: CaAction,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum JarAction {
+    /// List the sites in the jar, or the cookies stored under one site
+    List {
+        #[arg(short, long)]
+        site: Option<String>,
+    },
+    /// Remove the cookies stored under one site, or under every site with --all
+    Purge {
+        #[arg(short, long)]
+        site: Option<String>,
+        #[arg(long)]
+        all: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum LearnedAction {
+    /// List learned passthrough hosts and their status
+    List,
+    /// Confirm a host, so it is tunnelled even without tls_fallback.auto_apply
+    Promote {
+        #[arg(short, long)]
+        domain: String,
+    },
+    /// Intercept a host again and never learn it
+    Revoke {
+        #[arg(short, long)]
+        domain: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CaAction {
+    /// Show the CA certificate's fingerprint and validity
+    Show,
+    /// Write the CA certificate for installing in browsers and trust stores
+    Export {
+        #[arg(short, long, value_enum, default_value_t = CaFormat::Pem)]
+        format: CaFormat,
+        /// Defaults to blanktrace-ca.<format>
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Password protecting the p12 file
+        #[arg(long, default_value = "")]
+        password: String,
+    },
+    /// Replace the CA with a newly generated one
+    Rotate,
+    /// Print where the CA certificate and key are stored
+    Path,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum CaFormat {
+    Pem,
+    Der,
+    P12,
 }
 
 /// Execute a management subcommand without starting the proxy.
-pub async fn handle_management_cmd(cmd: Commands, db_path: String) -> Result<()> {
-    let db = Database::new(&db_path)?;
+pub async fn handle_management_cmd(cmd: Commands, cfg: &Config) -> Result<()> {
+    let db = Database::new(&cfg.db_path)?;
 
     match cmd {
         Commands::Stats => {
@@ -90,6 +181,102 @@ pub async fn handle_management_cmd(cmd: Commands, db_path: String) -> Result<()>
                 let (domain, count) = domain?;
                 println!("  {}. {} ({} requests)", i + 1, domain, count);
             }
+            drop(stmt);
+            drop(conn);
+
+            println!("\n=== Requests by Client ===");
+            for (client, requests, domains) in db.get_requests_by_client(10).await? {
+                println!("  {} ({} requests, {} domains)", client, requests, domains);
+            }
+
+            println!("\n=== Requests by User ===");
+            for (user, requests, blocked, bytes, cookies) in db.get_requests_by_user(10).await? {
+                println!(
+                    "  {} ({} requests, {} blocked, {} bytes, {} cookies blocked)",
+                    user, requests, blocked, bytes, cookies
+                );
+            }
+
+            println!("\n=== Requests by Route ===");
+            for (route, requests) in db.get_requests_by_route().await? {
+                println!("  {} ({} requests)", route, requests);
+            }
+
+            println!("\n=== Rejected Clients ===");
+            for (client, listener, attempts, last_seen) in db.get_rejected_clients(10).await? {
+                println!(
+                    "  {} on {} ({} attempts, last {})",
+                    client, listener, attempts, last_seen
+                );
+            }
+
+            println!("\n=== Top 10 Domains by Bytes Served ===");
+            for (i, (domain, bytes, latency)) in
+                db.get_top_domains_by_bytes(10).await?.iter().enumerate()
+            {
+                match latency {
+                    Some(ms) => println!(
+                        "  {}. {} ({} bytes, {:.0} ms average)",
+                        i + 1,
+                        domain,
+                        bytes,
+                        ms
+                    ),
+                    None => println!("  {}. {} ({} bytes)", i + 1, domain, bytes),
+                }
+            }
+            println!(
+                "Bytes Saved by Blocking (estimated): {}",
+                db.get_bytes_saved().await?
+            );
+
+            println!("\n=== Top 10 Tunnelled Domains (not intercepted) ===");
+            for (i, (domain, tunnels, blocked, sent, received)) in
+                db.get_tunnels_by_domain(10).await?.iter().enumerate()
+            {
+                println!(
+                    "  {}. {} ({} tunnels, {} blocked, {} bytes sent, {} bytes received)",
+                    i + 1,
+                    domain,
+                    tunnels,
+                    blocked,
+                    sent,
+                    received
+                );
+            }
+
+            println!("\n=== Top 10 Stripped Query Parameters ===");
+            for (i, (param, count)) in db.get_top_stripped_params(10).await?.iter().enumerate() {
+                println!("  {}. {} ({} removals)", i + 1, param, count);
+            }
+
+            println!("\n=== Top 10 Cache Validator Domains ===");
+            for (i, (domain, count)) in db
+                .get_top_cache_validator_domains(10)
+                .await?
+                .iter()
+                .enumerate()
+            {
+                println!("  {}. {} ({} validators removed)", i + 1, domain, count);
+            }
+
+            println!("\n=== Cookies by Category ===");
+            for (category, total, blocked) in db.get_cookie_breakdown(false, 10).await? {
+                println!("  {}: {} seen, {} blocked", category, total, blocked);
+            }
+
+            println!("\n=== Top 10 Cookie Vendors ===");
+            for (i, (vendor, total, blocked)) in
+                db.get_cookie_breakdown(true, 10).await?.iter().enumerate()
+            {
+                println!(
+                    "  {}. {} ({} seen, {} blocked)",
+                    i + 1,
+                    vendor,
+                    total,
+                    blocked
+                );
+            }
         }
         Commands::Domains { limit } => {
             let top = db.get_top_domains(limit.unwrap_or(10)).await?;
@@ -98,7 +285,7 @@ pub async fn handle_management_cmd(cmd: Commands, db_path: String) -> Result<()>
                 println!("{domain}: {hits}");
             }
         }
-        Commands::Export { file } => {
+        Commands::Export { file, client } => {
             println!("Exporting database to {}...", file);
 
             let conn = db.get_conn().lock().await;
@@ -107,25 +294,89 @@ pub async fn handle_management_cmd(cmd: Commands, db_path: String) -> Result<()>
             let mut export_data = serde_json::json!({
                 "export_time": chrono::Utc::now().to_rfc3339(),
                 "requests": [],
+                "clients": [],
                 "tracking_domains": [],
+                "tunnels": [],
+                "stripped_params": [],
+                "cache_validators": [],
                 "whitelist": []
             });
 
-            // Export requests
-            let mut stmt = conn.prepare("SELECT domain, path, user_agent, client_ip, timestamp FROM request_log ORDER BY timestamp DESC LIMIT 1000")?;
+            // Export requests, optionally for a single client
+            let mut stmt = conn.prepare(
+                "SELECT domain, path, user_agent, client_ip, client_port, method, scheme,
+                        via_connect, timestamp, blocked, status, content_type, response_bytes,
+                        latency_ms, username, route
+                 FROM request_log WHERE ?1 IS NULL OR client_ip = ?1
+                 ORDER BY timestamp DESC LIMIT 1000",
+            )?;
             let requests: Vec<serde_json::Value> = stmt
-                .query_map([], |row| {
+                .query_map([&client], |row| {
                     Ok(serde_json::json!({
                         "domain": row.get::<_, String>(0)?,
                         "path": row.get::<_, String>(1)?,
                         "user_agent": row.get::<_, String>(2)?,
                         "client_ip": row.get::<_, String>(3)?,
-                        "timestamp": row.get::<_, String>(4)?
+                        "client_port": row.get::<_, Option<u16>>(4)?,
+                        "method": row.get::<_, Option<String>>(5)?,
+                        "scheme": row.get::<_, Option<String>>(6)?,
+                        "via_connect": row.get::<_, Option<bool>>(7)?.unwrap_or(false),
+                        "timestamp": row.get::<_, String>(8)?,
+                        "blocked": row.get::<_, Option<bool>>(9)?.unwrap_or(false),
+                        "status": row.get::<_, Option<u16>>(10)?,
+                        "content_type": row.get::<_, Option<String>>(11)?,
+                        "response_bytes": row.get::<_, Option<i64>>(12)?,
+                        "latency_ms": row.get::<_, Option<i64>>(13)?,
+                        "user": row.get::<_, Option<String>>(14)?,
+                        "route": row.get::<_, Option<String>>(15)?
                     }))
                 })?
                 .collect::<Result<Vec<_>, _>>()?;
             export_data["requests"] = serde_json::json!(requests);
 
+            // Export per-client request totals
+            let mut stmt = conn.prepare(
+                "SELECT COALESCE(client_ip, 'unknown') AS client, COUNT(*), COUNT(DISTINCT domain)
+                 FROM request_log WHERE ?1 IS NULL OR client_ip = ?1 GROUP BY client",
+            )?;
+            let clients: Vec<serde_json::Value> = stmt
+                .query_map([&client], |row| {
+                    Ok(serde_json::json!({
+                        "client_ip": row.get::<_, String>(0)?,
+                        "requests": row.get::<_, i64>(1)?,
+                        "domains": row.get::<_, i64>(2)?
+                    }))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            export_data["clients"] = serde_json::json!(clients);
+
+            // Export opaque tunnels, optionally for a single client
+            let mut stmt = conn.prepare(
+                "SELECT domain, port, client_ip, client_port, username, route, reason, blocked,
+                        bytes_sent, bytes_received, duration_ms, timestamp
+                 FROM tunnel_log WHERE ?1 IS NULL OR client_ip = ?1
+                 ORDER BY timestamp DESC LIMIT 1000",
+            )?;
+            let tunnels: Vec<serde_json::Value> = stmt
+                .query_map([&client], |row| {
+                    Ok(serde_json::json!({
+                        "domain": row.get::<_, String>(0)?,
+                        "port": row.get::<_, u16>(1)?,
+                        "client_ip": row.get::<_, String>(2)?,
+                        "client_port": row.get::<_, Option<u16>>(3)?,
+                        "user": row.get::<_, Option<String>>(4)?,
+                        "route": row.get::<_, Option<String>>(5)?,
+                        "reason": row.get::<_, String>(6)?,
+                        "blocked": row.get::<_, bool>(7)?,
+                        "bytes_sent": row.get::<_, i64>(8)?,
+                        "bytes_received": row.get::<_, i64>(9)?,
+                        "duration_ms": row.get::<_, i64>(10)?,
+                        "timestamp": row.get::<_, String>(11)?
+                    }))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            export_data["tunnels"] = serde_json::json!(tunnels);
+
             // Export tracking domains
             let mut stmt =
                 conn.prepare("SELECT domain, hit_count, blocked FROM tracking_domains")?;
@@ -140,6 +391,36 @@ pub async fn handle_management_cmd(cmd: Commands, db_path: String) -> Result<()>
                 .collect::<Result<Vec<_>, _>>()?;
             export_data["tracking_domains"] = serde_json::json!(domains);
 
+            // Export stripped query parameters
+            let mut stmt =
+                conn.prepare("SELECT param, domain, hit_count, last_seen FROM stripped_params")?;
+            let params: Vec<serde_json::Value> = stmt
+                .query_map([], |row| {
+                    Ok(serde_json::json!({
+                        "param": row.get::<_, String>(0)?,
+                        "domain": row.get::<_, String>(1)?,
+                        "hit_count": row.get::<_, i64>(2)?,
+                        "last_seen": row.get::<_, String>(3)?
+                    }))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            export_data["stripped_params"] = serde_json::json!(params);
+
+            // Export neutralised cache validators
+            let mut stmt =
+                conn.prepare("SELECT domain, header, hit_count, last_seen FROM cache_validators")?;
+            let validators: Vec<serde_json::Value> = stmt
+                .query_map([], |row| {
+                    Ok(serde_json::json!({
+                        "domain": row.get::<_, String>(0)?,
+                        "header": row.get::<_, String>(1)?,
+                        "hit_count": row.get::<_, i64>(2)?,
+                        "last_seen": row.get::<_, String>(3)?
+                    }))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            export_data["cache_validators"] = serde_json::json!(validators);
+
             // Export whitelist
             let mut stmt = conn.prepare("SELECT domain, reason, added_at FROM whitelist")?;
             let whitelist: Vec<serde_json::Value> = stmt
@@ -147,7 +428,7 @@ pub async fn handle_management_cmd(cmd: Commands, db_path: String) -> Result<()>
                     Ok(serde_json::json!({
                         "domain": row.get::<_, String>(0)?,
                         "reason": row.get::<_, Option<String>>(1)?,
-                        "added_at": row.get::<_, String>(2)?
+                        "added_at": row.get::<_, Option<String>>(2)?
                     }))
                 })?
                 .collect::<Result<Vec<_>, _>>()?;
@@ -165,6 +446,128 @@ pub async fn handle_management_cmd(cmd: Commands, db_path: String) -> Result<()>
             db.manual_block(&domain).await?;
             println!("✓ Blocked {}", domain);
         }
+        Commands::Jar { action } => match action {
+            JarAction::List { site: None } => {
+                println!("Cookie jar sites:");
+                for (site, count) in db.jar_sites().await? {
+                    println!("  {} ({} cookies)", site, count);
+                }
+            }
+            JarAction::List { site: Some(site) } => {
+                let site = registrable_domain(&site);
+                println!("Cookies stored under {}:", site);
+                for c in db.jar_cookies(&site).await? {
+                    let expires = c
+                        .expires
+                        .and_then(|e| chrono::DateTime::from_timestamp(e, 0))
+                        .map(|t| t.to_rfc3339())
+                        .unwrap_or_else(|| "session".to_string());
+                    println!(
+                        "  {}{} {}={} (expires: {}{})",
+                        c.domain,
+                        c.path,
+                        c.name,
+                        c.value,
+                        expires,
+                        if c.secure { ", secure" } else { "" }
+                    );
+                }
+            }
+            JarAction::Purge { site, all } => {
+                let site = site.map(|s| registrable_domain(&s));
+                if site.is_none() && !all {
+                    bail!("specify --site <SITE> or --all");
+                }
+                let removed = db.jar_purge(site.as_deref()).await?;
+                match site {
+                    Some(site) => println!("✓ Purged {} cookies stored under {}", removed, site),
+                    None => println!("✓ Purged {} cookies from the jar", removed),
+                }
+            }
+        },
+        Commands::Learned { action } => match action {
+            LearnedAction::List => {
+                println!("Learned passthrough hosts:");
+                for h in db.learned_hosts().await? {
+                    let reviewed = h
+                        .reviewed_at
+                        .map(|t| format!(", reviewed {}", t))
+                        .unwrap_or_default();
+                    println!(
+                        "  {} [{}] ({} failed handshakes, learned {}{})",
+                        h.host, h.status, h.failures, h.learned_at, reviewed
+                    );
+                }
+            }
+            LearnedAction::Promote { domain } => {
+                let domain = domain.to_ascii_lowercase();
+                db.review_learned_host(&domain, PROMOTED).await?;
+                println!(
+                    "✓ Promoted {}: it will be tunnelled without interception",
+                    domain
+                );
+            }
+            LearnedAction::Revoke { domain } => {
+                let domain = domain.to_ascii_lowercase();
+                db.review_learned_host(&domain, REVOKED).await?;
+                println!("✓ Revoked {}: it will be intercepted again", domain);
+            }
+        },
+        Commands::Pac { output, profile } => {
+            let pac = PacGenerator::new(cfg)?.render(profile.as_deref(), None)?;
+            std::fs::write(&output, pac)?;
+            println!("✓ Wrote PAC file to {}", output);
+        }
+        Commands::Ca { action } => handle_ca_cmd(action, cfg)?,
+    }
+
+    Ok(())
+}
+
+fn handle_ca_cmd(action: CaAction, cfg: &Config) -> Result<()> {
+    let store = CaStore::new(cfg)?;
+
+    match action {
+        CaAction::Show => {
+            let ca = store.certificate()?;
+            let details = ca.details()?;
+            println!("Certificate: {}", store.cert_path().display());
+            println!("Subject: {}", details.subject);
+            println!("SHA-256 fingerprint: {}", ca.fingerprint());
+            println!("Valid from: {}", details.not_before.to_rfc3339());
+            println!("Valid until: {}", details.not_after.to_rfc3339());
+            if details.not_after < chrono::Utc::now() {
+                println!("⚠ The certificate has expired; replace it with `blanktrace ca rotate`");
+            }
+        }
+        CaAction::Export {
+            format,
+            output,
+            password,
+        } => {
+            let ca = store.certificate()?;
+            let (data, extension) = match format {
+                CaFormat::Pem => (ca.pem().into_bytes(), "pem"),
+                CaFormat::Der => (ca.der().to_vec(), "der"),
+                CaFormat::P12 => (ca.to_pkcs12(&password)?, "p12"),
+            };
+            let output = output.unwrap_or_else(|| format!("blanktrace-ca.{}", extension));
+            std::fs::write(&output, data)?;
+            println!("✓ Wrote CA certificate to {}", output);
+        }
+        CaAction::Rotate => {
+            let ca = store.rotate()?;
+            println!("✓ Generated a new CA in {}", store.dir().display());
+            println!("SHA-256 fingerprint: {}", ca.certificate().fingerprint());
+            println!(
+                "Restart the proxy, then replace the old CA in your browsers and trust stores"
+            );
+        }
+        CaAction::Path => {
+            println!("Directory: {}", store.dir().display());
+            println!("Certificate: {}", store.cert_path().display());
+            println!("Private key: {}", store.key_path().display());
+        }
     }
 
     Ok(())
@@ -1,5 +1,6 @@
 // src/config.rs
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::BufReader;
 
@@ -14,7 +15,7 @@ pub struct FingerprintConfig {
     pub randomize_user_agent: bool,
     /// Whether to randomize the Accept-Language header.
     pub randomize_accept_language: bool,
-    /// Whether to strip the Referer header.
+    /// Whether to strip the Referer header (used when `referer.default_policy` is unset).
     pub strip_referer: bool,
     /// List of Accept-Language values to choose from.
     #[serde(default = "default_accept_languages")]
@@ -25,6 +26,413 @@ fn default_accept_languages() -> Vec<String> {
     vec!["en-US,en;q=0.9".to_string(), "en-GB,en;q=0.8".to_string()]
 }
 
+/// How the `Referer` (and `Origin`) header is treated, modelled on Referrer-Policy.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RefererPolicy {
+    /// Forward the header unchanged.
+    Keep,
+    /// Remove the header on cross-origin requests only.
+    StripCrossOrigin,
+    /// Always trim the header to its origin (`scheme://host[:port]/`).
+    Origin,
+    /// Keep same-origin headers, trim cross-origin ones to their origin.
+    TrimCrossOrigin,
+    /// Always remove the header.
+    Strip,
+}
+
+/// A per-destination Referer policy override.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RefererRule {
+    /// Destination domain the rule applies to (including subdomains).
+    pub domain: String,
+    /// Policy to apply for this destination.
+    pub policy: RefererPolicy,
+}
+
+/// Configuration for Referer/Origin handling.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RefererConfig {
+    /// Policy used when no rule matches. Falls back to `fingerprint.strip_referer` when unset.
+    pub default_policy: Option<RefererPolicy>,
+    /// Per-destination overrides, first match wins.
+    #[serde(default)]
+    pub rules: Vec<RefererRule>,
+}
+
+/// Per-domain query parameter rule.
+#[derive(Debug, Deserialize, Clone)]
+pub struct QueryParamRule {
+    /// Domain the rule applies to (including subdomains).
+    pub domain: String,
+    /// Additional parameter names (globs) to strip for this domain.
+    #[serde(default)]
+    pub strip: Vec<String>,
+    /// Parameter names (globs) that are functional here and must be kept.
+    #[serde(default)]
+    pub keep: Vec<String>,
+}
+
+/// Configuration for tracking query parameter stripping.
+#[derive(Debug, Deserialize, Clone)]
+pub struct QueryParamsConfig {
+    /// Whether query parameter stripping is enabled.
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Whether to include the built-in ClearURLs-style parameter list.
+    #[serde(default = "default_true")]
+    pub builtin: bool,
+    /// Additional parameter names (globs) to strip on every domain.
+    #[serde(default)]
+    pub strip: Vec<String>,
+    /// Per-domain additions and exceptions.
+    #[serde(default)]
+    pub rules: Vec<QueryParamRule>,
+}
+
+impl Default for QueryParamsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            builtin: true,
+            strip: vec![],
+            rules: vec![],
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Listening sockets and client access control.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ListenConfig {
+    /// Socket addresses to listen on, e.g. `127.0.0.1:8080` or `[::1]:8080`.
+    /// Defaults to `127.0.0.1` on `port`.
+    pub addresses: Vec<String>,
+    /// Socket addresses to accept SOCKS5 clients on, e.g. `127.0.0.1:1080`.
+    /// No SOCKS listener is started when empty.
+    pub socks_addresses: Vec<String>,
+    /// Socket addresses to accept connections redirected by iptables/nftables
+    /// `REDIRECT` or `TPROXY` on (Linux only), e.g. `0.0.0.0:8443`.
+    pub transparent_addresses: Vec<String>,
+    /// Client networks in CIDR notation allowed besides loopback.
+    pub allow_clients: Vec<String>,
+    /// Required to listen on anything other than loopback.
+    pub allow_remote: bool,
+}
+
+/// Upstream proxies the proxied requests are sent through.
+///
+/// Proxy URLs are `http://[user:pass@]host:port` for an HTTP CONNECT proxy,
+/// `socks5h://[user:pass@]host:port` for SOCKS5 with remote DNS, or
+/// `socks5://` to resolve names locally.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct UpstreamConfig {
+    /// Proxy of the `default` route, used when no rule matches. Requests go
+    /// direct when unset.
+    pub url: Option<String>,
+    /// Further proxies by route name.
+    pub proxies: BTreeMap<String, String>,
+    /// Routing rules; the first matching rule picks the route.
+    pub rules: Vec<RouteRule>,
+    /// Use the first-party site as SOCKS5 username, so Tor builds separate
+    /// circuits per site.
+    pub isolate_sites: bool,
+}
+
+/// A rule sending matching requests through a route.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RouteRule {
+    /// Host glob patterns (e.g. `*.corp.example`); empty matches every host.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// First-party site glob patterns; empty matches every site.
+    #[serde(default)]
+    pub sites: Vec<String>,
+    /// `direct`, `default` or the name of an entry in `proxies`.
+    pub via: String,
+}
+
+/// Proxy auto-config (PAC) file served at `/proxy.pac` and `/wpad.dat`.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PacConfig {
+    /// Whether the HTTP listeners serve the PAC file.
+    pub enabled: bool,
+    /// Proxy address written into the PAC, e.g. `192.168.10.1:8080`. Defaults
+    /// to the address the PAC was fetched from, or the first listen address.
+    pub proxy: Option<String>,
+    /// Host globs (as in `shExpMatch`) and IP networks that bypass the proxy.
+    pub bypass: Vec<String>,
+    /// Send plain host names, `localhost` and loopback, private and
+    /// link-local addresses direct.
+    pub bypass_local: bool,
+    /// Named variants, served at `/proxy-<name>.pac`.
+    pub profiles: BTreeMap<String, PacProfile>,
+}
+
+impl Default for PacConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            proxy: None,
+            bypass: Vec::new(),
+            bypass_local: true,
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+/// A PAC variant for a group of clients.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct PacProfile {
+    /// Proxy address, overriding `pac.proxy`.
+    pub proxy: Option<String>,
+    /// Point clients at the SOCKS5 listener instead of the HTTP one.
+    pub socks: bool,
+    /// Bypass entries added to `pac.bypass`.
+    pub bypass: Vec<String>,
+}
+
+/// Learning hosts whose clients reject the proxy's certificates, as pinned
+/// apps do, so they can be tunnelled like `no_intercept` hosts.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct TlsFallbackConfig {
+    /// Whether client-side TLS handshake failures are tracked per host.
+    pub enabled: bool,
+    /// Failures within `window_seconds` after which a host is learned.
+    pub failures: usize,
+    pub window_seconds: u64,
+    /// Tunnel learned hosts right away; otherwise only once promoted.
+    pub auto_apply: bool,
+}
+
+impl Default for TlsFallbackConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            failures: 3,
+            window_seconds: 300,
+            auto_apply: false,
+        }
+    }
+}
+
+/// Where the interception CA certificate and key are kept.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct CaConfig {
+    /// Directory holding `ca_cert.pem` and `ca_key.pem`; defaults to the
+    /// platform data directory, e.g. `~/.local/share/blanktrace` on Linux.
+    pub dir: Option<String>,
+    /// Write the key as passphrase-encrypted PKCS#8.
+    pub encrypt_key: bool,
+    /// Environment variable holding the key passphrase.
+    pub passphrase_env: String,
+    /// File holding the key passphrase, read when the variable is unset.
+    pub passphrase_file: Option<String>,
+}
+
+impl Default for CaConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            encrypt_key: false,
+            passphrase_env: "BLANKTRACE_CA_PASSPHRASE".to_string(),
+            passphrase_file: None,
+        }
+    }
+}
+
+/// A proxy user with a hashed password.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProxyUser {
+    pub name: String,
+    /// bcrypt (`$2y$...`) or `{SHA}` hash, as produced by `htpasswd -B` or `-s`.
+    pub password_hash: String,
+}
+
+/// Proxy authentication settings.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// Whether clients must send `Proxy-Authorization: Basic` credentials.
+    pub enabled: bool,
+    /// Realm announced in `Proxy-Authenticate`.
+    pub realm: String,
+    /// Users defined inline.
+    pub users: Vec<ProxyUser>,
+    /// Optional htpasswd-style file with more users.
+    pub htpasswd_file: Option<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            realm: "BlankTrace".to_string(),
+            users: Vec::new(),
+            htpasswd_file: None,
+        }
+    }
+}
+
+/// How much of each request is written to `request_log`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestLogLevel {
+    /// Requests are not logged.
+    Off,
+    /// Only the domain is logged.
+    Domains,
+    /// Domain and path, without the query string.
+    #[default]
+    Paths,
+    /// Domain, path and query string.
+    Full,
+}
+
+/// How cookie values are written to `cookie_traffic`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CookieValueLogging {
+    /// Values are stored as seen on the wire.
+    Full,
+    /// Values are replaced by a truncated SHA-256 digest.
+    #[default]
+    Hash,
+    /// Values are replaced by a fixed marker.
+    Redact,
+    /// Only the cookie name is stored.
+    Names,
+}
+
+/// Privacy settings for the proxy's own logs.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// Request logging level.
+    pub requests: RequestLogLevel,
+    /// Cookie value handling.
+    pub cookie_values: CookieValueLogging,
+}
+
+/// Configuration for cache-based tracking defences.
+#[derive(Debug, Deserialize, Clone)]
+pub struct CacheTrackingConfig {
+    /// Whether cache validators are neutralised.
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Apply to every host instead of only third-party and tracker hosts.
+    #[serde(default)]
+    pub all_hosts: bool,
+}
+
+impl Default for CacheTrackingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            all_hosts: false,
+        }
+    }
+}
+
+/// Configuration for bounce-tracking redirect unwrapping.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RedirectsConfig {
+    /// Whether redirect unwrapping is enabled.
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Whether to load the bundled `redirect_rules.yaml`.
+    #[serde(default = "default_true")]
+    pub builtin: bool,
+    /// Optional path to an additional rule file in the same format.
+    pub rules_file: Option<String>,
+    /// Whether to follow 3xx chains of opaque redirectors inside the proxy.
+    #[serde(default)]
+    pub follow_opaque: bool,
+    /// Maximum number of hops followed per redirect.
+    #[serde(default = "default_max_hops")]
+    pub max_hops: usize,
+}
+
+fn default_max_hops() -> usize {
+    5
+}
+
+impl Default for RedirectsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            builtin: true,
+            rules_file: None,
+            follow_opaque: false,
+            max_hops: default_max_hops(),
+        }
+    }
+}
+
+/// Which side of an exchange a header rule applies to.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderPhase {
+    /// Rewrite outgoing request headers.
+    #[default]
+    Request,
+    /// Rewrite incoming response headers.
+    Response,
+}
+
+/// Operation performed by a header rule.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderAction {
+    /// Append `value` to the header.
+    Add,
+    /// Remove the header entirely.
+    Remove,
+    /// Overwrite an existing header with `value`.
+    Replace,
+    /// Apply the regex `pattern` to each value, substituting `replacement`.
+    Substitute,
+}
+
+/// A header rewrite rule, applied after the built-in privacy stages.
+#[derive(Debug, Deserialize, Clone)]
+pub struct HeaderRule {
+    /// Rule name, used in logs.
+    pub name: String,
+    /// Whether the rule applies to requests or responses.
+    #[serde(default)]
+    pub phase: HeaderPhase,
+    /// Host glob patterns (e.g. `*.example.com`); empty matches every host.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Request methods to match; empty matches every method.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Content-Type globs (e.g. `text/*`) of the rewritten message; empty matches any.
+    #[serde(default)]
+    pub content_types: Vec<String>,
+    /// Operation to perform.
+    pub action: HeaderAction,
+    /// Header name to operate on.
+    pub header: String,
+    /// Value for `add` and `replace`.
+    pub value: Option<String>,
+    /// Regex for `substitute`.
+    pub pattern: Option<String>,
+    /// Replacement for `substitute` (supports `$1` style captures).
+    pub replacement: Option<String>,
+}
+
 /// Configuration for cookie management.
 #[derive(Debug, Deserialize, Clone)]
 pub struct CookiesConfig {
@@ -38,6 +446,137 @@ pub struct CookiesConfig {
     /// List of domains to explicitly block cookies for.
     #[serde(default)]
     pub block_list: Vec<String>,
+    /// Cookie name globs allowed on every domain (e.g. `session*`).
+    #[serde(default)]
+    pub allow_names: Vec<String>,
+    /// Cookie name globs blocked on every domain (e.g. `_ga*`, `_fbp`, `IDE`).
+    #[serde(default)]
+    pub block_names: Vec<String>,
+    /// Per-domain cookie name rules, evaluated before the global name lists.
+    #[serde(default)]
+    pub rules: Vec<CookieRule>,
+    /// Catalogue categories whose cookies are blocked on every domain.
+    #[serde(default)]
+    pub block_categories: Vec<CookieCategory>,
+    /// Optional path to an additional cookie catalogue, checked before the bundled one.
+    #[serde(default)]
+    pub catalogue_file: Option<String>,
+    /// Set-Cookie attribute rewriting for cookies that are let through.
+    #[serde(default)]
+    pub harden: CookieHardening,
+    /// Proxy-managed cookie jar partitioned by top-level site.
+    #[serde(default)]
+    pub jar: CookieJarConfig,
+    /// In-memory cookie sessions that are purged when idle.
+    #[serde(default)]
+    pub ephemeral: EphemeralConfig,
+}
+
+/// Ephemeral cookie session settings.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct EphemeralConfig {
+    /// Domains (including subdomains) whose cookies only live for a session.
+    pub domains: Vec<String>,
+    /// Seconds without traffic after which a session is purged.
+    pub idle_timeout: u64,
+}
+
+impl Default for EphemeralConfig {
+    fn default() -> Self {
+        Self {
+            domains: Vec::new(),
+            idle_timeout: 1800,
+        }
+    }
+}
+
+/// Partitioned cookie jar settings.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct CookieJarConfig {
+    /// Whether third-party cookies are kept in the proxy's jar instead of the browser.
+    pub enabled: bool,
+    /// Also move first-party cookies into the jar.
+    pub capture_first_party: bool,
+}
+
+/// `SameSite` value enforced on hardened cookies.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SameSitePolicy {
+    Lax,
+    Strict,
+}
+
+/// Set-Cookie hardening settings.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct CookieHardening {
+    /// Whether allowed Set-Cookie headers are rewritten.
+    pub enabled: bool,
+    /// Maximum lifetime in seconds; 0 turns cookies into session cookies.
+    pub max_age: Option<u64>,
+    /// Minimum `SameSite` value; `None`-or-missing is raised to this.
+    pub same_site: Option<SameSitePolicy>,
+    /// Add `Secure` to cookies set over HTTPS.
+    pub secure: bool,
+    /// Remove the `Domain` attribute so cookies stay host-only.
+    pub strip_domain: bool,
+    /// Per-domain overrides; the first matching rule wins.
+    pub rules: Vec<CookieHardeningRule>,
+}
+
+/// Per-domain override of the hardening settings. Unset fields inherit.
+#[derive(Debug, Deserialize, Clone)]
+pub struct CookieHardeningRule {
+    /// Domain the rule applies to (including subdomains).
+    pub domain: String,
+    #[serde(default)]
+    pub enabled: Option<bool>,
+    #[serde(default)]
+    pub max_age: Option<u64>,
+    #[serde(default)]
+    pub same_site: Option<SameSitePolicy>,
+    #[serde(default)]
+    pub secure: Option<bool>,
+    #[serde(default)]
+    pub strip_domain: Option<bool>,
+}
+
+/// Purpose of a cookie, as classified by the cookie catalogue.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CookieCategory {
+    Essential,
+    Functional,
+    Analytics,
+    Advertising,
+}
+
+impl CookieCategory {
+    /// Returns the name used in configuration and the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CookieCategory::Essential => "essential",
+            CookieCategory::Functional => "functional",
+            CookieCategory::Analytics => "analytics",
+            CookieCategory::Advertising => "advertising",
+        }
+    }
+}
+
+/// Per-domain cookie name rule.
+#[derive(Debug, Deserialize, Clone)]
+pub struct CookieRule {
+    /// Domain the rule applies to (including subdomains).
+    pub domain: String,
+    /// Cookie name globs to allow on this domain.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Cookie name globs to block on this domain.
+    #[serde(default)]
+    pub block: Vec<String>,
 }
 
 /// Configuration for domain blocking.
@@ -74,14 +613,53 @@ fn default_cleanup_interval() -> u64 { 3600 }
 pub struct Config {
     /// Fingerprint randomization settings.
     pub fingerprint: FingerprintConfig,
+    /// Referer/Origin policy settings.
+    #[serde(default)]
+    pub referer: RefererConfig,
     /// Cookie management settings.
     pub cookies: CookiesConfig,
     /// Domain blocking settings.
     pub blocking: BlockingConfig,
+    /// Tracking query parameter settings.
+    #[serde(default)]
+    pub query_params: QueryParamsConfig,
+    /// Bounce-tracking redirect settings.
+    #[serde(default)]
+    pub redirects: RedirectsConfig,
+    /// ETag/Last-Modified tracking defence settings.
+    #[serde(default)]
+    pub cache_tracking: CacheTrackingConfig,
+    /// Header rewrite rules.
+    #[serde(default)]
+    pub header_rules: Vec<HeaderRule>,
+    /// Privacy settings for request and cookie logging.
+    #[serde(default)]
+    pub logging: LoggingConfig,
     /// Database cleanup settings.
     #[serde(default)]
     pub cleanup: CleanupConfig,
-    /// Port to listen on (default: 8080).
+    /// Listen addresses and allowed clients.
+    #[serde(default)]
+    pub listen: ListenConfig,
+    /// Proxy authentication settings.
+    #[serde(default)]
+    pub auth: AuthConfig,
+    /// Upstream proxy settings.
+    #[serde(default)]
+    pub upstream: UpstreamConfig,
+    /// Proxy auto-config settings.
+    #[serde(default)]
+    pub pac: PacConfig,
+    /// Host globs whose connections are tunnelled without TLS interception.
+    #[serde(default)]
+    pub no_intercept: Vec<String>,
+    /// Learned passthrough settings.
+    #[serde(default)]
+    pub tls_fallback: TlsFallbackConfig,
+    /// Interception CA storage settings.
+    #[serde(default)]
+    pub ca: CaConfig,
+    /// Port to listen on when no listen addresses are set (default: 8080).
     pub port: Option<u16>,
     /// Path to the SQLite database file.
     pub db_path: String,
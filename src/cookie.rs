@@ -1,12 +1,69 @@
-// src/cookie.rs
-use crate::config::Config;
-use hyper::{Body, Request, Response};
+use crate::catalogue::{CatalogueEntry, CookieCatalogue};
+use crate::config::{Config, SameSitePolicy};
+use crate::pattern::{domain_matches, glob};
+use hyper::header::{HeaderValue, COOKIE, ORIGIN, REFERER, SET_COOKIE};
+use hyper::{Body, Request, Response, Uri};
+use regex::Regex;
+use std::time::SystemTime;
+
+/// Direction label for cookies sent by the client.
+pub const DIRECTION_REQUEST: &str = "request";
+/// Direction label for cookies set by the server.
+pub const DIRECTION_RESPONSE: &str = "response";
+
+/// The outcome of filtering a single cookie.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CookieDecision {
+    /// Cookie name.
+    pub name: String,
+    /// The cookie as seen on the wire (`name=value`, or the full `Set-Cookie` value).
+    pub raw: String,
+    /// Whether the cookie was removed.
+    pub blocked: bool,
+}
+
+/// A Set-Cookie header whose attributes were hardened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CookieRewrite {
+    /// Cookie name.
+    pub name: String,
+    /// The rewritten `Set-Cookie` value.
+    pub rewritten: String,
+    /// The attributes that were changed (`max-age`, `samesite`, `secure`, `domain`).
+    pub changes: Vec<String>,
+}
+
+/// Hardening settings resolved for a single domain.
+#[derive(Debug, Clone, Copy)]
+struct Hardening {
+    enabled: bool,
+    max_age: Option<u64>,
+    same_site: Option<SameSitePolicy>,
+    secure: bool,
+    strip_domain: bool,
+}
+
+/// Compiled per-domain cookie name rule.
+#[derive(Clone)]
+struct NameRule {
+    domain: String,
+    allow: Vec<Regex>,
+    block: Vec<Regex>,
+}
 
 /// Handles cookie stripping logic based on configuration.
 #[derive(Clone)]
 pub struct CookieHandler {
     /// Application configuration.
     pub config: Config,
+    allow_names: Vec<Regex>,
+    block_names: Vec<Regex>,
+    rules: Vec<NameRule>,
+    catalogue: CookieCatalogue,
+}
+
+fn compile_all(patterns: &[String]) -> anyhow::Result<Vec<Regex>> {
+    patterns.iter().map(|p| glob(p)).collect()
 }
 
 impl CookieHandler {
@@ -15,80 +72,449 @@ impl CookieHandler {
     /// # Arguments
     ///
     /// * `config` - Application configuration.
-    pub fn new(config: Config) -> Self {
-        Self { config }
+    pub fn new(config: Config) -> anyhow::Result<Self> {
+        let cookies = &config.cookies;
+        let rules = cookies
+            .rules
+            .iter()
+            .map(|r| {
+                Ok(NameRule {
+                    domain: r.domain.clone(),
+                    allow: compile_all(&r.allow)?,
+                    block: compile_all(&r.block)?,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self {
+            catalogue: CookieCatalogue::new(&config)?,
+            allow_names: compile_all(&cookies.allow_names)?,
+            block_names: compile_all(&cookies.block_names)?,
+            rules,
+            config,
+        })
     }
 
-    /// Checks and strips cookies from an incoming request.
+    /// Decides whether a cookie should be blocked.
     ///
-    /// Returns the stripped cookie value if one was removed, or None.
+    /// Name rules are more specific than domain lists, so they are checked first:
+    /// per-domain name rules, then the global name lists, then `allow_list`,
+    /// blocked catalogue categories, `block_list` and `block_all`. A domain on
+    /// the allow list thus keeps cookies of blocked categories.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The cookie name.
+    /// * `host` - The hostname the cookie is exchanged with, if known.
+    pub fn should_block(&self, name: &str, host: Option<&str>) -> bool {
+        self.decide(name, host, self.config.cookies.block_all)
+    }
+
+    /// Looks up a cookie name in the cookie catalogue.
+    pub fn classify(&self, name: &str) -> Option<&CatalogueEntry> {
+        self.catalogue.classify(name)
+    }
+
+    /// Decides whether a cookie is blocked by an explicit name or domain rule,
+    /// ignoring `block_all`.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The cookie name.
+    /// * `host` - The hostname the cookie is exchanged with.
+    pub fn blocked_by_rule(&self, name: &str, host: &str) -> bool {
+        self.decide(name, Some(host), false)
+    }
+
+    fn decide(&self, name: &str, host: Option<&str>, block_all: bool) -> bool {
+        if let Some(h) = host {
+            for rule in self.rules.iter().filter(|r| domain_matches(h, &r.domain)) {
+                if rule.allow.iter().any(|p| p.is_match(name)) {
+                    return false;
+                }
+                if rule.block.iter().any(|p| p.is_match(name)) {
+                    return true;
+                }
+            }
+        }
+        if self.block_names.iter().any(|p| p.is_match(name)) {
+            return true;
+        }
+        if self.allow_names.iter().any(|p| p.is_match(name)) {
+            return false;
+        }
+
+        // If host is known, check allow list
+        if let Some(h) = host {
+            if self
+                .config
+                .cookies
+                .allow_list
+                .iter()
+                .any(|d| domain_matches(h, d))
+            {
+                return false;
+            }
+        }
+
+        if self
+            .classify(name)
+            .is_some_and(|e| self.config.cookies.block_categories.contains(&e.category))
+        {
+            return true;
+        }
+
+        // Check block list (if host known) or block_all
+        let explicitly_blocked = host.is_some_and(|h| {
+            self.config
+                .cookies
+                .block_list
+                .iter()
+                .any(|d| domain_matches(h, d))
+        });
+        explicitly_blocked || block_all
+    }
+
+    /// Whether a decision should be reported for logging.
+    ///
+    /// Allowed cookies on allow-listed domains are never logged.
+    fn should_log(&self, decision: &CookieDecision, host: Option<&str>) -> bool {
+        let allow_listed = host.is_some_and(|h| {
+            self.config
+                .cookies
+                .allow_list
+                .iter()
+                .any(|d| domain_matches(h, d))
+        });
+        decision.blocked || (self.config.cookies.log_attempts && !allow_listed)
+    }
+
+    /// Filters the cookies of an incoming request one by one.
+    ///
+    /// Blocked cookies are removed from the `Cookie` header, which is dropped
+    /// entirely once empty. Returns the decisions to log: every blocked cookie,
+    /// plus allowed ones when `log_attempts` is set.
     ///
     /// # Arguments
     ///
     /// * `req` - The mutable HTTP request.
     /// * `host` - The hostname of the request.
-    pub fn strip_cookies_request(&self, req: &mut Request<Body>, host: &str) -> Option<String> {
-        // Check allow list
-        if self.config.cookies.allow_list.iter().any(|d| host.ends_with(d)) {
-            return None;
+    pub fn strip_cookies_request(
+        &self,
+        req: &mut Request<Body>,
+        host: &str,
+    ) -> Vec<CookieDecision> {
+        let header: Vec<String> = req
+            .headers()
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .map(|s| s.to_string())
+            .collect();
+        if header.is_empty() {
+            return Vec::new();
         }
 
-        // Check block list or block_all
-        let explicitly_blocked = self.config.cookies.block_list.iter().any(|d| host.ends_with(d));
-        let should_block = explicitly_blocked || self.config.cookies.block_all;
+        let decisions: Vec<CookieDecision> = header
+            .iter()
+            .flat_map(|h| h.split(';'))
+            .map(|pair| pair.trim())
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let name = cookie_name(pair);
+                CookieDecision {
+                    blocked: self.should_block(&name, Some(host)),
+                    name,
+                    raw: pair.to_string(),
+                }
+            })
+            .collect();
 
-        if should_block {
-            if let Some(cookie) = req.headers_mut().remove(hyper::header::COOKIE) {
-                return cookie.to_str().ok().map(|s| s.to_string());
+        if decisions.iter().any(|d| d.blocked) {
+            let kept: Vec<&str> = decisions
+                .iter()
+                .filter(|d| !d.blocked)
+                .map(|d| d.raw.as_str())
+                .collect();
+            req.headers_mut().remove(COOKIE);
+            if !kept.is_empty() {
+                if let Ok(value) = HeaderValue::from_str(&kept.join("; ")) {
+                    req.headers_mut().insert(COOKIE, value);
+                }
             }
-        } else if self.config.cookies.log_attempts {
-            if let Some(cookie) = req.headers().get(hyper::header::COOKIE) {
-                return cookie.to_str().ok().map(|s| s.to_string());
+        }
+
+        decisions
+            .into_iter()
+            .filter(|d| self.should_log(d, Some(host)))
+            .collect()
+    }
+
+    /// Filters the Set-Cookie headers of an outgoing response one by one.
+    ///
+    /// Returns the decisions to log: every blocked cookie, plus allowed ones when
+    /// `log_attempts` is set.
+    ///
+    /// # Arguments
+    ///
+    /// * `res` - The mutable HTTP response.
+    /// * `host` - The hostname of the request the response answers.
+    pub fn strip_cookies_response(
+        &self,
+        res: &mut Response<Body>,
+        host: &str,
+    ) -> Vec<CookieDecision> {
+        let values: Vec<HeaderValue> = res.headers().get_all(SET_COOKIE).iter().cloned().collect();
+        if values.is_empty() {
+            return Vec::new();
+        }
+
+        let mut decisions = Vec::with_capacity(values.len());
+        let mut kept = Vec::new();
+        for value in values {
+            let raw = String::from_utf8_lossy(value.as_bytes()).to_string();
+            let name = cookie_name(&raw);
+            let blocked = self.should_block(&name, Some(host));
+            if !blocked {
+                kept.push(value);
             }
+            decisions.push(CookieDecision { name, raw, blocked });
         }
-        None
+
+        if decisions.iter().any(|d| d.blocked) {
+            res.headers_mut().remove(SET_COOKIE);
+            for value in kept {
+                res.headers_mut().append(SET_COOKIE, value);
+            }
+        }
+
+        decisions
+            .into_iter()
+            .filter(|d| self.should_log(d, Some(host)))
+            .collect()
     }
 
-    /// Checks and strips Set-Cookie headers from an outgoing response.
+    /// Resolves the hardening settings for a host, applying the first matching
+    /// per-domain override.
+    fn hardening_for(&self, host: Option<&str>) -> Hardening {
+        let harden = &self.config.cookies.harden;
+        let mut settings = Hardening {
+            enabled: harden.enabled,
+            max_age: harden.max_age,
+            same_site: harden.same_site,
+            secure: harden.secure,
+            strip_domain: harden.strip_domain,
+        };
+        let rule = host.and_then(|h| harden.rules.iter().find(|r| domain_matches(h, &r.domain)));
+        if let Some(rule) = rule {
+            settings.enabled = rule.enabled.unwrap_or(settings.enabled);
+            settings.max_age = rule.max_age.or(settings.max_age);
+            settings.same_site = rule.same_site.or(settings.same_site);
+            settings.secure = rule.secure.unwrap_or(settings.secure);
+            settings.strip_domain = rule.strip_domain.unwrap_or(settings.strip_domain);
+        }
+        settings
+    }
+
+    /// Rewrites the attributes of the remaining Set-Cookie headers.
     ///
-    /// Returns the stripped cookie value if one was removed, or None.
+    /// Lifetimes are capped, `SameSite` is raised, `Secure` is added on HTTPS
+    /// and `Domain` is removed, as configured for the host. Deletion cookies
+    /// (expiry in the past) are left untouched. Returns one entry per rewritten
+    /// cookie.
     ///
     /// # Arguments
     ///
     /// * `res` - The mutable HTTP response.
-    /// * `host` - The hostname of the request (optional, as it might not be available in response context).
-    pub fn strip_cookies_response(&self, res: &mut Response<Body>, host: Option<&str>) -> Option<String> {
-        // If host is known, check allow list
-        if let Some(h) = host {
-            if self.config.cookies.allow_list.iter().any(|d| h.ends_with(d)) {
-                return None;
+    /// * `host` - The hostname of the request, if known.
+    /// * `https` - Whether the response was received over HTTPS.
+    pub fn harden_set_cookies(
+        &self,
+        res: &mut Response<Body>,
+        host: Option<&str>,
+        https: bool,
+    ) -> Vec<CookieRewrite> {
+        let settings = self.hardening_for(host);
+        if !settings.enabled {
+            return Vec::new();
+        }
+
+        let values: Vec<HeaderValue> = res.headers().get_all(SET_COOKIE).iter().cloned().collect();
+        let now = SystemTime::now();
+        let mut rewrites = Vec::new();
+        let mut hardened = Vec::with_capacity(values.len());
+        for value in values {
+            let rewritten = value
+                .to_str()
+                .ok()
+                .and_then(|raw| harden(raw, &settings, https, now))
+                .and_then(|(raw, changes)| Some((HeaderValue::from_str(&raw).ok()?, raw, changes)));
+            match rewritten {
+                Some((new_value, raw, changes)) => {
+                    rewrites.push(CookieRewrite {
+                        name: cookie_name(&raw),
+                        rewritten: raw,
+                        changes,
+                    });
+                    hardened.push(new_value);
+                }
+                None => hardened.push(value),
             }
         }
 
-        // Check block list (if host known) or block_all
-        let explicitly_blocked = host.map_or(false, |h| {
-            self.config.cookies.block_list.iter().any(|d| h.ends_with(d))
-        });
-        
-        let should_block = explicitly_blocked || self.config.cookies.block_all;
+        if !rewrites.is_empty() {
+            res.headers_mut().remove(SET_COOKIE);
+            for value in hardened {
+                res.headers_mut().append(SET_COOKIE, value);
+            }
+        }
+        rewrites
+    }
+}
 
-        if should_block {
-            if let Some(cookie) = res.headers_mut().remove(hyper::header::SET_COOKIE) {
-                return cookie.to_str().ok().map(|s| s.to_string());
+/// Applies hardening settings to a single `Set-Cookie` value.
+///
+/// Returns the rewritten value and the changed attributes, or None if nothing
+/// changed.
+fn harden(
+    raw: &str,
+    settings: &Hardening,
+    https: bool,
+    now: SystemTime,
+) -> Option<(String, Vec<String>)> {
+    let mut parts = raw.split(';').map(str::trim);
+    let pair = parts.next()?;
+    let mut attrs: Vec<String> = parts.filter(|a| !a.is_empty()).map(String::from).collect();
+    let key = |a: &str| a.split('=').next().unwrap_or(a).trim().to_ascii_lowercase();
+    let value = |a: &str| a.split_once('=').map(|(_, v)| v.trim().to_string());
+    let mut changes = Vec::new();
+
+    if let Some(cap) = settings.max_age {
+        let find = |name: &str| attrs.iter().find(|a| key(a) == name).map(|a| value(a));
+        // Max-Age takes precedence over Expires; an unparsable Expires is left alone
+        let lifetime = match (find("max-age"), find("expires")) {
+            (Some(max_age), _) => max_age.and_then(|v| v.parse::<i64>().ok()),
+            (None, Some(expires)) => expires.and_then(|v| parse_expires(&v)).map(|t| {
+                t.duration_since(now)
+                    .map(|d| d.as_secs() as i64)
+                    .unwrap_or(0)
+            }),
+            (None, None) => None,
+        };
+        if lifetime.is_some_and(|l| l > 0 && l as u64 > cap) {
+            attrs.retain(|a| !matches!(key(a).as_str(), "max-age" | "expires"));
+            if cap > 0 {
+                attrs.push(format!("Max-Age={}", cap));
             }
-        } else if self.config.cookies.log_attempts {
-            if let Some(cookie) = res.headers().get(hyper::header::SET_COOKIE) {
-                return cookie.to_str().ok().map(|s| s.to_string());
+            changes.push("max-age".to_string());
+        }
+    }
+
+    if let Some(policy) = settings.same_site {
+        let current = attrs.iter().position(|a| key(a) == "samesite");
+        let current_value = current
+            .and_then(|i| value(&attrs[i]))
+            .map(|v| v.to_ascii_lowercase());
+        let weaker = !matches!(
+            (policy, current_value.as_deref()),
+            (_, Some("strict")) | (SameSitePolicy::Lax, Some("lax"))
+        );
+        if weaker {
+            let attr = match policy {
+                SameSitePolicy::Lax => "SameSite=Lax".to_string(),
+                SameSitePolicy::Strict => "SameSite=Strict".to_string(),
+            };
+            match current {
+                Some(i) => attrs[i] = attr,
+                None => attrs.push(attr),
             }
+            changes.push("samesite".to_string());
+        }
+    }
+
+    if settings.secure && https && !attrs.iter().any(|a| key(a) == "secure") {
+        attrs.push("Secure".to_string());
+        changes.push("secure".to_string());
+    }
+
+    if settings.strip_domain {
+        let before = attrs.len();
+        attrs.retain(|a| key(a) != "domain");
+        if attrs.len() != before {
+            changes.push("domain".to_string());
+        }
+    }
+
+    if changes.is_empty() {
+        return None;
+    }
+    let rewritten = std::iter::once(pair.to_string())
+        .chain(attrs)
+        .collect::<Vec<_>>()
+        .join("; ");
+    Some((rewritten, changes))
+}
+
+/// Parses a cookie `Expires` date, accepting the common dashed form
+/// (`Thu, 01-Jan-2099 00:00:00 GMT`) as well as HTTP dates.
+pub(crate) fn parse_expires(value: &str) -> Option<SystemTime> {
+    httpdate::parse_http_date(value)
+        .or_else(|_| httpdate::parse_http_date(&value.replace('-', " ")))
+        .ok()
+}
+
+/// Determines the first-party site a request is made on behalf of.
+///
+/// Top-level navigations are their own first party. They are recognised by
+/// `Sec-Fetch-Dest: document`, or, from clients that do not send it, by
+/// `Sec-Fetch-Mode: navigate` or an `Accept` asking for HTML. Subresource and
+/// iframe requests are attributed to the page in `Origin` or `Referer`,
+/// falling back to the request host when neither is present. Must be called
+/// before the referer policy strips those headers.
+///
+/// # Arguments
+///
+/// * `req` - The HTTP request.
+/// * `host` - The hostname of the request.
+pub fn first_party_site(req: &Request<Body>, host: &str) -> String {
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let top_level = match header("sec-fetch-dest") {
+        Some(dest) => dest == "document",
+        None => {
+            header("sec-fetch-mode") == Some("navigate")
+                || header("accept").is_some_and(|accept| {
+                    accept.split(',').any(|range| {
+                        let media = range.split(';').next().unwrap_or_default();
+                        media.trim().eq_ignore_ascii_case("text/html")
+                    })
+                })
         }
-        None
+    };
+    if top_level {
+        return host.to_string();
     }
+    [ORIGIN.as_str(), REFERER.as_str()]
+        .into_iter()
+        .filter_map(header)
+        .filter_map(|v| v.parse::<Uri>().ok())
+        .find_map(|u| u.host().map(|h| h.to_ascii_lowercase()))
+        .unwrap_or_else(|| host.to_string())
+}
+
+/// Extracts the cookie name from a `name=value` pair or a `Set-Cookie` value.
+pub(crate) fn cookie_name(cookie: &str) -> String {
+    let pair = cookie.split(';').next().unwrap_or(cookie);
+    pair.split('=').next().unwrap_or(pair).trim().to_string()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::{BlockingConfig, CleanupConfig, Config, CookiesConfig, FingerprintConfig};
+    use crate::config::{
+        AuthConfig, BlockingConfig, CaConfig, CacheTrackingConfig, CleanupConfig, Config,
+        CookieCategory, CookieHardening, CookieHardeningRule, CookieJarConfig, CookieRule,
+        CookiesConfig, EphemeralConfig, FingerprintConfig, ListenConfig, LoggingConfig, PacConfig,
+        QueryParamsConfig, RedirectsConfig, RefererConfig, TlsFallbackConfig, UpstreamConfig,
+    };
 
     fn create_test_config(
         block_all: bool,
@@ -104,56 +530,363 @@ mod tests {
                 strip_referer: false,
                 accept_languages: vec![],
             },
+            referer: RefererConfig::default(),
             cookies: CookiesConfig {
                 block_all,
                 log_attempts: true,
                 allow_list,
                 block_list,
+                allow_names: vec![],
+                block_names: vec![],
+                rules: vec![],
+                block_categories: vec![],
+                catalogue_file: None,
+                harden: CookieHardening::default(),
+                jar: CookieJarConfig::default(),
+                ephemeral: EphemeralConfig::default(),
             },
             blocking: BlockingConfig {
                 auto_block: false,
                 auto_block_threshold: 0,
                 block_patterns: vec![],
             },
+            query_params: QueryParamsConfig::default(),
+            redirects: RedirectsConfig::default(),
+            cache_tracking: CacheTrackingConfig::default(),
+            logging: LoggingConfig::default(),
+            listen: ListenConfig::default(),
+            auth: AuthConfig::default(),
+            upstream: UpstreamConfig::default(),
+            pac: PacConfig::default(),
+            no_intercept: vec![],
+            tls_fallback: TlsFallbackConfig::default(),
+            ca: CaConfig::default(),
+            header_rules: vec![],
             cleanup: CleanupConfig::default(),
             port: None,
             db_path: ":memory:".to_string(),
         }
     }
 
+    fn blocked(name: &str, raw: &str) -> CookieDecision {
+        CookieDecision {
+            name: name.to_string(),
+            raw: raw.to_string(),
+            blocked: true,
+        }
+    }
+
     #[test]
     fn test_strip_cookies_block_all() {
         let config = create_test_config(true, vec![], vec![]);
-        let handler = CookieHandler::new(config);
+        let handler = CookieHandler::new(config).unwrap();
         let mut req = Request::new(Body::empty());
-        req.headers_mut().insert(hyper::header::COOKIE, "foo=bar".parse().unwrap());
+        req.headers_mut()
+            .insert(hyper::header::COOKIE, "foo=bar".parse().unwrap());
 
         let stripped = handler.strip_cookies_request(&mut req, "example.com");
-        assert_eq!(stripped, Some("foo=bar".to_string()));
+        assert_eq!(stripped, vec![blocked("foo", "foo=bar")]);
         assert!(req.headers().get(hyper::header::COOKIE).is_none());
     }
 
     #[test]
     fn test_strip_cookies_allow_list() {
         let config = create_test_config(true, vec!["trusted.com".to_string()], vec![]);
-        let handler = CookieHandler::new(config);
+        let handler = CookieHandler::new(config).unwrap();
         let mut req = Request::new(Body::empty());
-        req.headers_mut().insert(hyper::header::COOKIE, "foo=bar".parse().unwrap());
+        req.headers_mut()
+            .insert(hyper::header::COOKIE, "foo=bar".parse().unwrap());
 
         let stripped = handler.strip_cookies_request(&mut req, "trusted.com");
-        assert_eq!(stripped, None);
+        assert!(stripped.is_empty());
         assert!(req.headers().get(hyper::header::COOKIE).is_some());
+
+        // Subdomains are allowed, look-alike domains are not
+        assert!(!handler.should_block("foo", Some("www.trusted.com")));
+        assert!(handler.should_block("foo", Some("untrusted.com")));
     }
 
     #[test]
     fn test_strip_cookies_block_list_override() {
         let config = create_test_config(false, vec![], vec!["evil.com".to_string()]);
-        let handler = CookieHandler::new(config);
+        let handler = CookieHandler::new(config).unwrap();
         let mut req = Request::new(Body::empty());
-        req.headers_mut().insert(hyper::header::COOKIE, "foo=bar".parse().unwrap());
+        req.headers_mut()
+            .insert(hyper::header::COOKIE, "foo=bar".parse().unwrap());
 
         let stripped = handler.strip_cookies_request(&mut req, "evil.com");
-        assert_eq!(stripped, Some("foo=bar".to_string()));
+        assert_eq!(stripped, vec![blocked("foo", "foo=bar")]);
         assert!(req.headers().get(hyper::header::COOKIE).is_none());
+
+        assert!(handler.should_block("foo", Some("cdn.evil.com")));
+        assert!(!handler.should_block("foo", Some("notevil.com")));
+    }
+
+    #[test]
+    fn test_strip_individual_cookies_by_name() {
+        let mut config = create_test_config(false, vec!["github.com".to_string()], vec![]);
+        config.cookies.block_names = vec!["_ga*".to_string(), "_fbp".to_string()];
+        let handler = CookieHandler::new(config).unwrap();
+        let mut req = Request::new(Body::empty());
+        req.headers_mut().insert(
+            COOKIE,
+            "_ga=GA1.2.3; user_session=abc; _gat_UA=1; _fbp=fb.1"
+                .parse()
+                .unwrap(),
+        );
+
+        let decisions = handler.strip_cookies_request(&mut req, "github.com");
+        let names: Vec<&str> = decisions.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, vec!["_ga", "_gat_UA", "_fbp"]);
+        assert!(decisions.iter().all(|d| d.blocked));
+        assert_eq!(req.headers()[COOKIE], "user_session=abc");
+    }
+
+    #[test]
+    fn test_block_by_category() {
+        let mut config = create_test_config(false, vec!["github.com".to_string()], vec![]);
+        config.cookies.block_categories = vec![CookieCategory::Analytics];
+        config.cookies.allow_names = vec!["_gid".to_string()];
+        let handler = CookieHandler::new(config).unwrap();
+
+        assert!(handler.should_block("_ga", Some("example.org")));
+        assert!(!handler.should_block("_gid", Some("example.org")));
+        assert!(!handler.should_block("_fbp", Some("example.org")));
+        // The allow list keeps cookies of blocked categories
+        assert!(!handler.should_block("_ga", Some("github.com")));
+        assert!(!handler.should_block("_ga", Some("gist.github.com")));
+        assert!(handler.should_block("_ga", Some("evilgithub.com")));
+        assert_eq!(handler.classify("_fbp").unwrap().vendor, "Meta");
+    }
+
+    #[test]
+    fn test_per_domain_cookie_rules() {
+        let mut config = create_test_config(true, vec![], vec![]);
+        config.cookies.rules = vec![CookieRule {
+            domain: "example.com".to_string(),
+            allow: vec!["sid".to_string()],
+            block: vec![],
+        }];
+        let handler = CookieHandler::new(config).unwrap();
+
+        assert!(!handler.should_block("sid", Some("www.example.com")));
+        assert!(handler.should_block("IDE", Some("www.example.com")));
+        assert!(handler.should_block("sid", Some("other.com")));
+    }
+
+    #[test]
+    fn test_strip_set_cookie_individually() {
+        let mut config = create_test_config(false, vec![], vec![]);
+        config.cookies.log_attempts = false;
+        config.cookies.block_names = vec!["IDE".to_string()];
+        let handler = CookieHandler::new(config).unwrap();
+        let mut res = Response::new(Body::empty());
+        res.headers_mut().append(
+            SET_COOKIE,
+            "IDE=xyz; Domain=.doubleclick.net; Max-Age=31536000"
+                .parse()
+                .unwrap(),
+        );
+        res.headers_mut()
+            .append(SET_COOKIE, "lang=en; Path=/".parse().unwrap());
+
+        let decisions = handler.strip_cookies_response(&mut res, "doubleclick.net");
+        assert_eq!(
+            decisions,
+            vec![blocked(
+                "IDE",
+                "IDE=xyz; Domain=.doubleclick.net; Max-Age=31536000"
+            )]
+        );
+        let remaining: Vec<_> = res.headers().get_all(SET_COOKIE).iter().collect();
+        assert_eq!(remaining, vec!["lang=en; Path=/"]);
+    }
+
+    fn hardening_handler() -> CookieHandler {
+        let mut config = create_test_config(false, vec![], vec![]);
+        config.cookies.harden = CookieHardening {
+            enabled: true,
+            max_age: Some(3600),
+            same_site: Some(SameSitePolicy::Lax),
+            secure: true,
+            strip_domain: true,
+            rules: vec![CookieHardeningRule {
+                domain: "bank.example".to_string(),
+                enabled: None,
+                max_age: Some(0),
+                same_site: Some(SameSitePolicy::Strict),
+                secure: None,
+                strip_domain: None,
+            }],
+        };
+        CookieHandler::new(config).unwrap()
+    }
+
+    fn harden_one(handler: &CookieHandler, host: &str, https: bool, cookie: &str) -> String {
+        let mut res = Response::new(Body::empty());
+        res.headers_mut()
+            .append(SET_COOKIE, cookie.parse().unwrap());
+        handler.harden_set_cookies(&mut res, Some(host), https);
+        res.headers()[SET_COOKIE].to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_harden_set_cookie_attributes() {
+        let handler = hardening_handler();
+
+        let mut res = Response::new(Body::empty());
+        res.headers_mut().append(
+            SET_COOKIE,
+            "uid=42; Domain=.example.com; Path=/; Expires=Thu, 01-Jan-2099 00:00:00 GMT; SameSite=None"
+                .parse()
+                .unwrap(),
+        );
+        let rewrites = handler.harden_set_cookies(&mut res, Some("www.example.com"), true);
+        assert_eq!(rewrites.len(), 1);
+        assert_eq!(rewrites[0].name, "uid");
+        assert_eq!(
+            rewrites[0].changes,
+            vec!["max-age", "samesite", "secure", "domain"]
+        );
+        assert_eq!(
+            res.headers()[SET_COOKIE],
+            "uid=42; Path=/; SameSite=Lax; Max-Age=3600; Secure"
+        );
+
+        // Plain HTTP does not get Secure; shorter lifetimes and Strict are kept
+        assert_eq!(
+            harden_one(
+                &handler,
+                "example.com",
+                false,
+                "a=1; Max-Age=60; SameSite=Strict"
+            ),
+            "a=1; Max-Age=60; SameSite=Strict"
+        );
+    }
+
+    #[test]
+    fn test_harden_keeps_deletions_and_applies_domain_rules() {
+        let handler = hardening_handler();
+
+        let deletion = "sid=; Expires=Thu, 01 Jan 1970 00:00:00 GMT; SameSite=Lax; Secure";
+        assert_eq!(
+            harden_one(&handler, "example.com", true, deletion),
+            deletion
+        );
+
+        // Per-domain override: session cookie and SameSite=Strict
+        assert_eq!(
+            harden_one(
+                &handler,
+                "login.bank.example",
+                true,
+                "sid=1; Max-Age=86400; SameSite=Lax; Secure"
+            ),
+            "sid=1; SameSite=Strict; Secure"
+        );
+    }
+
+    #[test]
+    fn test_response_allow_list_uses_host() {
+        let config = create_test_config(true, vec!["github.com".to_string()], vec![]);
+        let handler = CookieHandler::new(config).unwrap();
+        let set_cookie = || {
+            let mut res = Response::new(Body::empty());
+            res.headers_mut()
+                .append(SET_COOKIE, "user_session=abc; Path=/".parse().unwrap());
+            res
+        };
+
+        let mut res = set_cookie();
+        assert!(handler
+            .strip_cookies_response(&mut res, "github.com")
+            .is_empty());
+        assert!(res.headers().contains_key(SET_COOKIE));
+
+        let mut res = set_cookie();
+        let decisions = handler.strip_cookies_response(&mut res, "example.com");
+        assert!(decisions[0].blocked);
+        assert!(!res.headers().contains_key(SET_COOKIE));
+    }
+
+    #[test]
+    fn test_first_party_site() {
+        let req = |headers: &[(&str, &str)]| {
+            let mut builder = Request::builder().uri("https://cdn.tracker.net/pixel.gif");
+            for (name, value) in headers {
+                builder = builder.header(*name, *value);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let embedded = req(&[("referer", "https://news.example.com/article")]);
+        assert_eq!(
+            first_party_site(&embedded, "cdn.tracker.net"),
+            "news.example.com"
+        );
+
+        let cors = req(&[
+            ("origin", "https://shop.example.org"),
+            ("referer", "https://news.example.com/"),
+        ]);
+        assert_eq!(
+            first_party_site(&cors, "cdn.tracker.net"),
+            "shop.example.org"
+        );
+
+        let navigation = req(&[
+            ("sec-fetch-dest", "document"),
+            ("referer", "https://news.example.com/"),
+        ]);
+        assert_eq!(
+            first_party_site(&navigation, "cdn.tracker.net"),
+            "cdn.tracker.net"
+        );
+        assert_eq!(
+            first_party_site(&req(&[]), "cdn.tracker.net"),
+            "cdn.tracker.net"
+        );
+
+        // Without Sec-Fetch-Dest, a navigation with only a Referer is still
+        // top-level
+        let navigation = req(&[
+            (
+                "accept",
+                "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+            ),
+            ("referer", "https://news.example.com/"),
+        ]);
+        assert_eq!(
+            first_party_site(&navigation, "cdn.tracker.net"),
+            "cdn.tracker.net"
+        );
+        let navigation = req(&[
+            ("sec-fetch-mode", "navigate"),
+            ("referer", "https://news.example.com/"),
+        ]);
+        assert_eq!(
+            first_party_site(&navigation, "cdn.tracker.net"),
+            "cdn.tracker.net"
+        );
+        // Iframes navigate too, but are not top-level
+        let iframe = req(&[
+            ("sec-fetch-dest", "iframe"),
+            ("sec-fetch-mode", "navigate"),
+            ("accept", "text/html"),
+            ("referer", "https://news.example.com/"),
+        ]);
+        assert_eq!(
+            first_party_site(&iframe, "cdn.tracker.net"),
+            "news.example.com"
+        );
+        let image = req(&[
+            ("accept", "image/avif,image/webp,*/*"),
+            ("referer", "https://news.example.com/"),
+        ]);
+        assert_eq!(
+            first_party_site(&image, "cdn.tracker.net"),
+            "news.example.com"
+        );
     }
 }
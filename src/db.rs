@@ -1,30 +1,43 @@
 use anyhow::Result;
 use rusqlite::{params, Connection};
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use tokio::sync::{mpsc, Mutex};
 
 /// Represents a loggable event in the proxy.
 #[derive(Debug)]
 pub enum LogEvent {
-    /// A cookie was processed (and potentially blocked).
-    Cookie {
+    /// Cache validator headers were removed from an exchange.
+    CacheValidators {
         domain: String,
-        cookie: String,
-        blocked: bool,
+        headers: Vec<String>,
+    },
+    /// A single cookie was processed (and potentially blocked).
+    Cookie(CookieRecord),
+    /// A Set-Cookie header was hardened.
+    CookieRewrite {
+        domain: String,
+        name: String,
+        changes: Vec<String>,
     },
     /// A fingerprint rotation occurred.
     Fingerprint {
         user_agent: String,
         accept_language: String,
         mode: String,
+        /// Authenticated user whose request triggered the rotation.
+        user: Option<String>,
     },
+    /// A connection from a client outside the allow list was refused.
+    RejectedClient { client_ip: String, listener: String },
     /// A request was proxied.
-    Request {
-        domain: String,
-        path: String,
-        user_agent: String,
-        client_ip: String,
-    },
+    Request(RequestRecord),
+    /// The response to a logged request was received or synthesized.
+    Response(ResponseRecord),
+    /// A connection was tunnelled without interception.
+    Tunnel(TunnelRecord),
+    /// Tracking query parameters were removed from a request URI.
+    StrippedParams { domain: String, params: Vec<String> },
     /// A tracking domain was hit.
     TrackerHit {
         domain: String,
@@ -32,6 +45,170 @@ pub enum LogEvent {
     },
 }
 
+/// A proxied request, as stored in `request_log`.
+#[derive(Debug, Clone, Default)]
+pub struct RequestRecord {
+    pub domain: String,
+    pub path: String,
+    pub user_agent: String,
+    /// IP address of the client connection.
+    pub client_ip: String,
+    /// Source port of the client connection.
+    pub client_port: Option<u16>,
+    pub method: String,
+    /// `http` or `https`.
+    pub scheme: String,
+    /// Whether the request arrived through a CONNECT tunnel.
+    pub via_connect: bool,
+    /// Whether the proxy blocked the request.
+    pub blocked: bool,
+    /// Authenticated proxy user, if authentication is enabled.
+    pub user: Option<String>,
+    /// Route the request was sent along: `direct` or an upstream proxy name.
+    pub route: Option<String>,
+    /// Proxy-assigned exchange id, used to attach the response.
+    pub exchange: u64,
+}
+
+/// Response metadata for a logged request.
+#[derive(Debug, Clone, Default)]
+pub struct ResponseRecord {
+    /// Exchange id of the request this response belongs to.
+    pub exchange: u64,
+    pub status: u16,
+    pub content_type: Option<String>,
+    /// Content length, or the streamed byte count.
+    pub bytes: Option<u64>,
+    /// Time from forwarding the request to receiving the response headers.
+    pub latency_ms: Option<u64>,
+}
+
+/// A connection tunnelled without interception, as stored in `tunnel_log`.
+#[derive(Debug, Clone, Default)]
+pub struct TunnelRecord {
+    /// Destination host, preferring the TLS server name over an address.
+    pub domain: String,
+    pub port: u16,
+    /// IP address of the client connection.
+    pub client_ip: String,
+    /// Source port of the client connection.
+    pub client_port: Option<u16>,
+    /// Authenticated proxy user, if authentication is enabled.
+    pub user: Option<String>,
+    /// Route the tunnel was opened along; None when it was blocked.
+    pub route: Option<String>,
+    /// Why the stream was not intercepted: `no_intercept`, `learned`,
+    /// `protocol` or `blocked`.
+    pub reason: String,
+    /// Whether the proxy refused the connection.
+    pub blocked: bool,
+    /// Bytes sent by the client.
+    pub bytes_sent: u64,
+    /// Bytes received from the destination.
+    pub bytes_received: u64,
+    pub duration_ms: u64,
+}
+
+/// An entry of the learned passthrough list, as stored in
+/// `learned_passthrough`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LearnedHost {
+    pub host: String,
+    /// `learned`, `promoted` or `revoked`.
+    pub status: String,
+    /// Failed handshakes counted when the host was learned.
+    pub failures: i64,
+    pub learned_at: String,
+    /// When the entry was last promoted or revoked.
+    pub reviewed_at: Option<String>,
+}
+
+/// Exchanges awaiting their response kept by the logger; older ones are
+/// dropped, as their requests most likely failed upstream.
+const MAX_PENDING_EXCHANGES: usize = 4096;
+
+/// A single cookie seen by the proxy, as stored in `cookie_traffic`.
+#[derive(Debug, Clone)]
+pub struct CookieRecord {
+    /// Host the cookie was exchanged with.
+    pub domain: String,
+    /// First-party site of the exchange.
+    pub site: String,
+    pub name: String,
+    /// The cookie as seen on the wire.
+    pub cookie: String,
+    /// `request` or `response`.
+    pub direction: String,
+    pub blocked: bool,
+    /// Vendor from the cookie catalogue, if known.
+    pub vendor: Option<String>,
+    /// Category from the cookie catalogue, if known.
+    pub category: Option<String>,
+    /// Authenticated proxy user, if authentication is enabled.
+    pub user: Option<String>,
+}
+
+/// A cookie held in the partitioned cookie jar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JarCookie {
+    /// Top-level site the cookie was set under.
+    pub site: String,
+    /// Domain the cookie is sent to.
+    pub domain: String,
+    pub name: String,
+    pub path: String,
+    pub value: String,
+    /// Whether the cookie is sent to `domain` only, not its subdomains.
+    pub host_only: bool,
+    pub secure: bool,
+    /// Expiry as a Unix timestamp; None for session cookies.
+    pub expires: Option<i64>,
+}
+
+/// Columns added to existing tables after their first release, as
+/// `(table, column, declaration)`. `schema.sql` already contains them for new
+/// databases; older databases are upgraded when opened.
+const MIGRATIONS: &[(&str, &str, &str)] = &[
+    ("cookie_traffic", "name", "TEXT"),
+    ("cookie_traffic", "direction", "TEXT"),
+    ("cookie_traffic", "site", "TEXT"),
+    ("cookie_traffic", "vendor", "TEXT"),
+    ("cookie_traffic", "category", "TEXT"),
+    ("request_log", "client_port", "INTEGER"),
+    ("request_log", "method", "TEXT"),
+    ("request_log", "scheme", "TEXT"),
+    ("request_log", "via_connect", "INTEGER DEFAULT 0"),
+    ("request_log", "blocked", "INTEGER DEFAULT 0"),
+    ("request_log", "status", "INTEGER"),
+    ("request_log", "content_type", "TEXT"),
+    ("request_log", "response_bytes", "INTEGER"),
+    ("request_log", "latency_ms", "INTEGER"),
+    ("request_log", "username", "TEXT"),
+    ("request_log", "route", "TEXT"),
+    ("cookie_traffic", "username", "TEXT"),
+    ("fingerprint_rotations", "username", "TEXT"),
+    ("whitelist", "added_at", "DATETIME"),
+];
+
+/// Adds any missing columns listed in `MIGRATIONS`.
+fn migrate(conn: &Connection) -> Result<()> {
+    for (table, column, declaration) in MIGRATIONS {
+        let exists = conn
+            .prepare(&format!(
+                "SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1",
+                table
+            ))?
+            .exists([column])?;
+        if !exists {
+            conn.execute_batch(&format!(
+                "ALTER TABLE {} ADD COLUMN {} {}",
+                table, column, declaration
+            ))?;
+        }
+    }
+    Ok(())
+}
+
 /// Thread-safe wrapper around the SQLite database connection.
 #[derive(Clone)]
 pub struct Database {
@@ -48,6 +225,7 @@ impl Database {
         let conn = Connection::open(path)?;
         // Execute schema (assumes schema.sql is placed next to Cargo.toml)
         conn.execute_batch(include_str!("../schema.sql"))?;
+        migrate(&conn)?;
         Ok(Self {
             conn: Arc::new(Mutex::new(conn)),
         })
@@ -58,42 +236,472 @@ impl Database {
         &self.conn
     }
 
-    /// Logs a cookie event.
-    pub async fn log_cookie(&self, domain: String, cookie: String, blocked: bool) -> Result<()> {
+    /// Logs a single cookie event.
+    pub async fn log_cookie(&self, record: &CookieRecord) -> Result<()> {
         let conn = self.conn.lock().await;
         conn.execute(
-            "INSERT INTO cookie_traffic (domain, cookie, blocked) VALUES (?1, ?2, ?3)",
-            params![domain, cookie, blocked],
+            "INSERT INTO cookie_traffic
+                 (domain, site, name, cookie, direction, blocked, vendor, category, username)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                record.domain,
+                record.site,
+                record.name,
+                record.cookie,
+                record.direction,
+                record.blocked,
+                record.vendor,
+                record.category,
+                record.user
+            ],
         )?;
         Ok(())
     }
 
-    /// Logs a fingerprint rotation event.
-    pub async fn log_fingerprint(&self, ua: String, lang: String, mode: String) -> Result<()> {
+    /// Logs a hardened Set-Cookie header.
+    pub async fn log_cookie_rewrite(
+        &self,
+        domain: &str,
+        name: &str,
+        changes: &[String],
+    ) -> Result<()> {
         let conn = self.conn.lock().await;
         conn.execute(
-            "INSERT INTO fingerprint_rotations (user_agent, accept_language, mode) VALUES (?1, ?2, ?3)",
-            params![ua, lang, mode],
+            "INSERT INTO cookie_rewrites (domain, name, changes) VALUES (?1, ?2, ?3)",
+            params![domain, name, changes.join(",")],
         )?;
         Ok(())
     }
 
-    /// Logs a proxied request.
-    pub async fn log_request(
+    /// Logs a fingerprint rotation event.
+    pub async fn log_fingerprint(
         &self,
-        domain: String,
-        path: String,
         ua: String,
-        client_ip: String,
+        lang: String,
+        mode: String,
+        user: Option<String>,
     ) -> Result<()> {
         let conn = self.conn.lock().await;
         conn.execute(
-            "INSERT INTO request_log (domain, path, user_agent, client_ip) VALUES (?1, ?2, ?3, ?4)",
-            params![domain, path, ua, client_ip],
+            "INSERT INTO fingerprint_rotations (user_agent, accept_language, mode, username)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![ua, lang, mode, user],
+        )?;
+        Ok(())
+    }
+
+    /// Logs a proxied request.
+    ///
+    /// Returns the row id of the new `request_log` entry.
+    pub async fn log_request(&self, record: &RequestRecord) -> Result<i64> {
+        let conn = self.conn.lock().await;
+        conn.execute(
+            "INSERT INTO request_log
+                 (domain, path, user_agent, client_ip, client_port, method, scheme, via_connect,
+                  blocked, username, route)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                record.domain,
+                record.path,
+                record.user_agent,
+                record.client_ip,
+                record.client_port,
+                record.method,
+                record.scheme,
+                record.via_connect,
+                record.blocked,
+                record.user,
+                record.route
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Stores response metadata on the `request_log` entry with the given row id.
+    pub async fn log_response(&self, row: i64, record: &ResponseRecord) -> Result<()> {
+        let conn = self.conn.lock().await;
+        conn.execute(
+            "UPDATE request_log SET status = ?2, content_type = ?3, response_bytes = ?4,
+                 latency_ms = ?5
+             WHERE id = ?1",
+            params![
+                row,
+                record.status,
+                record.content_type,
+                record.bytes.map(|b| b as i64),
+                record.latency_ms.map(|l| l as i64)
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Retrieves the domains that served the most bytes as
+    /// (domain, bytes, average latency in ms).
+    pub async fn get_top_domains_by_bytes(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<(String, i64, Option<f64>)>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT domain, SUM(response_bytes) AS total, AVG(latency_ms) FROM request_log
+             WHERE blocked = 0 AND response_bytes IS NOT NULL
+             GROUP BY domain ORDER BY total DESC LIMIT ?1",
+        )?;
+        let rows = stmt.query_map([limit as i64], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        let mut vec = Vec::new();
+        for r in rows {
+            vec.push(r?);
+        }
+        Ok(vec)
+    }
+
+    /// Estimates the bytes saved by blocking: each blocked request counts as
+    /// the average response size its domain had while it was still served.
+    pub async fn get_bytes_saved(&self) -> Result<i64> {
+        let conn = self.conn.lock().await;
+        let saved: Option<f64> = conn.query_row(
+            "SELECT SUM(served.avg_bytes) FROM request_log AS b
+             JOIN (SELECT domain, AVG(response_bytes) AS avg_bytes FROM request_log
+                   WHERE blocked = 0 AND response_bytes IS NOT NULL GROUP BY domain) AS served
+               ON served.domain = b.domain
+             WHERE b.blocked = 1",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(saved.unwrap_or(0.0) as i64)
+    }
+
+    /// Logs a connection tunnelled without interception.
+    pub async fn log_tunnel(&self, record: &TunnelRecord) -> Result<()> {
+        let conn = self.conn.lock().await;
+        conn.execute(
+            "INSERT INTO tunnel_log
+                 (domain, port, client_ip, client_port, username, route, reason, blocked,
+                  bytes_sent, bytes_received, duration_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                record.domain,
+                record.port,
+                record.client_ip,
+                record.client_port,
+                record.user,
+                record.route,
+                record.reason,
+                record.blocked,
+                record.bytes_sent as i64,
+                record.bytes_received as i64,
+                record.duration_ms as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Retrieves the domains with the most tunnelled traffic as
+    /// (domain, tunnels, blocked, bytes sent, bytes received).
+    pub async fn get_tunnels_by_domain(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<(String, i64, i64, i64, i64)>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT domain, COUNT(*), SUM(blocked), SUM(bytes_sent), SUM(bytes_received)
+             FROM tunnel_log GROUP BY domain
+             ORDER BY SUM(bytes_sent + bytes_received) DESC, COUNT(*) DESC LIMIT ?1",
+        )?;
+        let rows = stmt.query_map([limit as i64], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ))
+        })?;
+        let mut vec = Vec::new();
+        for r in rows {
+            vec.push(r?);
+        }
+        Ok(vec)
+    }
+
+    /// Counts a refused client connection on a listener.
+    pub async fn log_rejected_client(&self, client_ip: &str, listener: &str) -> Result<()> {
+        let conn = self.conn.lock().await;
+        conn.execute(
+            "INSERT INTO rejected_clients (client_ip, listener, hit_count) VALUES (?1, ?2, 1)
+             ON CONFLICT(client_ip, listener) DO UPDATE SET
+                 hit_count = hit_count + 1, last_seen = CURRENT_TIMESTAMP",
+            params![client_ip, listener],
+        )?;
+        Ok(())
+    }
+
+    /// Retrieves refused clients as (client, listener, attempts, last seen).
+    pub async fn get_rejected_clients(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<(String, String, i64, String)>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT client_ip, listener, hit_count, last_seen FROM rejected_clients
+             ORDER BY hit_count DESC LIMIT ?1",
+        )?;
+        let rows = stmt.query_map([limit as i64], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?;
+        let mut vec = Vec::new();
+        for r in rows {
+            vec.push(r?);
+        }
+        Ok(vec)
+    }
+
+    /// Retrieves request counts per client IP as (client, requests, distinct domains).
+    pub async fn get_requests_by_client(&self, limit: usize) -> Result<Vec<(String, i64, i64)>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT COALESCE(client_ip, 'unknown') AS client, COUNT(*) AS total,
+                    COUNT(DISTINCT domain)
+             FROM request_log GROUP BY client ORDER BY total DESC LIMIT ?1",
+        )?;
+        let rows = stmt.query_map([limit as i64], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        let mut vec = Vec::new();
+        for r in rows {
+            vec.push(r?);
+        }
+        Ok(vec)
+    }
+
+    /// Retrieves per-user totals of authenticated requests as
+    /// (user, requests, blocked requests, bytes served, cookies blocked).
+    pub async fn get_requests_by_user(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<(String, i64, i64, i64, i64)>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT r.username, COUNT(*) AS total, COALESCE(SUM(r.blocked), 0),
+                    COALESCE(SUM(r.response_bytes), 0),
+                    (SELECT COUNT(*) FROM cookie_traffic c
+                     WHERE c.username = r.username AND c.blocked = 1)
+             FROM request_log r WHERE r.username IS NOT NULL
+             GROUP BY r.username ORDER BY total DESC LIMIT ?1",
+        )?;
+        let rows = stmt.query_map([limit as i64], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ))
+        })?;
+        let mut vec = Vec::new();
+        for r in rows {
+            vec.push(r?);
+        }
+        Ok(vec)
+    }
+
+    /// Retrieves request counts per route as (route, requests).
+    pub async fn get_requests_by_route(&self) -> Result<Vec<(String, i64)>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT route, COUNT(*) AS total FROM request_log WHERE route IS NOT NULL
+             GROUP BY route ORDER BY total DESC",
+        )?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let mut vec = Vec::new();
+        for r in rows {
+            vec.push(r?);
+        }
+        Ok(vec)
+    }
+
+    /// Counts removed query parameters, one row per (parameter, domain).
+    pub async fn log_stripped_params(&self, domain: &str, params: &[String]) -> Result<()> {
+        let conn = self.conn.lock().await;
+        for param in params {
+            conn.execute(
+                "INSERT INTO stripped_params (param, domain, hit_count) VALUES (?1, ?2, 1)
+                 ON CONFLICT(param, domain) DO UPDATE SET
+                     hit_count = hit_count + 1, last_seen = CURRENT_TIMESTAMP",
+                params![param, domain],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Retrieves the most frequently stripped query parameters across all domains.
+    pub async fn get_top_stripped_params(&self, limit: usize) -> Result<Vec<(String, i64)>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT param, SUM(hit_count) AS total FROM stripped_params
+             GROUP BY param ORDER BY total DESC LIMIT ?1",
         )?;
+        let rows = stmt.query_map([limit as i64], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let mut vec = Vec::new();
+        for r in rows {
+            vec.push(r?);
+        }
+        Ok(vec)
+    }
+
+    /// Counts neutralised cache validators, one row per (domain, header).
+    pub async fn log_cache_validators(&self, domain: &str, headers: &[String]) -> Result<()> {
+        let conn = self.conn.lock().await;
+        for header in headers {
+            conn.execute(
+                "INSERT INTO cache_validators (domain, header, hit_count) VALUES (?1, ?2, 1)
+                 ON CONFLICT(domain, header) DO UPDATE SET
+                     hit_count = hit_count + 1, last_seen = CURRENT_TIMESTAMP",
+                params![domain, header],
+            )?;
+        }
         Ok(())
     }
 
+    /// Retrieves the domains with the most neutralised cache validators.
+    pub async fn get_top_cache_validator_domains(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<(String, i64)>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT domain, SUM(hit_count) AS total FROM cache_validators
+             GROUP BY domain ORDER BY total DESC LIMIT ?1",
+        )?;
+        let rows = stmt.query_map([limit as i64], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let mut vec = Vec::new();
+        for r in rows {
+            vec.push(r?);
+        }
+        Ok(vec)
+    }
+
+    /// Stores a cookie in the jar, or removes it if it has already expired.
+    pub async fn jar_store(&self, cookie: &JarCookie) -> Result<()> {
+        let conn = self.conn.lock().await;
+        let now = chrono::Utc::now().timestamp();
+        if cookie.expires.is_some_and(|e| e <= now) {
+            conn.execute(
+                "DELETE FROM cookie_jar WHERE site = ?1 AND domain = ?2 AND name = ?3 AND path = ?4",
+                params![cookie.site, cookie.domain, cookie.name, cookie.path],
+            )?;
+        } else {
+            conn.execute(
+                "INSERT OR REPLACE INTO cookie_jar
+                     (site, domain, name, path, value, host_only, secure, expires)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    cookie.site,
+                    cookie.domain,
+                    cookie.name,
+                    cookie.path,
+                    cookie.value,
+                    cookie.host_only,
+                    cookie.secure,
+                    cookie.expires
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Retrieves the unexpired cookies stored under a top-level site.
+    pub async fn jar_cookies(&self, site: &str) -> Result<Vec<JarCookie>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT site, domain, name, path, value, host_only, secure, expires FROM cookie_jar
+             WHERE site = ?1 AND (expires IS NULL OR expires > ?2)
+             ORDER BY domain, name",
+        )?;
+        let rows = stmt.query_map(params![site, chrono::Utc::now().timestamp()], |row| {
+            Ok(JarCookie {
+                site: row.get(0)?,
+                domain: row.get(1)?,
+                name: row.get(2)?,
+                path: row.get(3)?,
+                value: row.get(4)?,
+                host_only: row.get(5)?,
+                secure: row.get(6)?,
+                expires: row.get(7)?,
+            })
+        })?;
+        let mut vec = Vec::new();
+        for r in rows {
+            vec.push(r?);
+        }
+        Ok(vec)
+    }
+
+    /// Lists the sites that have cookies in the jar, with their cookie counts.
+    pub async fn jar_sites(&self) -> Result<Vec<(String, i64)>> {
+        let conn = self.conn.lock().await;
+        let mut stmt =
+            conn.prepare("SELECT site, COUNT(*) FROM cookie_jar GROUP BY site ORDER BY site")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let mut vec = Vec::new();
+        for r in rows {
+            vec.push(r?);
+        }
+        Ok(vec)
+    }
+
+    /// Removes the jar cookies of one site, or of every site.
+    ///
+    /// Returns the number of cookies removed.
+    pub async fn jar_purge(&self, site: Option<&str>) -> Result<usize> {
+        let conn = self.conn.lock().await;
+        let removed = match site {
+            Some(site) => conn.execute("DELETE FROM cookie_jar WHERE site = ?1", [site])?,
+            None => conn.execute("DELETE FROM cookie_jar", [])?,
+        };
+        Ok(removed)
+    }
+
+    /// Removes jar session cookies, which must not outlive a proxy run.
+    pub async fn jar_purge_session(&self) -> Result<usize> {
+        let conn = self.conn.lock().await;
+        Ok(conn.execute("DELETE FROM cookie_jar WHERE expires IS NULL", [])?)
+    }
+
+    /// Counts logged cookies per catalogue vendor or category.
+    ///
+    /// Returns `(label, total, blocked)` rows; unknown cookies are grouped
+    /// under `unknown`.
+    ///
+    /// # Arguments
+    ///
+    /// * `by_vendor` - Group by vendor if true, by category otherwise.
+    /// * `limit` - Maximum number of rows.
+    pub async fn get_cookie_breakdown(
+        &self,
+        by_vendor: bool,
+        limit: usize,
+    ) -> Result<Vec<(String, i64, i64)>> {
+        let conn = self.conn.lock().await;
+        let column = if by_vendor { "vendor" } else { "category" };
+        let mut stmt = conn.prepare(&format!(
+            "SELECT COALESCE({0}, 'unknown') AS label, COUNT(*) AS total,
+                    SUM(CASE WHEN blocked THEN 1 ELSE 0 END)
+             FROM cookie_traffic GROUP BY label ORDER BY total DESC LIMIT ?1",
+            column
+        ))?;
+        let rows = stmt.query_map([limit as i64], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        let mut vec = Vec::new();
+        for r in rows {
+            vec.push(r?);
+        }
+        Ok(vec)
+    }
+
     /// Increments the hit count for a tracking domain.
     ///
     /// Returns a tuple containing the new hit count and whether the domain is currently blocked.
@@ -120,6 +728,14 @@ impl Database {
         Ok((hit_count, blocked))
     }
 
+    /// Checks if a tracking domain is blocked, without counting a hit.
+    pub async fn is_blocked(&self, domain: &str) -> Result<bool> {
+        let conn = self.conn.lock().await;
+        let mut stmt =
+            conn.prepare("SELECT 1 FROM tracking_domains WHERE domain = ?1 AND blocked = 1")?;
+        Ok(stmt.exists(params![domain])?)
+    }
+
     /// Checks if a domain is whitelisted.
     pub async fn is_whitelisted(&self, domain: &str) -> Result<bool> {
         let conn = self.conn.lock().await;
@@ -147,6 +763,55 @@ impl Database {
         Ok(())
     }
 
+    /// Adds a host to the learned passthrough list.
+    ///
+    /// Returns false when the host is already listed, whatever its status.
+    pub async fn learn_host(&self, host: &str, failures: i64) -> Result<bool> {
+        let conn = self.conn.lock().await;
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO learned_passthrough (host, status, failures)
+             VALUES (?1, 'learned', ?2)",
+            params![host, failures],
+        )?;
+        Ok(inserted == 1)
+    }
+
+    /// Sets the status of a learned passthrough entry, adding the host if
+    /// it is not listed yet.
+    pub async fn review_learned_host(&self, host: &str, status: &str) -> Result<()> {
+        let conn = self.conn.lock().await;
+        conn.execute(
+            "INSERT INTO learned_passthrough (host, status, reviewed_at)
+             VALUES (?1, ?2, CURRENT_TIMESTAMP)
+             ON CONFLICT(host) DO UPDATE SET status = ?2, reviewed_at = CURRENT_TIMESTAMP",
+            params![host, status],
+        )?;
+        Ok(())
+    }
+
+    /// Retrieves the learned passthrough list, most recently learned first.
+    pub async fn learned_hosts(&self) -> Result<Vec<LearnedHost>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT host, status, failures, learned_at, reviewed_at FROM learned_passthrough
+             ORDER BY learned_at DESC, host",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(LearnedHost {
+                host: row.get(0)?,
+                status: row.get(1)?,
+                failures: row.get(2)?,
+                learned_at: row.get(3)?,
+                reviewed_at: row.get(4)?,
+            })
+        })?;
+        let mut vec = Vec::new();
+        for r in rows {
+            vec.push(r?);
+        }
+        Ok(vec)
+    }
+
     /// Manually blocks a domain.
     pub async fn manual_block(&self, domain: &str) -> Result<()> {
         self.set_blocked(domain, true).await
@@ -185,12 +850,30 @@ impl Database {
             params![days_str],
         )?;
 
+        // Cleanup tunnel_log
+        total_deleted += conn.execute(
+            "DELETE FROM tunnel_log WHERE timestamp < datetime('now', ?1)",
+            params![days_str],
+        )?;
+
         // Cleanup cookie_traffic
         total_deleted += conn.execute(
             "DELETE FROM cookie_traffic WHERE timestamp < datetime('now', ?1)",
             params![days_str],
         )?;
 
+        // Cleanup cookie_rewrites
+        total_deleted += conn.execute(
+            "DELETE FROM cookie_rewrites WHERE timestamp < datetime('now', ?1)",
+            params![days_str],
+        )?;
+
+        // Cleanup expired jar cookies
+        total_deleted += conn.execute(
+            "DELETE FROM cookie_jar WHERE expires IS NOT NULL AND expires <= ?1",
+            params![chrono::Utc::now().timestamp()],
+        )?;
+
         // Cleanup fingerprint_rotations
         total_deleted += conn.execute(
             "DELETE FROM fingerprint_rotations WHERE timestamp < datetime('now', ?1)",
@@ -215,29 +898,57 @@ pub async fn spawn_logger(db_path: String, mut rx: mpsc::Receiver<LogEvent>) {
             return;
         }
     };
+    // Row ids of logged requests by exchange id, until their response arrives
+    let mut pending: BTreeMap<u64, i64> = BTreeMap::new();
     while let Some(event) = rx.recv().await {
         match event {
-            LogEvent::Cookie {
-                domain,
-                cookie,
-                blocked,
-            } => {
-                let _ = db.log_cookie(domain, cookie, blocked).await;
+            LogEvent::CacheValidators { domain, headers } => {
+                let _ = db.log_cache_validators(&domain, &headers).await;
+            }
+            LogEvent::Cookie(record) => {
+                let _ = db.log_cookie(&record).await;
             }
             LogEvent::Fingerprint {
                 user_agent,
                 accept_language,
                 mode,
+                user,
             } => {
-                let _ = db.log_fingerprint(user_agent, accept_language, mode).await;
+                let _ = db
+                    .log_fingerprint(user_agent, accept_language, mode, user)
+                    .await;
             }
-            LogEvent::Request {
-                domain,
-                path,
-                user_agent,
+            LogEvent::RejectedClient {
                 client_ip,
+                listener,
             } => {
-                let _ = db.log_request(domain, path, user_agent, client_ip).await;
+                let _ = db.log_rejected_client(&client_ip, &listener).await;
+            }
+            LogEvent::Request(record) => {
+                if let Ok(row) = db.log_request(&record).await {
+                    pending.insert(record.exchange, row);
+                    if pending.len() > MAX_PENDING_EXCHANGES {
+                        pending.pop_first();
+                    }
+                }
+            }
+            LogEvent::Response(record) => {
+                if let Some(row) = pending.remove(&record.exchange) {
+                    let _ = db.log_response(row, &record).await;
+                }
+            }
+            LogEvent::CookieRewrite {
+                domain,
+                name,
+                changes,
+            } => {
+                let _ = db.log_cookie_rewrite(&domain, &name, &changes).await;
+            }
+            LogEvent::Tunnel(record) => {
+                let _ = db.log_tunnel(&record).await;
+            }
+            LogEvent::StrippedParams { domain, params } => {
+                let _ = db.log_stripped_params(&domain, &params).await;
             }
             LogEvent::TrackerHit { domain, category } => {
                 let _ = db.increment_tracker(&domain, category.as_deref()).await;
@@ -255,12 +966,37 @@ mod tests {
         let db = Database::new(":memory:").unwrap();
         
         // Test request logging
-        db.log_request(
-            "example.com".to_string(),
-            "/".to_string(),
-            "Mozilla/5.0".to_string(),
-            "127.0.0.1".to_string()
-        ).await.unwrap();
+        db.log_request(&RequestRecord {
+            domain: "example.com".to_string(),
+            path: "/".to_string(),
+            user_agent: "Mozilla/5.0".to_string(),
+            client_ip: "127.0.0.1".to_string(),
+            client_port: Some(50000),
+            method: "GET".to_string(),
+            scheme: "https".to_string(),
+            via_connect: true,
+            blocked: false,
+            user: None,
+            route: Some("direct".to_string()),
+            exchange: 1,
+        })
+        .await
+        .unwrap();
+        db.log_request(&RequestRecord {
+            domain: "example.org".to_string(),
+            client_ip: "127.0.0.1".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            db.get_requests_by_client(10).await.unwrap(),
+            vec![("127.0.0.1".to_string(), 2, 2)]
+        );
+        assert_eq!(
+            db.get_requests_by_route().await.unwrap(),
+            vec![("direct".to_string(), 1)]
+        );
 
         let conn = db.conn.lock().await;
         let count: i32 = conn.query_row(
@@ -268,7 +1004,168 @@ mod tests {
             [],
             |row| row.get(0)
         ).unwrap();
-        assert_eq!(count, 1);
+        assert_eq!(count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_response_metadata_and_bytes_saved() {
+        let db = Database::new(":memory:").unwrap();
+        for (exchange, bytes) in [(1, 1000), (2, 3000)] {
+            let row = db
+                .log_request(&RequestRecord {
+                    domain: "cdn.tracker.net".to_string(),
+                    exchange,
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            db.log_response(
+                row,
+                &ResponseRecord {
+                    exchange,
+                    status: 200,
+                    content_type: Some("image/gif".to_string()),
+                    bytes: Some(bytes),
+                    latency_ms: Some(40),
+                },
+            )
+            .await
+            .unwrap();
+        }
+        let row = db
+            .log_request(&RequestRecord {
+                domain: "cdn.tracker.net".to_string(),
+                blocked: true,
+                exchange: 3,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        db.log_response(
+            row,
+            &ResponseRecord {
+                exchange: 3,
+                status: 403,
+                bytes: Some(24),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(db.get_bytes_saved().await.unwrap(), 2000);
+        assert_eq!(
+            db.get_top_domains_by_bytes(10).await.unwrap(),
+            vec![("cdn.tracker.net".to_string(), 4000, Some(40.0))]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_totals() {
+        let db = Database::new(":memory:").unwrap();
+        let tunnel = TunnelRecord {
+            domain: "bank.example".to_string(),
+            port: 443,
+            client_ip: "127.0.0.1".to_string(),
+            route: Some("direct".to_string()),
+            reason: "no_intercept".to_string(),
+            bytes_sent: 100,
+            bytes_received: 4000,
+            ..Default::default()
+        };
+        db.log_tunnel(&tunnel).await.unwrap();
+        db.log_tunnel(&tunnel).await.unwrap();
+        db.log_tunnel(&TunnelRecord {
+            domain: "tracker.example".to_string(),
+            route: None,
+            blocked: true,
+            bytes_sent: 0,
+            bytes_received: 0,
+            ..tunnel
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            db.get_tunnels_by_domain(10).await.unwrap(),
+            vec![
+                ("bank.example".to_string(), 2, 0, 200, 8000),
+                ("tracker.example".to_string(), 1, 1, 0, 0),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_migrate_legacy_cookie_table() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE cookie_traffic (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                domain TEXT,
+                cookie TEXT,
+                timestamp DATETIME DEFAULT CURRENT_TIMESTAMP,
+                blocked BOOLEAN
+            );",
+        )
+        .unwrap();
+        conn.execute_batch(include_str!("../schema.sql")).unwrap();
+        migrate(&conn).unwrap();
+        // Running twice must be a no-op
+        migrate(&conn).unwrap();
+
+        let db = Database {
+            conn: Arc::new(Mutex::new(conn)),
+        };
+        db.log_cookie(&CookieRecord {
+            domain: "example.com".to_string(),
+            site: "news.example".to_string(),
+            name: "_ga".to_string(),
+            cookie: "_ga=GA1.2".to_string(),
+            direction: "request".to_string(),
+            blocked: true,
+            vendor: Some("Google Analytics".to_string()),
+            category: Some("analytics".to_string()),
+            user: None,
+        })
+        .await
+        .unwrap();
+        let conn = db.conn.lock().await;
+        let (name, direction): (String, String) = conn
+            .query_row("SELECT name, direction FROM cookie_traffic", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(name, "_ga");
+        assert_eq!(direction, "request");
+    }
+
+    #[tokio::test]
+    async fn test_cookie_breakdown() {
+        let db = Database::new(":memory:").unwrap();
+        let record = |name: &str, vendor: Option<&str>, blocked: bool| CookieRecord {
+            domain: "example.com".to_string(),
+            site: "example.com".to_string(),
+            name: name.to_string(),
+            cookie: format!("{}=1", name),
+            direction: "request".to_string(),
+            blocked,
+            vendor: vendor.map(String::from),
+            category: vendor.map(|_| "analytics".to_string()),
+            user: None,
+        };
+        db.log_cookie(&record("_ga", Some("Google Analytics"), true))
+            .await
+            .unwrap();
+        db.log_cookie(&record("_gid", Some("Google Analytics"), false))
+            .await
+            .unwrap();
+        db.log_cookie(&record("sid", None, false)).await.unwrap();
+
+        let vendors = db.get_cookie_breakdown(true, 10).await.unwrap();
+        assert_eq!(vendors[0], ("Google Analytics".to_string(), 2, 1));
+        assert_eq!(vendors[1], ("unknown".to_string(), 1, 0));
+        let categories = db.get_cookie_breakdown(false, 10).await.unwrap();
+        assert_eq!(categories[0], ("analytics".to_string(), 2, 1));
     }
 
     #[tokio::test]
@@ -283,6 +1180,106 @@ mod tests {
         assert_eq!(hits2, 2);
     }
 
+    #[tokio::test]
+    async fn test_stripped_params_counting() {
+        let db = Database::new(":memory:").unwrap();
+
+        let params = vec!["utm_source".to_string(), "fbclid".to_string()];
+        db.log_stripped_params("example.com", &params).await.unwrap();
+        db.log_stripped_params("example.com", &params[..1]).await.unwrap();
+        db.log_stripped_params("other.com", &params[..1]).await.unwrap();
+
+        let top = db.get_top_stripped_params(10).await.unwrap();
+        assert_eq!(top[0], ("utm_source".to_string(), 3));
+        assert_eq!(top[1], ("fbclid".to_string(), 1));
+    }
+
+    #[tokio::test]
+    async fn test_cache_validators_counting() {
+        let db = Database::new(":memory:").unwrap();
+
+        let headers = vec!["etag".to_string(), "if-none-match".to_string()];
+        db.log_cache_validators("cdn.tracker.net", &headers)
+            .await
+            .unwrap();
+        db.log_cache_validators("cdn.tracker.net", &headers[..1])
+            .await
+            .unwrap();
+        db.log_cache_validators("example.com", &headers[..1])
+            .await
+            .unwrap();
+
+        let top = db.get_top_cache_validator_domains(10).await.unwrap();
+        assert_eq!(top[0], ("cdn.tracker.net".to_string(), 3));
+        assert_eq!(top[1], ("example.com".to_string(), 1));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_clients_counting() {
+        let db = Database::new(":memory:").unwrap();
+        db.log_rejected_client("203.0.113.9", "0.0.0.0:8080")
+            .await
+            .unwrap();
+        db.log_rejected_client("203.0.113.9", "0.0.0.0:8080")
+            .await
+            .unwrap();
+
+        let rejected = db.get_rejected_clients(10).await.unwrap();
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].0, "203.0.113.9");
+        assert_eq!(rejected[0].2, 2);
+    }
+
+    #[tokio::test]
+    async fn test_requests_by_user() {
+        let db = Database::new(":memory:").unwrap();
+        let request = |user: Option<&str>, blocked: bool| RequestRecord {
+            domain: "example.com".to_string(),
+            blocked,
+            user: user.map(String::from),
+            ..Default::default()
+        };
+        let row = db
+            .log_request(&request(Some("alice"), false))
+            .await
+            .unwrap();
+        db.log_response(
+            row,
+            &ResponseRecord {
+                status: 200,
+                bytes: Some(512),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        db.log_request(&request(Some("alice"), true)).await.unwrap();
+        db.log_request(&request(Some("bob"), false)).await.unwrap();
+        db.log_request(&request(None, false)).await.unwrap();
+        db.log_cookie(&CookieRecord {
+            domain: "example.com".to_string(),
+            site: "example.com".to_string(),
+            name: "_ga".to_string(),
+            cookie: "_ga=1".to_string(),
+            direction: "request".to_string(),
+            blocked: true,
+            vendor: None,
+            category: None,
+            user: Some("alice".to_string()),
+        })
+        .await
+        .unwrap();
+
+        let users = db.get_requests_by_user(10).await.unwrap();
+        assert_eq!(
+            users,
+            vec![
+                ("alice".to_string(), 2, 1, 512, 1),
+                ("bob".to_string(), 1, 0, 0, 0)
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn test_whitelist_operations() {
         let db = Database::new(":memory:").unwrap();
@@ -0,0 +1,368 @@
+use crate::config::Config;
+use crate::cookie::{cookie_name, CookieDecision};
+use crate::db::JarCookie;
+use crate::jar::{cookie_matches, parse_set_cookie};
+use crate::pattern::domain_matches;
+use hyper::header::{HeaderValue, COOKIE, SET_COOKIE};
+use hyper::{Body, Request, Response};
+use log::info;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Cookies of one ephemeral domain.
+struct Session {
+    cookies: Vec<JarCookie>,
+    last_seen: Instant,
+}
+
+/// In-memory cookie sessions for configured domains.
+///
+/// Cookies are held by the proxy and replayed to requests, while the browser
+/// only receives them as session cookies. Once a session has been idle for
+/// longer than the timeout it is dropped, either by the next request to the
+/// domain or by `run_sweeper`, and the browser copies are cleared with
+/// expired `Set-Cookie` headers on the next response from the domain.
+pub struct EphemeralSessions {
+    domains: Vec<String>,
+    idle_timeout: Duration,
+    sessions: Mutex<HashMap<String, Session>>,
+    /// Cookies of purged sessions still to be cleared in the browser, by domain.
+    pending_clear: Mutex<HashMap<String, Vec<JarCookie>>>,
+}
+
+impl EphemeralSessions {
+    /// Creates a new EphemeralSessions store.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg` - Application configuration.
+    pub fn new(cfg: &Config) -> Self {
+        Self {
+            domains: cfg.cookies.ephemeral.domains.clone(),
+            idle_timeout: Duration::from_secs(cfg.cookies.ephemeral.idle_timeout),
+            sessions: Mutex::new(HashMap::new()),
+            pending_clear: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the configured ephemeral domain covering `host`, if any.
+    pub fn domain_for(&self, host: &str) -> Option<&str> {
+        self.domains
+            .iter()
+            .find(|d| domain_matches(host, d))
+            .map(|d| d.as_str())
+    }
+
+    /// Purges idle sessions periodically, so their cookies do not outlive the
+    /// timeout in memory while the domain sees no traffic.
+    pub async fn run_sweeper(self: Arc<Self>) {
+        let period = (self.idle_timeout / 4).clamp(Duration::from_secs(1), Duration::from_secs(60));
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            self.sweep(Instant::now());
+        }
+    }
+
+    /// Purges every session that has been idle too long.
+    fn sweep(&self, now: Instant) {
+        let domains: Vec<String> = self.sessions.lock().unwrap().keys().cloned().collect();
+        for domain in domains {
+            self.expire_idle(&domain, now);
+        }
+    }
+
+    /// Purges the session of `domain` if it has been idle too long.
+    fn expire_idle(&self, domain: &str, now: Instant) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let idle = sessions
+            .get(domain)
+            .is_some_and(|s| now.duration_since(s.last_seen) > self.idle_timeout);
+        if idle {
+            if let Some(session) = sessions.remove(domain) {
+                info!(
+                    "Ephemeral cookie session for {} expired ({} cookies)",
+                    domain,
+                    session.cookies.len()
+                );
+                self.pending_clear
+                    .lock()
+                    .unwrap()
+                    .entry(domain.to_string())
+                    .or_default()
+                    .extend(session.cookies);
+            }
+        }
+    }
+
+    /// Replaces the request's cookies with those of the current session.
+    ///
+    /// Cookies the browser still holds from an earlier session are never
+    /// forwarded. Returns the names of the replayed cookies.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The mutable HTTP request.
+    /// * `host` - The hostname of the request.
+    /// * `https` - Whether the request is made over HTTPS.
+    pub fn replay(&self, req: &mut Request<Body>, host: &str, https: bool) -> Vec<String> {
+        let Some(domain) = self.domain_for(host) else {
+            return Vec::new();
+        };
+        let now = Instant::now();
+        self.expire_idle(domain, now);
+        req.headers_mut().remove(COOKIE);
+
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.get_mut(domain) else {
+            return Vec::new();
+        };
+        session.last_seen = now;
+        let path = req.uri().path().to_string();
+        let matching: Vec<&JarCookie> = session
+            .cookies
+            .iter()
+            .filter(|c| cookie_matches(c, host, &path, https))
+            .collect();
+        if matching.is_empty() {
+            return Vec::new();
+        }
+        let header = matching
+            .iter()
+            .map(|c| format!("{}={}", c.name, c.value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        if let Ok(value) = HeaderValue::from_str(&header) {
+            req.headers_mut().insert(COOKIE, value);
+        }
+        matching.into_iter().map(|c| c.name.clone()).collect()
+    }
+
+    /// Captures the response's cookies into the session and hands them to the
+    /// browser as session cookies.
+    ///
+    /// Cookies for which `blocked` returns true are removed. Expired `Set-Cookie`
+    /// headers are appended for cookies of purged sessions. Returns a decision
+    /// for every `Set-Cookie` header of the response.
+    ///
+    /// # Arguments
+    ///
+    /// * `res` - The mutable HTTP response.
+    /// * `host` - The hostname of the request.
+    /// * `path` - The path of the request, for cookies without `Path`.
+    /// * `blocked` - Decides, by cookie name, which cookies are dropped.
+    pub fn capture(
+        &self,
+        res: &mut Response<Body>,
+        host: &str,
+        path: &str,
+        blocked: impl Fn(&str) -> bool,
+    ) -> Vec<CookieDecision> {
+        let Some(domain) = self.domain_for(host) else {
+            return Vec::new();
+        };
+        let now = Instant::now();
+        self.expire_idle(domain, now);
+
+        let values: Vec<String> = res
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).to_string())
+            .collect();
+        res.headers_mut().remove(SET_COOKIE);
+
+        let unix_now = chrono::Utc::now().timestamp();
+        let mut decisions = Vec::with_capacity(values.len());
+        let mut fresh = Vec::new();
+        {
+            let mut sessions = self.sessions.lock().unwrap();
+            let session = sessions.entry(domain.to_string()).or_insert(Session {
+                cookies: Vec::new(),
+                last_seen: now,
+            });
+            session.last_seen = now;
+            for raw in values {
+                let name = cookie_name(&raw);
+                let parsed = parse_set_cookie(&raw, host, path, domain, unix_now);
+                let Some(cookie) = parsed.filter(|_| !blocked(&name)) else {
+                    decisions.push(CookieDecision {
+                        name,
+                        raw,
+                        blocked: true,
+                    });
+                    continue;
+                };
+                session.cookies.retain(|c| {
+                    (&c.domain, &c.name, &c.path) != (&cookie.domain, &cookie.name, &cookie.path)
+                });
+                let deletion = cookie.expires.is_some_and(|e| e <= unix_now);
+                let forwarded = if deletion {
+                    raw.clone()
+                } else {
+                    fresh.push((
+                        cookie.domain.clone(),
+                        cookie.name.clone(),
+                        cookie.path.clone(),
+                    ));
+                    session.cookies.push(cookie);
+                    to_session_cookie(&raw)
+                };
+                if let Ok(value) = HeaderValue::from_str(&forwarded) {
+                    res.headers_mut().append(SET_COOKIE, value);
+                }
+                decisions.push(CookieDecision {
+                    name,
+                    raw,
+                    blocked: false,
+                });
+            }
+        }
+
+        // Clear leftovers of purged sessions that this host is able to remove
+        let mut pending = self.pending_clear.lock().unwrap();
+        if let Some(stale) = pending.get_mut(domain) {
+            stale.retain(|c| {
+                // Cookies set again by this response belong to the new session
+                if fresh.contains(&(c.domain.clone(), c.name.clone(), c.path.clone())) {
+                    return false;
+                }
+                if !cookie_matches(c, host, &c.path, true) {
+                    return true;
+                }
+                let mut clear = format!(
+                    "{}=; Path={}; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT",
+                    c.name, c.path
+                );
+                if !c.host_only {
+                    clear.push_str(&format!("; Domain={}", c.domain));
+                }
+                if let Ok(value) = HeaderValue::from_str(&clear) {
+                    res.headers_mut().append(SET_COOKIE, value);
+                }
+                false
+            });
+            if stale.is_empty() {
+                pending.remove(domain);
+            }
+        }
+        decisions
+    }
+}
+
+/// Removes `Expires` and `Max-Age` so the browser keeps the cookie for the
+/// browsing session only.
+fn to_session_cookie(raw: &str) -> String {
+    raw.split(';')
+        .map(str::trim)
+        .enumerate()
+        .filter(|(i, attr)| {
+            let key = attr.split('=').next().unwrap_or("").trim();
+            *i == 0 || !(key.eq_ignore_ascii_case("expires") || key.eq_ignore_ascii_case("max-age"))
+        })
+        .map(|(_, attr)| attr)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sessions(idle_timeout: u64) -> EphemeralSessions {
+        EphemeralSessions {
+            domains: vec!["example.com".to_string()],
+            idle_timeout: Duration::from_secs(idle_timeout),
+            sessions: Mutex::new(HashMap::new()),
+            pending_clear: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn response(cookies: &[&str]) -> Response<Body> {
+        let mut res = Response::new(Body::empty());
+        for c in cookies {
+            res.headers_mut().append(SET_COOKIE, c.parse().unwrap());
+        }
+        res
+    }
+
+    fn set_cookies(res: &Response<Body>) -> Vec<&str> {
+        res.headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_session_cookies_replayed() {
+        let s = sessions(60);
+        let mut res = response(&["sid=1; Path=/; Max-Age=31536000; Secure", "_ga=2"]);
+        let decisions = s.capture(&mut res, "www.example.com", "/", |n| n == "_ga");
+        let blocked: Vec<_> = decisions
+            .iter()
+            .map(|d| (d.name.as_str(), d.blocked))
+            .collect();
+        assert_eq!(blocked, vec![("sid", false), ("_ga", true)]);
+        assert_eq!(set_cookies(&res), vec!["sid=1; Path=/; Secure"]);
+
+        let mut req = Request::builder()
+            .uri("https://www.example.com/account")
+            .header(COOKIE, "old=stale")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(s.replay(&mut req, "www.example.com", true), vec!["sid"]);
+        assert_eq!(req.headers()[COOKIE], "sid=1");
+
+        // Other domains are not affected
+        let mut req = Request::builder()
+            .header(COOKIE, "a=1")
+            .body(Body::empty())
+            .unwrap();
+        assert!(s.replay(&mut req, "other.org", true).is_empty());
+        assert_eq!(req.headers()[COOKIE], "a=1");
+    }
+
+    #[test]
+    fn test_idle_session_purged_and_cleared() {
+        let s = sessions(5);
+        let mut res = response(&["sid=1; Path=/"]);
+        s.capture(&mut res, "example.com", "/", |_| false);
+        s.sessions
+            .lock()
+            .unwrap()
+            .get_mut("example.com")
+            .unwrap()
+            .last_seen -= Duration::from_secs(10);
+
+        let mut req = Request::builder()
+            .uri("https://example.com/")
+            .header(COOKIE, "sid=1")
+            .body(Body::empty())
+            .unwrap();
+        assert!(s.replay(&mut req, "example.com", true).is_empty());
+        assert!(!req.headers().contains_key(COOKIE));
+
+        let mut res = response(&[]);
+        s.capture(&mut res, "example.com", "/", |_| false);
+        assert_eq!(
+            set_cookies(&res),
+            vec!["sid=; Path=/; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT"]
+        );
+        assert!(s.pending_clear.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sweep_purges_idle_sessions() {
+        let s = sessions(5);
+        let mut res = response(&["sid=1; Path=/"]);
+        s.capture(&mut res, "example.com", "/", |_| false);
+
+        s.sweep(Instant::now());
+        assert!(s.sessions.lock().unwrap().contains_key("example.com"));
+        s.sweep(Instant::now() + Duration::from_secs(10));
+        assert!(s.sessions.lock().unwrap().is_empty());
+        assert_eq!(s.pending_clear.lock().unwrap()["example.com"].len(), 1);
+    }
+}
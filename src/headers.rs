@@ -0,0 +1,364 @@
+use crate::config::{Config, HeaderAction, HeaderPhase, HeaderRule};
+use crate::pattern::glob;
+use anyhow::{anyhow, Context};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response};
+use regex::Regex;
+
+/// Compiled form of a header rule's operation.
+#[derive(Clone)]
+enum Operation {
+    Add(HeaderValue),
+    Remove,
+    Replace(HeaderValue),
+    Substitute(Regex, String),
+}
+
+/// A validated header rule ready to be matched against messages.
+#[derive(Clone)]
+struct CompiledRule {
+    name: String,
+    hosts: Vec<Regex>,
+    methods: Vec<Method>,
+    content_types: Vec<Regex>,
+    header: HeaderName,
+    operation: Operation,
+}
+
+impl CompiledRule {
+    fn compile(rule: &HeaderRule) -> anyhow::Result<Self> {
+        let header = HeaderName::from_bytes(rule.header.as_bytes())
+            .with_context(|| format!("invalid header name '{}'", rule.header))?;
+
+        let value = || -> anyhow::Result<HeaderValue> {
+            let value = rule
+                .value
+                .as_deref()
+                .ok_or_else(|| anyhow!("action {:?} requires a value", rule.action))?;
+            HeaderValue::from_str(value)
+                .with_context(|| format!("invalid header value '{}'", value))
+        };
+
+        let operation = match rule.action {
+            HeaderAction::Add => Operation::Add(value()?),
+            HeaderAction::Remove => Operation::Remove,
+            HeaderAction::Replace => Operation::Replace(value()?),
+            HeaderAction::Substitute => {
+                let pattern = rule
+                    .pattern
+                    .as_deref()
+                    .ok_or_else(|| anyhow!("action substitute requires a pattern"))?;
+                let regex = Regex::new(pattern)
+                    .with_context(|| format!("invalid pattern '{}'", pattern))?;
+                Operation::Substitute(regex, rule.replacement.clone().unwrap_or_default())
+            }
+        };
+
+        let methods = rule
+            .methods
+            .iter()
+            .map(|m| {
+                Method::from_bytes(m.to_ascii_uppercase().as_bytes())
+                    .with_context(|| format!("invalid method '{}'", m))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            name: rule.name.clone(),
+            hosts: rule
+                .hosts
+                .iter()
+                .map(|h| glob(h))
+                .collect::<anyhow::Result<_>>()?,
+            methods,
+            content_types: rule
+                .content_types
+                .iter()
+                .map(|c| glob(c))
+                .collect::<anyhow::Result<_>>()?,
+            header,
+            operation,
+        })
+    }
+
+    fn matches(&self, host: &str, method: &Method, content_type: Option<&str>) -> bool {
+        if !self.hosts.is_empty() && !self.hosts.iter().any(|h| h.is_match(host)) {
+            return false;
+        }
+        if !self.methods.is_empty() && !self.methods.contains(method) {
+            return false;
+        }
+        if !self.content_types.is_empty() {
+            // Compare against the media type only, ignoring parameters like charset
+            let essence = content_type
+                .and_then(|c| c.split(';').next())
+                .map(|c| c.trim())
+                .unwrap_or("");
+            if !self.content_types.iter().any(|c| c.is_match(essence)) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Applies the operation, returning `true` if the headers changed.
+    fn apply(&self, headers: &mut HeaderMap) -> bool {
+        match &self.operation {
+            Operation::Add(value) => {
+                headers.append(self.header.clone(), value.clone());
+                true
+            }
+            Operation::Remove => headers.remove(&self.header).is_some(),
+            Operation::Replace(value) => {
+                if headers.contains_key(&self.header) {
+                    headers.insert(self.header.clone(), value.clone());
+                    true
+                } else {
+                    false
+                }
+            }
+            Operation::Substitute(regex, replacement) => {
+                let old: Vec<HeaderValue> = headers.get_all(&self.header).iter().cloned().collect();
+                let mut changed = false;
+                let mut new = Vec::with_capacity(old.len());
+                for value in old {
+                    let rewritten = value.to_str().ok().and_then(|s| {
+                        let out = regex.replace_all(s, replacement.as_str());
+                        if out != s {
+                            HeaderValue::from_str(&out).ok()
+                        } else {
+                            None
+                        }
+                    });
+                    match rewritten {
+                        Some(v) => {
+                            new.push(v);
+                            changed = true;
+                        }
+                        None => new.push(value),
+                    }
+                }
+                if changed {
+                    headers.remove(&self.header);
+                    for v in new {
+                        headers.append(self.header.clone(), v);
+                    }
+                }
+                changed
+            }
+        }
+    }
+}
+
+/// Applies user-defined header rewrite rules to requests and responses.
+#[derive(Clone)]
+pub struct HeaderRewriter {
+    request_rules: Vec<CompiledRule>,
+    response_rules: Vec<CompiledRule>,
+}
+
+impl HeaderRewriter {
+    /// Creates a new HeaderRewriter, validating every configured rule.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg` - Application configuration.
+    pub fn new(cfg: &Config) -> anyhow::Result<Self> {
+        Self::from_rules(&cfg.header_rules)
+    }
+
+    /// Creates a new HeaderRewriter from a list of rules.
+    ///
+    /// # Arguments
+    ///
+    /// * `rules` - Header rules in the order they should be applied.
+    pub fn from_rules(rules: &[HeaderRule]) -> anyhow::Result<Self> {
+        let mut request_rules = Vec::new();
+        let mut response_rules = Vec::new();
+        for rule in rules {
+            let compiled = CompiledRule::compile(rule)
+                .with_context(|| format!("header rule '{}'", rule.name))?;
+            match rule.phase {
+                HeaderPhase::Request => request_rules.push(compiled),
+                HeaderPhase::Response => response_rules.push(compiled),
+            }
+        }
+        Ok(Self {
+            request_rules,
+            response_rules,
+        })
+    }
+
+    /// Applies request-phase rules.
+    ///
+    /// Returns the names of the rules that modified the request.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The mutable HTTP request.
+    /// * `host` - The hostname of the request.
+    pub fn apply_request(&self, req: &mut Request<Body>, host: &str) -> Vec<String> {
+        let method = req.method().clone();
+        apply_rules(&self.request_rules, req.headers_mut(), host, &method)
+    }
+
+    /// Applies response-phase rules.
+    ///
+    /// Returns the names of the rules that modified the response.
+    ///
+    /// # Arguments
+    ///
+    /// * `res` - The mutable HTTP response.
+    /// * `host` - The hostname of the originating request.
+    /// * `method` - The method of the originating request.
+    pub fn apply_response(
+        &self,
+        res: &mut Response<Body>,
+        host: &str,
+        method: &Method,
+    ) -> Vec<String> {
+        apply_rules(&self.response_rules, res.headers_mut(), host, method)
+    }
+}
+
+fn apply_rules(
+    rules: &[CompiledRule],
+    headers: &mut HeaderMap,
+    host: &str,
+    method: &Method,
+) -> Vec<String> {
+    let mut applied = Vec::new();
+    for rule in rules {
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|s| s.to_string());
+        if rule.matches(host, method, content_type.as_deref()) && rule.apply(headers) {
+            applied.push(rule.name.clone());
+        }
+    }
+    applied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(phase: HeaderPhase, action: HeaderAction, header: &str) -> HeaderRule {
+        HeaderRule {
+            name: "test".to_string(),
+            phase,
+            hosts: vec![],
+            methods: vec![],
+            content_types: vec![],
+            action,
+            header: header.to_string(),
+            value: None,
+            pattern: None,
+            replacement: None,
+        }
+    }
+
+    fn rewriter(rules: Vec<HeaderRule>) -> anyhow::Result<HeaderRewriter> {
+        HeaderRewriter::from_rules(&rules)
+    }
+
+    #[test]
+    fn test_remove_by_host_pattern() {
+        let mut r = rule(
+            HeaderPhase::Request,
+            HeaderAction::Remove,
+            "X-Requested-With",
+        );
+        r.hosts = vec!["*.example.com".to_string()];
+        let rw = rewriter(vec![r]).unwrap();
+
+        let mut req = Request::new(Body::empty());
+        req.headers_mut()
+            .insert("x-requested-with", "XMLHttpRequest".parse().unwrap());
+        assert!(rw.apply_request(&mut req, "other.org").is_empty());
+        assert!(req.headers().contains_key("x-requested-with"));
+
+        assert_eq!(rw.apply_request(&mut req, "api.example.com"), vec!["test"]);
+        assert!(!req.headers().contains_key("x-requested-with"));
+    }
+
+    #[test]
+    fn test_add_with_method_filter() {
+        let mut r = rule(HeaderPhase::Request, HeaderAction::Add, "X-Api-Key");
+        r.methods = vec!["post".to_string()];
+        r.value = Some("secret".to_string());
+        let rw = rewriter(vec![r]).unwrap();
+
+        let mut get = Request::new(Body::empty());
+        rw.apply_request(&mut get, "api.example.com");
+        assert!(!get.headers().contains_key("x-api-key"));
+
+        let mut post = Request::builder()
+            .method(Method::POST)
+            .body(Body::empty())
+            .unwrap();
+        rw.apply_request(&mut post, "api.example.com");
+        assert_eq!(post.headers()["x-api-key"], "secret");
+    }
+
+    #[test]
+    fn test_response_substitute_and_content_type() {
+        let mut r = rule(
+            HeaderPhase::Response,
+            HeaderAction::Substitute,
+            "Cache-Control",
+        );
+        r.content_types = vec!["text/*".to_string()];
+        r.pattern = Some(r"max-age=\d+".to_string());
+        r.replacement = Some("max-age=0".to_string());
+        let mut drop = rule(HeaderPhase::Response, HeaderAction::Remove, "Server-Timing");
+        drop.name = "drop".to_string();
+        let rw = rewriter(vec![r, drop]).unwrap();
+
+        let mut res = Response::builder()
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .header("cache-control", "public, max-age=3600")
+            .header("server-timing", "db;dur=53")
+            .body(Body::empty())
+            .unwrap();
+        let applied = rw.apply_response(&mut res, "example.com", &Method::GET);
+        assert_eq!(applied, vec!["test", "drop"]);
+        assert_eq!(res.headers()["cache-control"], "public, max-age=0");
+        assert!(!res.headers().contains_key("server-timing"));
+
+        let mut json = Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .header("cache-control", "max-age=60")
+            .body(Body::empty())
+            .unwrap();
+        rw.apply_response(&mut json, "example.com", &Method::GET);
+        assert_eq!(json.headers()["cache-control"], "max-age=60");
+    }
+
+    #[test]
+    fn test_replace_only_existing() {
+        let mut r = rule(HeaderPhase::Request, HeaderAction::Replace, "Accept");
+        r.value = Some("*/*".to_string());
+        let rw = rewriter(vec![r]).unwrap();
+
+        let mut req = Request::new(Body::empty());
+        assert!(rw.apply_request(&mut req, "example.com").is_empty());
+        assert!(!req.headers().contains_key("accept"));
+    }
+
+    #[test]
+    fn test_validation() {
+        assert!(rewriter(vec![rule(HeaderPhase::Request, HeaderAction::Add, "X-Foo")]).is_err());
+        assert!(rewriter(vec![rule(
+            HeaderPhase::Request,
+            HeaderAction::Remove,
+            "bad header"
+        )])
+        .is_err());
+
+        let mut r = rule(HeaderPhase::Request, HeaderAction::Substitute, "X-Foo");
+        r.pattern = Some("(".to_string());
+        assert!(rewriter(vec![r]).is_err());
+    }
+}
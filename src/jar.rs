@@ -0,0 +1,417 @@
+use crate::config::Config;
+use crate::cookie::{cookie_name, parse_expires, CookieDecision};
+use crate::db::{Database, JarCookie};
+use crate::pattern::{domain_matches, is_public_suffix, registrable_domain};
+use hyper::header::{HeaderValue, COOKIE, SET_COOKIE};
+use hyper::{Body, Request, Response};
+use log::warn;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+/// Proxy-owned cookie storage partitioned by top-level site.
+///
+/// For partitioned exchanges the browser never sees the cookies: `Set-Cookie`
+/// headers are captured into the jar under (site, cookie domain) and replayed
+/// only to requests made under the same top-level site.
+#[derive(Clone)]
+pub struct CookieJar {
+    enabled: bool,
+    capture_first_party: bool,
+    db: Arc<Mutex<Database>>,
+}
+
+impl CookieJar {
+    /// Creates a new CookieJar.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg` - Application configuration.
+    /// * `db` - Shared database connection.
+    pub fn new(cfg: &Config, db: Arc<Mutex<Database>>) -> Self {
+        Self {
+            enabled: cfg.cookies.jar.enabled,
+            capture_first_party: cfg.cookies.jar.capture_first_party,
+            db,
+        }
+    }
+
+    /// Whether cookies exchanged with `host` under the top-level `site` are kept in the jar.
+    pub fn is_partitioned(&self, host: &str, site: &str) -> bool {
+        self.enabled
+            && (self.capture_first_party || registrable_domain(host) != registrable_domain(site))
+    }
+
+    /// Replaces the request's cookies with the jar cookies for `host` under `site`.
+    ///
+    /// Returns the names of the replayed cookies.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The mutable HTTP request.
+    /// * `host` - The hostname of the request.
+    /// * `site` - The first-party site of the request.
+    /// * `https` - Whether the request is made over HTTPS.
+    pub async fn replay(
+        &self,
+        req: &mut Request<Body>,
+        host: &str,
+        site: &str,
+        https: bool,
+    ) -> Vec<String> {
+        req.headers_mut().remove(COOKIE);
+        let stored = match self
+            .db
+            .lock()
+            .await
+            .jar_cookies(&registrable_domain(site))
+            .await
+        {
+            Ok(stored) => stored,
+            Err(e) => {
+                warn!("Cookie jar lookup failed: {}", e);
+                return Vec::new();
+            }
+        };
+
+        let path = req.uri().path().to_string();
+        let mut matching: Vec<JarCookie> = stored
+            .into_iter()
+            .filter(|c| cookie_matches(c, host, &path, https))
+            .collect();
+        if matching.is_empty() {
+            return Vec::new();
+        }
+        // Cookies with longer paths are listed first
+        matching.sort_by_key(|c| std::cmp::Reverse(c.path.len()));
+
+        let header = matching
+            .iter()
+            .map(|c| format!("{}={}", c.name, c.value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        if let Ok(value) = HeaderValue::from_str(&header) {
+            req.headers_mut().insert(COOKIE, value);
+        }
+        matching.into_iter().map(|c| c.name).collect()
+    }
+
+    /// Moves the response's `Set-Cookie` headers into the jar.
+    ///
+    /// Every `Set-Cookie` header is removed from the response. Cookies for which
+    /// `blocked` returns true are dropped instead of stored. Returns one decision
+    /// per cookie.
+    ///
+    /// # Arguments
+    ///
+    /// * `res` - The mutable HTTP response.
+    /// * `host` - The hostname of the request.
+    /// * `path` - The path of the request, for cookies without `Path`.
+    /// * `site` - The first-party site of the request.
+    /// * `blocked` - Decides, by cookie name, which cookies must not be stored.
+    pub async fn capture(
+        &self,
+        res: &mut Response<Body>,
+        host: &str,
+        path: &str,
+        site: &str,
+        blocked: impl Fn(&str) -> bool,
+    ) -> Vec<CookieDecision> {
+        let values: Vec<String> = res
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).to_string())
+            .collect();
+        res.headers_mut().remove(SET_COOKIE);
+
+        let now = chrono::Utc::now().timestamp();
+        let site = registrable_domain(site);
+        let mut decisions = Vec::with_capacity(values.len());
+        for raw in values {
+            let name = cookie_name(&raw);
+            let parsed = parse_set_cookie(&raw, host, path, &site, now);
+            let stored = match parsed {
+                Some(cookie) if !blocked(&name) => {
+                    let db = self.db.lock().await;
+                    match db.jar_store(&cookie).await {
+                        Ok(()) => true,
+                        Err(e) => {
+                            warn!("Failed to store cookie {} in jar: {}", name, e);
+                            false
+                        }
+                    }
+                }
+                _ => false,
+            };
+            decisions.push(CookieDecision {
+                name,
+                raw,
+                blocked: !stored,
+            });
+        }
+        decisions
+    }
+}
+
+/// Parses a `Set-Cookie` value received from `host` in answer to a request
+/// for `request_path` into a jar entry.
+///
+/// Returns None for malformed cookies and for `Domain` attributes that do not
+/// cover `host` within its site or name a public suffix other than `host`
+/// itself. Cookies without a `Path` attribute get the default path of the
+/// request.
+pub(crate) fn parse_set_cookie(
+    raw: &str,
+    host: &str,
+    request_path: &str,
+    site: &str,
+    now: i64,
+) -> Option<JarCookie> {
+    let mut parts = raw.split(';').map(str::trim);
+    let (name, value) = parts.next()?.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+
+    let host = host.to_ascii_lowercase();
+    let mut cookie = JarCookie {
+        site: site.to_string(),
+        domain: host.clone(),
+        name: name.to_string(),
+        path: default_path(request_path),
+        value: value.trim().to_string(),
+        host_only: true,
+        secure: false,
+        expires: None,
+    };
+    let mut max_age = None;
+    let mut expires = None;
+    for attr in parts {
+        let (key, val) = attr.split_once('=').unwrap_or((attr, ""));
+        let val = val.trim();
+        match key.trim().to_ascii_lowercase().as_str() {
+            "domain" if !val.is_empty() => {
+                let domain = val.trim_start_matches('.').to_ascii_lowercase();
+                // A public suffix is only accepted as the host itself, and
+                // then stays host-only (RFC 6265 section 5.3, step 5)
+                if is_public_suffix(&domain) {
+                    if domain != host {
+                        return None;
+                    }
+                    continue;
+                }
+                // The domain must cover the host but not reach above its site
+                if !domain_matches(&host, &domain)
+                    || !domain_matches(&domain, &registrable_domain(&host))
+                {
+                    return None;
+                }
+                cookie.domain = domain;
+                cookie.host_only = false;
+            }
+            "path" if val.starts_with('/') => cookie.path = val.to_string(),
+            "secure" => cookie.secure = true,
+            "max-age" => max_age = val.parse::<i64>().ok(),
+            "expires" => {
+                expires = parse_expires(val).map(|t| {
+                    t.duration_since(UNIX_EPOCH)
+                        .unwrap_or(Duration::ZERO)
+                        .as_secs() as i64
+                })
+            }
+            _ => {}
+        }
+    }
+    // Max-Age takes precedence over Expires
+    cookie.expires = max_age.map(|m| now + m).or(expires);
+    Some(cookie)
+}
+
+/// Default cookie path (RFC 6265 section 5.1.4): the request path up to, but
+/// not including, its last `/`, or `/` when that leaves nothing.
+fn default_path(request_path: &str) -> String {
+    match request_path.rfind('/') {
+        Some(end) if end > 0 && request_path.starts_with('/') => request_path[..end].to_string(),
+        _ => "/".to_string(),
+    }
+}
+
+/// Checks whether a jar cookie is sent with a request to `host` and `path`.
+pub(crate) fn cookie_matches(cookie: &JarCookie, host: &str, path: &str, https: bool) -> bool {
+    let domain_ok = if cookie.host_only {
+        host.eq_ignore_ascii_case(&cookie.domain)
+    } else {
+        domain_matches(host, &cookie.domain)
+    };
+    let path_ok = path == cookie.path
+        || (path.starts_with(&cookie.path)
+            && (cookie.path.ends_with('/') || path[cookie.path.len()..].starts_with('/')));
+    domain_ok && path_ok && (https || !cookie.secure)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jar() -> CookieJar {
+        CookieJar {
+            enabled: true,
+            capture_first_party: false,
+            db: Arc::new(Mutex::new(Database::new(":memory:").unwrap())),
+        }
+    }
+
+    fn request(uri: &str) -> Request<Body> {
+        Request::builder().uri(uri).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_capture_and_replay_per_site() {
+        let jar = jar();
+        assert!(jar.is_partitioned("cdn.tracker.net", "www.news.example"));
+        assert!(!jar.is_partitioned("img.news.example", "www.news.example"));
+
+        let mut res = Response::new(Body::empty());
+        res.headers_mut().append(
+            SET_COOKIE,
+            "uid=42; Domain=tracker.net; Max-Age=3600".parse().unwrap(),
+        );
+        res.headers_mut()
+            .append(SET_COOKIE, "_fbp=1; Path=/".parse().unwrap());
+        let decisions = jar
+            .capture(&mut res, "cdn.tracker.net", "/", "www.news.example", |n| {
+                n == "_fbp"
+            })
+            .await;
+        assert!(!res.headers().contains_key(SET_COOKIE));
+        assert_eq!(
+            decisions
+                .iter()
+                .map(|d| (d.name.as_str(), d.blocked))
+                .collect::<Vec<_>>(),
+            vec![("uid", false), ("_fbp", true)]
+        );
+
+        // Same top-level site: replayed, also to sibling tracker hosts
+        let mut req = request("https://pixel.tracker.net/p.gif");
+        req.headers_mut()
+            .insert(COOKIE, "browser=1".parse().unwrap());
+        let replayed = jar
+            .replay(&mut req, "pixel.tracker.net", "news.example", true)
+            .await;
+        assert_eq!(replayed, vec!["uid"]);
+        assert_eq!(req.headers()[COOKIE], "uid=42");
+
+        // Another top-level site sees nothing
+        let mut req = request("https://pixel.tracker.net/p.gif");
+        let replayed = jar
+            .replay(&mut req, "pixel.tracker.net", "shop.example", true)
+            .await;
+        assert!(replayed.is_empty());
+        assert!(!req.headers().contains_key(COOKIE));
+    }
+
+    #[tokio::test]
+    async fn test_public_suffix_tenants_are_separate_sites() {
+        let jar = jar();
+        assert!(jar.is_partitioned("bob.github.io", "alice.github.io"));
+
+        let mut res = Response::new(Body::empty());
+        res.headers_mut()
+            .append(SET_COOKIE, "uid=42; Domain=github.io".parse().unwrap());
+        res.headers_mut()
+            .append(SET_COOKIE, "sid=1".parse().unwrap());
+        jar.capture(&mut res, "bob.github.io", "/", "alice.github.io", |_| false)
+            .await;
+
+        // The Domain=github.io cookie was dropped, so bob.github.io only gets
+        // its host-only cookie back
+        let mut req = request("https://bob.github.io/");
+        let replayed = jar
+            .replay(&mut req, "bob.github.io", "alice.github.io", true)
+            .await;
+        assert_eq!(replayed, vec!["sid"]);
+        let mut req = request("https://carol.github.io/");
+        let replayed = jar
+            .replay(&mut req, "carol.github.io", "alice.github.io", true)
+            .await;
+        assert!(replayed.is_empty());
+    }
+
+    #[test]
+    fn test_parse_and_match() {
+        let now = 1_700_000_000;
+        let c = parse_set_cookie(
+            "sid=abc; Path=/app; Secure; Expires=Thu, 01 Jan 2099 00:00:00 GMT",
+            "api.example.com",
+            "/",
+            "example.com",
+            now,
+        )
+        .unwrap();
+        assert!(c.host_only);
+        assert_eq!(c.domain, "api.example.com");
+        assert_eq!(c.expires, Some(4_070_908_800));
+        assert!(cookie_matches(&c, "api.example.com", "/app/x", true));
+        assert!(!cookie_matches(&c, "api.example.com", "/app/x", false));
+        assert!(!cookie_matches(&c, "api.example.com", "/application", true));
+        assert!(!cookie_matches(&c, "www.api.example.com", "/app", true));
+
+        let c = parse_set_cookie(
+            "a=1; Max-Age=60; Expires=Thu, 01 Jan 2099 00:00:00 GMT",
+            "example.com",
+            "/",
+            "s",
+            now,
+        )
+        .unwrap();
+        assert_eq!(c.expires, Some(now + 60));
+
+        let parse = |raw| parse_set_cookie(raw, "example.com", "/", "s", now);
+        assert!(parse("a=1; Domain=other.com").is_none());
+        assert!(parse("a=1; Domain=com").is_none());
+
+        let now = 0;
+        assert!(
+            parse_set_cookie("a=1; Domain=co.jp", "www.example.co.jp", "/", "s", now).is_none()
+        );
+        assert!(
+            parse_set_cookie("a=1; Domain=github.io", "alice.github.io", "/", "s", now).is_none()
+        );
+        let c = parse_set_cookie(
+            "a=1; Domain=example.co.jp",
+            "www.example.co.jp",
+            "/",
+            "s",
+            now,
+        )
+        .unwrap();
+        assert!(!c.host_only);
+        // A public suffix naming the host itself stays host-only
+        let c = parse_set_cookie("a=1; Domain=github.io", "github.io", "/", "s", now).unwrap();
+        assert!(c.host_only);
+        assert_eq!(c.domain, "github.io");
+        assert!(parse("novalue").is_none());
+    }
+
+    #[test]
+    fn test_default_path() {
+        let path = |request_path| {
+            parse_set_cookie("a=1", "example.com", request_path, "s", 0)
+                .unwrap()
+                .path
+        };
+        assert_eq!(path("/account/settings"), "/account");
+        assert_eq!(path("/account/"), "/account");
+        assert_eq!(path("/a/b/c"), "/a/b");
+        assert_eq!(path("/login"), "/");
+        assert_eq!(path("/"), "/");
+        assert_eq!(path(""), "/");
+        assert_eq!(path("*"), "/");
+        // An explicit Path wins
+        let c = parse_set_cookie("a=1; Path=/", "example.com", "/account/x", "s", 0).unwrap();
+        assert_eq!(c.path, "/");
+    }
+}
@@ -0,0 +1,205 @@
+use crate::config::{Config, TlsFallbackConfig};
+use crate::db::Database;
+use log::{info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Status of a host learned from failed handshakes and not reviewed yet.
+pub const LEARNED: &str = "learned";
+/// Status of a learned host confirmed with `blanktrace learned promote`.
+pub const PROMOTED: &str = "promoted";
+/// Status of a host that must be intercepted and is never learned again.
+pub const REVOKED: &str = "revoked";
+
+/// How long the list is cached before it is read again, so changes made
+/// with the CLI reach a running proxy.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Hosts with failures in progress kept before stale ones are dropped.
+const MAX_TRACKED_HOSTS: usize = 1024;
+
+/// The learned passthrough list: hosts whose clients keep failing the TLS
+/// handshake with the proxy, persisted in `learned_passthrough`.
+///
+/// Learned hosts are tunnelled like `no_intercept` hosts, right away with
+/// `tls_fallback.auto_apply` or else once promoted. Revoked hosts stay
+/// intercepted.
+pub struct LearnedHosts {
+    enabled: bool,
+    threshold: usize,
+    window: Duration,
+    auto_apply: bool,
+    db: Arc<Mutex<Database>>,
+    /// Recent failure times per host not learned yet.
+    failures: std::sync::Mutex<HashMap<String, VecDeque<Instant>>>,
+    /// Statuses by host, with the time they were read.
+    cache: Mutex<Option<(Instant, HashMap<String, String>)>>,
+}
+
+impl LearnedHosts {
+    /// Creates a new LearnedHosts.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg` - Application configuration.
+    /// * `db` - Database holding the learned list.
+    pub fn new(cfg: &Config, db: Arc<Mutex<Database>>) -> Self {
+        Self::from_fallback(&cfg.tls_fallback, db)
+    }
+
+    /// Creates a LearnedHosts from `tls_fallback` settings alone.
+    pub fn from_fallback(fallback: &TlsFallbackConfig, db: Arc<Mutex<Database>>) -> Self {
+        Self {
+            enabled: fallback.enabled,
+            threshold: fallback.failures.max(1),
+            window: Duration::from_secs(fallback.window_seconds),
+            auto_apply: fallback.auto_apply,
+            db,
+            failures: std::sync::Mutex::new(HashMap::new()),
+            cache: Mutex::new(None),
+        }
+    }
+
+    /// Whether connections to `host` are to be tunnelled.
+    pub async fn contains(&self, host: &str) -> bool {
+        let stale = self
+            .cache
+            .lock()
+            .await
+            .as_ref()
+            .is_none_or(|(loaded, _)| loaded.elapsed() >= RELOAD_INTERVAL);
+        if stale {
+            // Read without holding the cache lock, which `handshake_failed`
+            // takes while the database is free
+            let hosts = self.db.lock().await.learned_hosts().await;
+            match hosts {
+                Ok(hosts) => {
+                    let statuses = hosts.into_iter().map(|h| (h.host, h.status)).collect();
+                    *self.cache.lock().await = Some((Instant::now(), statuses));
+                }
+                Err(e) => warn!("Failed to read the learned passthrough list: {}", e),
+            }
+        }
+        let cache = self.cache.lock().await;
+        match cache.as_ref().and_then(|(_, statuses)| statuses.get(host)) {
+            Some(status) if status == PROMOTED => true,
+            Some(status) if status == LEARNED => self.auto_apply,
+            _ => false,
+        }
+    }
+
+    /// Counts a client that failed the TLS handshake for `host`, adding the
+    /// host to the list once `tls_fallback.failures` happen within the window.
+    pub async fn handshake_failed(&self, host: &str) {
+        if !self.enabled {
+            return;
+        }
+        let count = {
+            let now = Instant::now();
+            let mut failures = self.failures.lock().unwrap();
+            if failures.len() >= MAX_TRACKED_HOSTS {
+                failures.retain(|_, times| {
+                    times
+                        .back()
+                        .is_some_and(|t| now.duration_since(*t) <= self.window)
+                });
+            }
+            let times = failures.entry(host.to_string()).or_default();
+            times.push_back(now);
+            while times
+                .front()
+                .is_some_and(|t| now.duration_since(*t) > self.window)
+            {
+                times.pop_front();
+            }
+            let count = times.len();
+            if count >= self.threshold {
+                failures.remove(host);
+            }
+            count
+        };
+        info!(
+            "Client failed the TLS handshake for {} ({} of {} within {}s)",
+            host,
+            count,
+            self.threshold,
+            self.window.as_secs()
+        );
+        if count < self.threshold {
+            return;
+        }
+        let learned = self.db.lock().await.learn_host(host, count as i64).await;
+        match learned {
+            Ok(true) => {
+                warn!(
+                    "Learned {} as a passthrough host after {} failed handshakes; review with `blanktrace learned list`",
+                    host, count
+                );
+                *self.cache.lock().await = None;
+            }
+            // Already listed, possibly revoked
+            Ok(false) => {}
+            Err(e) => warn!("Failed to store learned passthrough host {}: {}", host, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn learned(auto_apply: bool) -> (LearnedHosts, Arc<Mutex<Database>>) {
+        let db = Arc::new(Mutex::new(Database::new(":memory:").unwrap()));
+        let fallback = TlsFallbackConfig {
+            enabled: true,
+            failures: 2,
+            window_seconds: 60,
+            auto_apply,
+        };
+        (LearnedHosts::from_fallback(&fallback, db.clone()), db)
+    }
+
+    #[tokio::test]
+    async fn test_learn_after_repeated_failures() {
+        let (learned, db) = learned(true);
+        learned.handshake_failed("pinned.example").await;
+        assert!(!learned.contains("pinned.example").await);
+        learned.handshake_failed("pinned.example").await;
+        assert!(learned.contains("pinned.example").await);
+        assert!(!learned.contains("other.example").await);
+
+        let hosts = db.lock().await.learned_hosts().await.unwrap();
+        assert_eq!(hosts.len(), 1);
+        assert_eq!(hosts[0].status, LEARNED);
+        assert_eq!(hosts[0].failures, 2);
+
+        // Revoked hosts are intercepted and not learned again
+        db.lock()
+            .await
+            .review_learned_host("pinned.example", REVOKED)
+            .await
+            .unwrap();
+        *learned.cache.lock().await = None;
+        learned.handshake_failed("pinned.example").await;
+        learned.handshake_failed("pinned.example").await;
+        assert!(!learned.contains("pinned.example").await);
+    }
+
+    #[tokio::test]
+    async fn test_review_required_without_auto_apply() {
+        let (learned, db) = learned(false);
+        learned.handshake_failed("pinned.example").await;
+        learned.handshake_failed("pinned.example").await;
+        assert!(!learned.contains("pinned.example").await);
+
+        db.lock()
+            .await
+            .review_learned_host("pinned.example", PROMOTED)
+            .await
+            .unwrap();
+        *learned.cache.lock().await = None;
+        assert!(learned.contains("pinned.example").await);
+    }
+}
@@ -0,0 +1,1531 @@
+use crate::auth::ProxyAuth;
+use crate::config::{Config, ListenConfig};
+use crate::db::LogEvent;
+use crate::pac::{PacGenerator, PAC_CONTENT_TYPE};
+use crate::passthrough::{Passthrough, TunnelReason};
+use crate::sniff::{sniff, Sniffed, Watched};
+use crate::socks;
+use crate::upstream::splice_upgrade;
+use anyhow::{bail, Context};
+use hyper::client::conn::SendRequest;
+use hyper::header::{HOST, PROXY_AUTHORIZATION, UPGRADE};
+use hyper::http::uri::Authority;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use ipnet::IpNet;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{self, Poll};
+use std::time::Duration;
+use tokio::io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::Sender;
+
+/// Upper bound for the first request head, read before a connection is piped.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Time a client has to send the first request head.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Protocol spoken by clients of a listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// HTTP proxy requests and CONNECT tunnels.
+    Http,
+    /// SOCKS5 CONNECT.
+    Socks5,
+    /// Connections redirected by iptables/nftables `REDIRECT` or `TPROXY`.
+    Transparent,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Http => write!(f, "HTTP"),
+            Protocol::Socks5 => write!(f, "SOCKS5"),
+            Protocol::Transparent => write!(f, "transparent"),
+        }
+    }
+}
+
+/// A client connection accepted by a front-end listener.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// Address the client connected from.
+    pub addr: SocketAddr,
+    /// Authenticated proxy user, if authentication is enabled.
+    pub user: Option<String>,
+    /// Whether the client opened a CONNECT tunnel, rather than sending proxy
+    /// requests or coming through SOCKS5 or a transparent listener.
+    pub via_connect: bool,
+    /// Address a transparent client connected to. Its requests are sent
+    /// there, the host name only selecting the certificate and policies.
+    pub destination: Option<SocketAddr>,
+}
+
+/// Real clients of the connections piped to the internal proxy, keyed by the
+/// local port of the internal connection.
+///
+/// hudsucker only sees the front-end as its client; the handler uses this map
+/// to recover who actually connected.
+#[derive(Default)]
+pub struct ClientMap {
+    clients: Mutex<HashMap<u16, ClientInfo>>,
+}
+
+impl ClientMap {
+    /// Returns the client behind a connection accepted by the internal proxy,
+    /// or None if the connection did not come through a front-end listener.
+    pub fn resolve(&self, internal: SocketAddr) -> Option<ClientInfo> {
+        if !internal.ip().is_loopback() {
+            return None;
+        }
+        self.clients.lock().unwrap().get(&internal.port()).cloned()
+    }
+
+    fn insert(&self, port: u16, client: ClientInfo) {
+        self.clients.lock().unwrap().insert(port, client);
+    }
+
+    fn remove(&self, port: u16) {
+        self.clients.lock().unwrap().remove(&port);
+    }
+}
+
+/// Accepts client connections on the configured addresses, enforces the
+/// client allow list and pipes accepted connections to the internal proxy.
+///
+/// Requests addressed to the proxy itself rather than through it are answered
+/// by the front-end; it serves the PAC file.
+///
+/// Streams from SOCKS5 clients and redirected connections are handed to the
+/// internal proxy as well when they carry TLS or plain HTTP; anything else
+/// goes to the passthrough.
+pub struct Frontend {
+    addresses: Vec<SocketAddr>,
+    socks_addresses: Vec<SocketAddr>,
+    transparent_addresses: Vec<SocketAddr>,
+    allowed: Vec<IpNet>,
+    clients: Arc<ClientMap>,
+    auth: Arc<ProxyAuth>,
+    passthrough: Arc<Passthrough>,
+    pac: Arc<PacGenerator>,
+    db_logger: Sender<LogEvent>,
+}
+
+impl Frontend {
+    /// Creates a new Frontend.
+    ///
+    /// Fails on invalid addresses or networks, when a non-loopback address
+    /// is configured without `listen.allow_remote`, and on transparent
+    /// listeners outside Linux.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg` - Application configuration.
+    /// * `clients` - Map the real clients are recorded in.
+    /// * `auth` - Proxy authentication checked on every new connection.
+    /// * `passthrough` - Tunnels streams that cannot be intercepted.
+    /// * `db_logger` - Channel for rejected connection events.
+    pub fn new(
+        cfg: &Config,
+        clients: Arc<ClientMap>,
+        auth: Arc<ProxyAuth>,
+        passthrough: Arc<Passthrough>,
+        db_logger: Sender<LogEvent>,
+    ) -> anyhow::Result<Self> {
+        let port = cfg.port.unwrap_or(8080);
+        let pac = Arc::new(PacGenerator::new(cfg)?);
+        Self::from_listen(
+            &cfg.listen,
+            port,
+            clients,
+            auth,
+            passthrough,
+            pac,
+            db_logger,
+        )
+    }
+
+    /// Creates a Frontend from listen settings, defaulting to loopback on `port`.
+    pub fn from_listen(
+        listen: &ListenConfig,
+        port: u16,
+        clients: Arc<ClientMap>,
+        auth: Arc<ProxyAuth>,
+        passthrough: Arc<Passthrough>,
+        pac: Arc<PacGenerator>,
+        db_logger: Sender<LogEvent>,
+    ) -> anyhow::Result<Self> {
+        let addresses = if listen.addresses.is_empty() {
+            vec![SocketAddr::from(([127, 0, 0, 1], port))]
+        } else {
+            parse_addresses(&listen.addresses)?
+        };
+        let socks_addresses = parse_addresses(&listen.socks_addresses)?;
+        let transparent_addresses = parse_addresses(&listen.transparent_addresses)?;
+        if !transparent_addresses.is_empty() && !cfg!(target_os = "linux") {
+            bail!("transparent listeners are only supported on Linux");
+        }
+        if !listen.allow_remote {
+            if let Some(addr) = addresses
+                .iter()
+                .chain(&socks_addresses)
+                .chain(&transparent_addresses)
+                .find(|a| !a.ip().is_loopback())
+            {
+                bail!(
+                    "refusing to listen on {} without listen.allow_remote: this would expose the proxy beyond this machine",
+                    addr
+                );
+            }
+        }
+        let allowed = listen
+            .allow_clients
+            .iter()
+            .map(|n| {
+                n.parse::<IpNet>()
+                    .or_else(|_| n.parse::<IpAddr>().map(IpNet::from))
+                    .with_context(|| format!("invalid client network '{}'", n))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            addresses,
+            socks_addresses,
+            transparent_addresses,
+            allowed,
+            clients,
+            auth,
+            passthrough,
+            pac,
+            db_logger,
+        })
+    }
+
+    /// The configured listen addresses.
+    pub fn addresses(&self) -> &[SocketAddr] {
+        &self.addresses
+    }
+
+    /// Loopback clients are always allowed, others only from `allow_clients`.
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        ip.is_loopback() || self.allowed.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Binds every listen address and serves them in the background.
+    ///
+    /// Returns the protocol and bound address of each listener once all
+    /// listeners are up.
+    ///
+    /// # Arguments
+    ///
+    /// * `internal` - Address of the internal proxy accepted connections are piped to.
+    pub async fn bind(self, internal: SocketAddr) -> anyhow::Result<Vec<(Protocol, SocketAddr)>> {
+        let configured = self
+            .addresses
+            .iter()
+            .map(|a| (Protocol::Http, a))
+            .chain(self.socks_addresses.iter().map(|a| (Protocol::Socks5, a)))
+            .chain(
+                self.transparent_addresses
+                    .iter()
+                    .map(|a| (Protocol::Transparent, a)),
+            );
+        let mut listeners = Vec::new();
+        for (protocol, addr) in configured {
+            let listener = match protocol {
+                Protocol::Transparent => bind_transparent(*addr),
+                _ => TcpListener::bind(addr).await,
+            }
+            .with_context(|| format!("failed to listen on {}", addr))?;
+            listeners.push((protocol, listener));
+        }
+        if self.auth.is_enabled() && !self.transparent_addresses.is_empty() {
+            warn!("Transparent connections carry no proxy credentials and are admitted by the client allow list alone");
+        }
+        let bound = listeners
+            .iter()
+            .map(|(protocol, l)| l.local_addr().map(|a| (*protocol, a)))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let frontend = Arc::new(self);
+        for (protocol, listener) in listeners {
+            tokio::spawn(frontend.clone().serve(listener, protocol, internal));
+        }
+        Ok(bound)
+    }
+
+    async fn serve(
+        self: Arc<Self>,
+        listener: TcpListener,
+        protocol: Protocol,
+        internal: SocketAddr,
+    ) {
+        loop {
+            let (stream, client) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            if !self.is_allowed(client.ip()) {
+                let local = listener
+                    .local_addr()
+                    .map(|a| a.to_string())
+                    .unwrap_or_default();
+                warn!("Rejected connection from {} on {}", client, local);
+                // Never wait on the logger here: a flood of rejected clients
+                // would stall accepting the allowed ones
+                let _ = self.db_logger.try_send(LogEvent::RejectedClient {
+                    client_ip: client.ip().to_canonical().to_string(),
+                    listener: local,
+                });
+                continue;
+            }
+            let frontend = self.clone();
+            let local = listener.local_addr();
+            tokio::spawn(async move {
+                let result = match (protocol, local) {
+                    (Protocol::Http, _) => frontend.pipe(stream, client, internal).await,
+                    (Protocol::Socks5, _) => frontend.socks(stream, client, internal).await,
+                    (Protocol::Transparent, Ok(local)) => {
+                        frontend.transparent(stream, client, local, internal).await
+                    }
+                    (Protocol::Transparent, Err(e)) => Err(e),
+                };
+                if let Err(e) = result {
+                    debug!("Connection from {} ended: {}", client, e);
+                }
+            });
+        }
+    }
+
+    /// Pipes a client connection to the internal proxy, recording the client
+    /// for the lifetime of the connection.
+    ///
+    /// A first request in origin form is addressed to the proxy itself and
+    /// served locally. With authentication enabled the first request head must
+    /// carry valid credentials, otherwise the client gets a `407` and is
+    /// disconnected. A connection that does not start with a CONNECT is
+    /// relayed request by request, see `relay`. CONNECT requests to
+    /// `no_intercept` or learned hosts go to the passthrough; for others, a
+    /// client failing the TLS handshake is counted towards learning the host.
+    async fn pipe(
+        &self,
+        mut inbound: TcpStream,
+        client: SocketAddr,
+        internal: SocketAddr,
+    ) -> std::io::Result<()> {
+        let mut head = Vec::new();
+        read_head(&mut inbound, &mut head).await?;
+        if let Some(path) = origin_form_path(&head) {
+            return self.serve_local(inbound, client, &path).await;
+        }
+        let mut user = None;
+        if self.auth.is_enabled() {
+            let credentials = proxy_authorization(&head);
+            user = self
+                .auth
+                .authenticate(client.ip(), credentials.as_deref())
+                .await;
+            if user.is_none() {
+                if credentials.is_some() {
+                    warn!("Proxy authentication failed for {}", client);
+                }
+                let response = format!(
+                    "HTTP/1.1 407 Proxy Authentication Required\r\n\
+                     Proxy-Authenticate: {}\r\n\
+                     Content-Length: 0\r\n\
+                     Connection: close\r\n\r\n",
+                    self.auth.challenge()
+                );
+                inbound.write_all(response.as_bytes()).await?;
+                return inbound.shutdown().await;
+            }
+        }
+
+        let connect = connect_authority(&head);
+        let client = ClientInfo {
+            addr: client,
+            user,
+            via_connect: connect.is_some(),
+            destination: None,
+        };
+        let Some((host, port)) = connect else {
+            let (ip, user) = (client.addr.ip(), client.user.clone());
+            let (outbound, port) = self.connect_internal(internal, client).await?;
+            let inbound = Rewound::new(head, inbound);
+            let result = relay(inbound, outbound, self.auth.clone(), ip, user).await;
+            self.clients.remove(port);
+            return result;
+        };
+        if let Some(reason) = self.passthrough.no_intercept(&host).await {
+            return self
+                .connect_opaque(inbound, client, &host, port, reason)
+                .await;
+        }
+
+        let (mut outbound, local_port) = self.connect_internal(internal, client).await?;
+        let mut inbound = Watched::new(inbound);
+        let mut result = outbound.write_all(&head).await;
+        if result.is_ok() {
+            result = copy_bidirectional(&mut inbound, &mut outbound)
+                .await
+                .map(|_| ());
+        }
+        self.clients.remove(local_port);
+        if inbound.watch.handshake_failed() {
+            self.passthrough.handshake_failed(&host).await;
+        }
+        result
+    }
+
+    /// Accepts a CONNECT request for a `no_intercept` or learned host and
+    /// tunnels it without terminating TLS, or answers `403` when the host is
+    /// blocked.
+    async fn connect_opaque(
+        &self,
+        mut inbound: TcpStream,
+        client: ClientInfo,
+        host: &str,
+        port: u16,
+        reason: TunnelReason,
+    ) -> std::io::Result<()> {
+        if self.passthrough.refuse(&client, host, port, reason).await {
+            inbound
+                .write_all(
+                    b"HTTP/1.1 403 Forbidden\r\n\
+                      Content-Length: 0\r\n\
+                      Connection: close\r\n\r\n",
+                )
+                .await?;
+            return inbound.shutdown().await;
+        }
+        inbound
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await?;
+        // The server name is only known once the client starts TLS
+        let server_name = match sniff(&inbound).await? {
+            Sniffed::Tls { server_name } => server_name,
+            _ => None,
+        };
+        self.passthrough
+            .tunnel(inbound, &client, host, port, server_name.as_deref(), reason)
+            .await
+    }
+
+    /// Answers a request made to the proxy itself: the PAC file, or `404`.
+    ///
+    /// Browsers fetch the PAC before they know about the proxy, so no
+    /// credentials are asked for.
+    async fn serve_local(
+        &self,
+        mut inbound: TcpStream,
+        client: SocketAddr,
+        path: &str,
+    ) -> std::io::Result<()> {
+        let local = inbound.local_addr()?.ip();
+        let pac = self
+            .pac
+            .is_enabled()
+            .then(|| self.pac.serve(path, local))
+            .flatten();
+        let response = match pac {
+            Some(pac) => {
+                debug!("Serving {} to {}", path, client);
+                format!(
+                    "HTTP/1.1 200 OK\r\n\
+                     Content-Type: {}\r\n\
+                     Content-Length: {}\r\n\
+                     Cache-Control: no-cache\r\n\
+                     Connection: close\r\n\r\n{}",
+                    PAC_CONTENT_TYPE,
+                    pac.len(),
+                    pac
+                )
+            }
+            None => "HTTP/1.1 404 Not Found\r\n\
+                     Content-Length: 0\r\n\
+                     Connection: close\r\n\r\n"
+                .to_string(),
+        };
+        inbound.write_all(response.as_bytes()).await?;
+        inbound.shutdown().await
+    }
+
+    /// Accepts a SOCKS5 client and intercepts the stream it opens.
+    async fn socks(
+        &self,
+        mut inbound: TcpStream,
+        client: SocketAddr,
+        internal: SocketAddr,
+    ) -> std::io::Result<()> {
+        let refuse = async |request: &socks::SocksRequest| {
+            let info = ClientInfo {
+                addr: client,
+                user: request.user.clone(),
+                via_connect: false,
+                destination: None,
+            };
+            let (host, port) = (&request.host, request.port);
+            self.passthrough
+                .refuse(&info, host, port, TunnelReason::Blocked)
+                .await
+        };
+        let Some(request) = socks::accept(&mut inbound, client, &self.auth, refuse).await? else {
+            return inbound.shutdown().await;
+        };
+        let info = ClientInfo {
+            addr: client,
+            user: request.user,
+            via_connect: false,
+            destination: None,
+        };
+        self.intercept(inbound, info, &request.host, request.port, internal)
+            .await
+    }
+
+    /// Intercepts a connection redirected to a transparent listener.
+    ///
+    /// The destination is recovered from the connection and the host name
+    /// taken from the TLS SNI or `Host` header. Transparent clients cannot
+    /// send proxy credentials, so only the client allow list applies.
+    async fn transparent(
+        &self,
+        inbound: TcpStream,
+        client: SocketAddr,
+        listener: SocketAddr,
+        internal: SocketAddr,
+    ) -> std::io::Result<()> {
+        let destination = original_destination(&inbound)?;
+        if destination == inbound.local_addr()? && destination.port() == listener.port() {
+            warn!(
+                "Refusing connection from {} addressed to the transparent listener itself",
+                client
+            );
+            return Ok(());
+        }
+        let info = ClientInfo {
+            addr: client,
+            user: None,
+            via_connect: false,
+            destination: Some(destination),
+        };
+        let host = destination.ip().to_canonical().to_string();
+        // Redirected connections cannot be answered with an error
+        if self
+            .passthrough
+            .refuse(&info, &host, destination.port(), TunnelReason::Blocked)
+            .await
+        {
+            return Ok(());
+        }
+        self.intercept(inbound, info, &host, destination.port(), internal)
+            .await
+    }
+
+    /// Hands a stream to `host:port` to the internal proxy, so the usual
+    /// interception, blocking, cookie and fingerprint handling applies.
+    ///
+    /// TLS is fed in through a CONNECT tunnel to the SNI host, if the client
+    /// sent one, and plain HTTP is rewritten to proxy requests. Other
+    /// protocols, and streams to `no_intercept` or learned hosts by address
+    /// or server name, go to the passthrough. `host` is expected to have
+    /// passed the blocker already.
+    async fn intercept(
+        &self,
+        inbound: TcpStream,
+        client: ClientInfo,
+        host: &str,
+        port: u16,
+        internal: SocketAddr,
+    ) -> std::io::Result<()> {
+        let sniffed = sniff(&inbound).await?;
+        let server_name = match &sniffed {
+            Sniffed::Tls { server_name } => server_name.as_deref(),
+            _ => None,
+        };
+        let mut reason = self.passthrough.no_intercept(host).await;
+        if let (None, Some(name)) = (reason, server_name) {
+            reason = self.passthrough.no_intercept(name).await;
+        }
+        if let Some(reason) = reason {
+            return self
+                .passthrough
+                .tunnel(inbound, &client, host, port, server_name, reason)
+                .await;
+        }
+        match sniffed {
+            Sniffed::Tls { server_name } => {
+                let host = server_name.as_deref().unwrap_or(host);
+                self.connect_tunnel(inbound, client, host, port, internal)
+                    .await
+            }
+            Sniffed::Http => {
+                let (outbound, local_port) = self.connect_internal(internal, client).await?;
+                let result = bridge(inbound, outbound, authority(host, port)).await;
+                self.clients.remove(local_port);
+                result
+            }
+            Sniffed::Other => {
+                self.passthrough
+                    .tunnel(inbound, &client, host, port, None, TunnelReason::Protocol)
+                    .await
+            }
+        }
+    }
+
+    /// Opens a CONNECT tunnel to `host:port` through the internal proxy and
+    /// pipes the client's TLS stream into it, counting a failed handshake
+    /// towards learning the host.
+    async fn connect_tunnel(
+        &self,
+        inbound: TcpStream,
+        client: ClientInfo,
+        host: &str,
+        port: u16,
+        internal: SocketAddr,
+    ) -> std::io::Result<()> {
+        let (mut outbound, local_port) = self.connect_internal(internal, client).await?;
+        let mut inbound = Watched::new(inbound);
+        let result = async {
+            let connect = format!(
+                "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n",
+                authority(host, port)
+            );
+            outbound.write_all(connect.as_bytes()).await?;
+            let mut head = Vec::new();
+            read_head(&mut outbound, &mut head).await?;
+            if !head.starts_with(b"HTTP/1.1 200") {
+                return Err(std::io::Error::other("internal proxy refused CONNECT"));
+            }
+            copy_bidirectional(&mut inbound, &mut outbound)
+                .await
+                .map(|_| ())
+        }
+        .await;
+        self.clients.remove(local_port);
+        if inbound.watch.handshake_failed() {
+            self.passthrough.handshake_failed(host).await;
+        }
+        result
+    }
+
+    /// Connects to the internal proxy and records `client` as the client
+    /// behind the connection. Returns the connection and its local port, which
+    /// the caller removes from the client map when done.
+    async fn connect_internal(
+        &self,
+        internal: SocketAddr,
+        client: ClientInfo,
+    ) -> std::io::Result<(TcpStream, u16)> {
+        let outbound = TcpStream::connect(internal).await?;
+        let port = outbound.local_addr()?.port();
+        self.clients.insert(port, client);
+        Ok((outbound, port))
+    }
+}
+
+/// Parses configured socket addresses.
+fn parse_addresses(addresses: &[String]) -> anyhow::Result<Vec<SocketAddr>> {
+    addresses
+        .iter()
+        .map(|a| {
+            a.parse::<SocketAddr>()
+                .with_context(|| format!("invalid listen address '{}'", a))
+        })
+        .collect()
+}
+
+/// Binds a listener for redirected connections.
+///
+/// `TPROXY` needs `IP_TRANSPARENT`, which requires `CAP_NET_ADMIN`; `REDIRECT`
+/// works without it.
+#[cfg(target_os = "linux")]
+fn bind_transparent(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    use socket2::{Domain, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv4() {
+        if let Err(e) = socket.set_ip_transparent(true) {
+            info!(
+                "IP_TRANSPARENT unavailable on {} ({}): TPROXY will not work, REDIRECT will",
+                addr, e
+            );
+        }
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_transparent(_addr: SocketAddr) -> std::io::Result<TcpListener> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Recovers where a redirected connection was headed: `SO_ORIGINAL_DST` for
+/// `REDIRECT`, otherwise the local address, which `TPROXY` leaves untouched.
+#[cfg(target_os = "linux")]
+fn original_destination(stream: &TcpStream) -> std::io::Result<SocketAddr> {
+    let local = stream.local_addr()?;
+    let socket = socket2::SockRef::from(stream);
+    let nat = if local.is_ipv6() {
+        socket.original_dst_ipv6()
+    } else {
+        socket.original_dst()
+    };
+    Ok(nat.ok().and_then(|a| a.as_socket()).unwrap_or(local))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn original_destination(stream: &TcpStream) -> std::io::Result<SocketAddr> {
+    stream.local_addr()
+}
+
+/// Serves the plain HTTP requests of a tunnelled stream by forwarding them
+/// over one connection to the internal proxy.
+///
+/// Requests are rewritten to the absolute form a proxy expects, using the
+/// `Host` header or else the destination the client connected to.
+async fn bridge(inbound: TcpStream, outbound: TcpStream, authority: String) -> std::io::Result<()> {
+    let (sender, connection) = hyper::client::conn::Builder::new()
+        .http1_preserve_header_case(true)
+        .handshake::<_, Body>(outbound)
+        .await
+        .map_err(std::io::Error::other)?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("Internal proxy connection ended: {}", e);
+        }
+    });
+    let sender = Arc::new(tokio::sync::Mutex::new(sender));
+    let service = service_fn(move |request| {
+        let sender = sender.clone();
+        let authority = authority.clone();
+        async move {
+            let host = request
+                .headers()
+                .get(HOST)
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or(&authority)
+                .to_string();
+            let response = forward(request, &sender, &authority).await;
+            Ok::<_, Infallible>(response.unwrap_or_else(|e| {
+                debug!("Bridged request to {} failed: {}", host, e);
+                status_response(StatusCode::BAD_GATEWAY)
+            }))
+        }
+    });
+    Http::new()
+        .http1_preserve_header_case(true)
+        .serve_connection(inbound, service)
+        .with_upgrades()
+        .await
+        .map_err(std::io::Error::other)
+}
+
+/// Serves the requests of a plain proxy connection through one internal
+/// proxy connection, keeping both alive.
+///
+/// Each request head is checked like the first one was: a CONNECT, a request
+/// to the proxy itself or one with other credentials closes the connection
+/// without an answer, and the client retries it on a new connection where the
+/// front-end sees it first.
+async fn relay(
+    inbound: Rewound<TcpStream>,
+    outbound: TcpStream,
+    auth: Arc<ProxyAuth>,
+    client: IpAddr,
+    user: Option<String>,
+) -> std::io::Result<()> {
+    let (sender, connection) = hyper::client::conn::Builder::new()
+        .http1_preserve_header_case(true)
+        .handshake::<_, Body>(outbound)
+        .await
+        .map_err(std::io::Error::other)?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("Internal proxy connection ended: {}", e);
+        }
+    });
+    let sender = Arc::new(tokio::sync::Mutex::new(sender));
+    let user = Arc::new(user);
+    let service = service_fn(move |request| {
+        let (sender, auth, user) = (sender.clone(), auth.clone(), user.clone());
+        async move {
+            if !admits(&auth, client, user.as_deref(), &request).await {
+                return Err(std::io::Error::other(format!(
+                    "{} {} needs a new connection",
+                    request.method(),
+                    request.uri()
+                )));
+            }
+            // Once the internal proxy closes its side, so does the client's
+            forward(request, &sender, "")
+                .await
+                .map_err(std::io::Error::other)
+        }
+    });
+    Http::new()
+        .http1_preserve_header_case(true)
+        .http1_header_read_timeout(HEAD_TIMEOUT)
+        .serve_connection(inbound, service)
+        .with_upgrades()
+        .await
+        .map_err(std::io::Error::other)
+}
+
+/// Whether a request on a kept-alive proxy connection may go to the internal
+/// proxy: it is a proxy request other than CONNECT, and any credentials it
+/// carries are those of the user the connection was authenticated as.
+async fn admits(
+    auth: &ProxyAuth,
+    client: IpAddr,
+    user: Option<&str>,
+    request: &Request<Body>,
+) -> bool {
+    if request.method() == Method::CONNECT || request.uri().authority().is_none() {
+        return false;
+    }
+    match request.headers().get(PROXY_AUTHORIZATION) {
+        Some(credentials) if auth.is_enabled() => {
+            auth.authenticate(client, credentials.to_str().ok())
+                .await
+                .as_deref()
+                == user
+        }
+        _ => true,
+    }
+}
+
+/// Forwards one bridged or relayed request to the internal proxy.
+///
+/// Fails when the internal proxy connection is closed or broken.
+async fn forward(
+    mut request: Request<Body>,
+    sender: &tokio::sync::Mutex<SendRequest<Body>>,
+    authority: &str,
+) -> hyper::Result<Response<Body>> {
+    // Requests in origin form are rewritten to the absolute form
+    if request.uri().authority().is_none() {
+        let host = request
+            .headers()
+            .get(HOST)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or(authority);
+        let path = request
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+        match format!("http://{}{}", host, path).parse() {
+            Ok(uri) => *request.uri_mut() = uri,
+            Err(_) => return Ok(status_response(StatusCode::BAD_REQUEST)),
+        }
+    }
+    let client_upgrade = request
+        .headers()
+        .contains_key(UPGRADE)
+        .then(|| hyper::upgrade::on(&mut request));
+
+    let mut sender = sender.lock().await;
+    let sent = async {
+        std::future::poll_fn(|cx| sender.poll_ready(cx)).await?;
+        sender.send_request(request).await
+    };
+    let mut response = sent.await?;
+    if let Some(client_upgrade) = client_upgrade {
+        splice_upgrade(client_upgrade, &mut response);
+    }
+    Ok(response)
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+/// Reads from `stream` into `buf` until the end of the request head, giving
+/// up after `HEAD_TIMEOUT`.
+async fn read_head(stream: &mut TcpStream, buf: &mut Vec<u8>) -> std::io::Result<()> {
+    let read = async {
+        let mut chunk = [0; 4096];
+        while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < MAX_HEAD_SIZE {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                break;
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        Ok(())
+    };
+    tokio::time::timeout(HEAD_TIMEOUT, read)
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "no request head"))?
+}
+
+/// Client stream that first yields the request head already read from it.
+struct Rewound<S> {
+    head: Vec<u8>,
+    inner: S,
+}
+
+impl<S> Rewound<S> {
+    fn new(head: Vec<u8>, inner: S) -> Self {
+        Self { head, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewound<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.head.is_empty() {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        let n = self.head.len().min(buf.remaining());
+        buf.put_slice(&self.head[..n]);
+        self.head.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewound<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Extracts the `Proxy-Authorization` header from a request head.
+fn proxy_authorization(head: &[u8]) -> Option<String> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    request.parse(head).ok()?;
+    request
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("proxy-authorization"))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+        .map(str::to_string)
+}
+
+/// Returns the path of a `GET` request in origin form, which a client only
+/// sends to the proxy when addressing the proxy itself.
+fn origin_form_path(head: &[u8]) -> Option<String> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    request.parse(head).ok()?;
+    let path = request.path?;
+    (request.method == Some("GET") && path.starts_with('/')).then(|| path.to_string())
+}
+
+/// Formats `host:port`, bracketing IPv6 addresses.
+fn authority(host: &str, port: u16) -> String {
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => format!("[{}]:{}", host, port),
+        _ => format!("{}:{}", host, port),
+    }
+}
+
+/// Returns the host and port of a `CONNECT` request.
+fn connect_authority(head: &[u8]) -> Option<(String, u16)> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    request.parse(head).ok()?;
+    if request.method != Some("CONNECT") {
+        return None;
+    }
+    let authority = request.path?.parse::<Authority>().ok()?;
+    let host = authority
+        .host()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    Some((
+        host.to_ascii_lowercase(),
+        authority.port_u16().unwrap_or(443),
+    ))
+}
+
+/// Picks a free loopback address for the internal proxy.
+pub fn internal_address() -> std::io::Result<SocketAddr> {
+    std::net::TcpListener::bind(("127.0.0.1", 0))?.local_addr()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocker::Blocker;
+    use crate::config::{BlockingConfig, PacConfig, TlsFallbackConfig};
+    use crate::db::Database;
+    use crate::learned::LearnedHosts;
+    use crate::upstream::Upstream;
+
+    fn no_auth() -> Arc<ProxyAuth> {
+        Arc::new(ProxyAuth::from_users(false, "test", Vec::new()).unwrap())
+    }
+
+    /// Passthrough that blocks nothing, connects directly and does not
+    /// intercept `localhost`.
+    fn passthrough() -> Arc<Passthrough> {
+        passthrough_with(Arc::new(tokio::sync::Mutex::new(
+            Database::new(":memory:").unwrap(),
+        )))
+    }
+
+    /// Passthrough learning hosts from `db` after a single failed handshake,
+    /// blocking hosts containing "tracker".
+    fn passthrough_with(db: Arc<tokio::sync::Mutex<Database>>) -> Arc<Passthrough> {
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let blocking = BlockingConfig {
+            auto_block: true,
+            auto_block_threshold: 1,
+            block_patterns: vec!["tracker".to_string()],
+        };
+        let blocker = Arc::new(Blocker::from_blocking(&blocking, db.clone(), tx.clone()));
+        let fallback = TlsFallbackConfig {
+            failures: 1,
+            auto_apply: true,
+            ..Default::default()
+        };
+        let learned = Arc::new(LearnedHosts::from_fallback(&fallback, db));
+        let hosts = vec!["localhost".to_string(), "tracker.localhost".to_string()];
+        let upstream = Arc::new(Upstream::default());
+        Arc::new(Passthrough::from_hosts(&hosts, blocker, upstream, learned, tx).unwrap())
+    }
+
+    fn pac() -> Arc<PacGenerator> {
+        let http = "127.0.0.1:8080".parse().unwrap();
+        Arc::new(PacGenerator::from_pac(&PacConfig::default(), http, None).unwrap())
+    }
+
+    fn frontend(listen: ListenConfig) -> anyhow::Result<Frontend> {
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let clients = Arc::new(ClientMap::default());
+        Frontend::from_listen(&listen, 8080, clients, no_auth(), passthrough(), pac(), tx)
+    }
+
+    #[test]
+    fn test_remote_listen_requires_opt_in() {
+        let listen = ListenConfig {
+            addresses: vec!["0.0.0.0:8080".to_string()],
+            ..Default::default()
+        };
+        assert!(frontend(listen.clone()).is_err());
+        let f = frontend(ListenConfig {
+            allow_remote: true,
+            ..listen
+        })
+        .unwrap();
+        assert_eq!(f.addresses(), ["0.0.0.0:8080".parse().unwrap()]);
+
+        let f = frontend(ListenConfig {
+            addresses: vec!["[::1]:8080".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(f.addresses()[0].is_ipv6());
+    }
+
+    #[test]
+    fn test_client_allow_list() {
+        let f = frontend(ListenConfig {
+            allow_clients: vec!["192.168.10.0/24".to_string(), "fd00::1".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(f.is_allowed("127.0.0.1".parse().unwrap()));
+        assert!(f.is_allowed("::1".parse().unwrap()));
+        assert!(f.is_allowed("192.168.10.7".parse().unwrap()));
+        assert!(f.is_allowed("::ffff:192.168.10.7".parse().unwrap()));
+        assert!(f.is_allowed("fd00::1".parse().unwrap()));
+        assert!(!f.is_allowed("192.168.11.7".parse().unwrap()));
+        assert!(!f.is_allowed("fd00::2".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_pipe_records_client() {
+        let internal = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let internal_addr = internal.local_addr().unwrap();
+        let clients = Arc::new(ClientMap::default());
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let listen = ListenConfig::default();
+        let bound = Frontend::from_listen(
+            &listen,
+            0,
+            clients.clone(),
+            no_auth(),
+            passthrough(),
+            pac(),
+            tx,
+        )
+        .unwrap()
+        .bind(internal_addr)
+        .await
+        .unwrap();
+
+        let head = b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let mut client = TcpStream::connect(bound[0].1).await.unwrap();
+        client.write_all(head).await.unwrap();
+        let (mut conn, peer) = internal.accept().await.unwrap();
+        let mut buf = Vec::new();
+        read_head(&mut conn, &mut buf).await.unwrap();
+        assert!(buf.starts_with(b"GET http://example.com/ HTTP/1.1\r\n"));
+        assert_eq!(
+            clients.resolve(peer),
+            Some(ClientInfo {
+                addr: client.local_addr().unwrap(),
+                user: None,
+                via_connect: false,
+                destination: None,
+            })
+        );
+
+        // Requests inside a CONNECT tunnel are recorded as such
+        let head = b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n";
+        let mut client = TcpStream::connect(bound[0].1).await.unwrap();
+        client.write_all(head).await.unwrap();
+        let (mut conn, peer) = internal.accept().await.unwrap();
+        let mut buf = vec![0; head.len()];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(clients.resolve(peer).map(|c| c.via_connect), Some(true));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_connection_is_kept_alive() {
+        let internal = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let bound = Frontend::from_listen(
+            &ListenConfig::default(),
+            0,
+            Arc::new(ClientMap::default()),
+            no_auth(),
+            passthrough(),
+            pac(),
+            tx,
+        )
+        .unwrap()
+        .bind(internal.local_addr().unwrap())
+        .await
+        .unwrap();
+
+        let mut client = TcpStream::connect(bound[0].1).await.unwrap();
+        let mut conn = None;
+        for path in ["/a", "/b"] {
+            let head = format!(
+                "GET http://example.com{} HTTP/1.1\r\nHost: example.com\r\n\r\n",
+                path
+            );
+            client.write_all(head.as_bytes()).await.unwrap();
+            // Both requests arrive on the same internal connection
+            if conn.is_none() {
+                conn = Some(internal.accept().await.unwrap().0);
+            }
+            let conn = conn.as_mut().unwrap();
+            let mut buf = Vec::new();
+            read_head(conn, &mut buf).await.unwrap();
+            assert!(buf.starts_with(format!("GET http://example.com{} ", path).as_bytes()));
+            conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                .await
+                .unwrap();
+            let mut buf = Vec::new();
+            read_head(&mut client, &mut buf).await.unwrap();
+            if !buf.ends_with(b"ok") {
+                let mut body = [0; 2];
+                client.read_exact(&mut body).await.unwrap();
+            }
+            assert!(buf.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        }
+
+        // A later CONNECT is not answered: the client retries it on a new
+        // connection, where the front-end checks it
+        client
+            .write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = Vec::new();
+        assert_eq!(client.read_to_end(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_later_requests_are_checked() {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+
+        let users = vec![
+            (
+                "alice".to_string(),
+                "{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=".to_string(),
+            ),
+            (
+                "bob".to_string(),
+                "{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=".to_string(),
+            ),
+        ];
+        let auth = ProxyAuth::from_users(true, "Lab", users).unwrap();
+        let client = IpAddr::from([127, 0, 0, 1]);
+        let request = |method: &str, uri: &str, user: Option<&str>| {
+            let mut builder = Request::builder().method(method).uri(uri);
+            if let Some(user) = user {
+                let credentials = STANDARD.encode(format!("{}:password", user));
+                builder = builder.header(PROXY_AUTHORIZATION, format!("Basic {}", credentials));
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        let auth = &auth;
+        let admitted = |request| async move { admits(auth, client, Some("alice"), &request).await };
+
+        assert!(admitted(request("GET", "http://example.com/", None)).await);
+        assert!(admitted(request("GET", "http://example.com/", Some("alice"))).await);
+        assert!(!admitted(request("GET", "http://example.com/", Some("bob"))).await);
+        assert!(!admitted(request("CONNECT", "example.com:443", None)).await);
+        assert!(!admitted(request("GET", "/proxy.pac", None)).await);
+    }
+
+    #[tokio::test]
+    async fn test_no_intercept_connect_is_tunnelled() {
+        let internal = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let clients = Arc::new(ClientMap::default());
+        let listen = ListenConfig::default();
+        let bound = Frontend::from_listen(&listen, 0, clients, no_auth(), passthrough(), pac(), tx)
+            .unwrap()
+            .bind(internal.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = origin.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut conn, _) = origin.accept().await.unwrap();
+            let mut buf = [0; 5];
+            conn.read_exact(&mut buf).await.unwrap();
+            conn.write_all(&buf).await.unwrap();
+        });
+
+        let mut client = TcpStream::connect(bound[0].1).await.unwrap();
+        let connect = format!(
+            "CONNECT localhost:{0} HTTP/1.1\r\nHost: localhost:{0}\r\n\r\n",
+            port
+        );
+        client.write_all(connect.as_bytes()).await.unwrap();
+        let established = b"HTTP/1.1 200 Connection established\r\n\r\n";
+        let mut buf = vec![0; established.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, established);
+        // Stand-in for a ClientHello, passed on untouched
+        client.write_all(&[0x16, 3, 1, 0, 0]).await.unwrap();
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, [0x16, 3, 1, 0, 0]);
+
+        // The tunnel counts the tracker hit, which blocks it at the threshold
+        // of 1, so the next CONNECT is refused before it is confirmed
+        let connect = format!(
+            "CONNECT tracker.localhost:{0} HTTP/1.1\r\nHost: tracker.localhost:{0}\r\n\r\n",
+            port
+        );
+        let mut client = TcpStream::connect(bound[0].1).await.unwrap();
+        client.write_all(connect.as_bytes()).await.unwrap();
+        client.write_all(&[0x16, 3, 1, 0, 0]).await.unwrap();
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, established);
+        let mut client = TcpStream::connect(bound[0].1).await.unwrap();
+        client.write_all(connect.as_bytes()).await.unwrap();
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.starts_with(b"HTTP/1.1 403 Forbidden\r\n"));
+
+        let accepted =
+            tokio::time::timeout(std::time::Duration::from_millis(100), internal.accept()).await;
+        assert!(accepted.is_err(), "tunnel must bypass the internal proxy");
+    }
+
+    #[tokio::test]
+    async fn test_failed_handshake_is_learned() {
+        let db = Arc::new(tokio::sync::Mutex::new(Database::new(":memory:").unwrap()));
+        let internal = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let clients = Arc::new(ClientMap::default());
+        let listen = ListenConfig::default();
+        let passthrough = passthrough_with(db.clone());
+        let bound = Frontend::from_listen(&listen, 0, clients, no_auth(), passthrough, pac(), tx)
+            .unwrap()
+            .bind(internal.local_addr().unwrap())
+            .await
+            .unwrap();
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connect = format!(
+            "CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n",
+            origin.local_addr().unwrap()
+        );
+        let established = b"HTTP/1.1 200 Connection established\r\n\r\n";
+
+        // The internal proxy accepts the CONNECT; the client answers its
+        // certificate with an alert
+        tokio::spawn(async move {
+            let (mut conn, _) = internal.accept().await.unwrap();
+            let mut head = Vec::new();
+            read_head(&mut conn, &mut head).await.unwrap();
+            conn.write_all(established).await.unwrap();
+            let mut rest = Vec::new();
+            let _ = conn.read_to_end(&mut rest).await;
+        });
+        let mut client = TcpStream::connect(bound[0].1).await.unwrap();
+        client.write_all(connect.as_bytes()).await.unwrap();
+        let mut buf = vec![0; established.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        client.write_all(&[0x16, 3, 1, 0, 0]).await.unwrap();
+        client.write_all(&[0x15, 3, 3, 0, 2, 2, 42]).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+
+        let mut learned = Vec::new();
+        for _ in 0..50 {
+            learned = db.lock().await.learned_hosts().await.unwrap();
+            if !learned.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(learned.len(), 1);
+        assert_eq!(learned[0].host, "127.0.0.1");
+
+        // The host is now tunnelled to the origin itself
+        tokio::spawn(async move {
+            let (mut conn, _) = origin.accept().await.unwrap();
+            conn.write_all(b"origin").await.unwrap();
+        });
+        let mut client = TcpStream::connect(bound[0].1).await.unwrap();
+        client.write_all(connect.as_bytes()).await.unwrap();
+        let mut buf = vec![0; established.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        client.write_all(&[0x16, 3, 1, 0, 0]).await.unwrap();
+        let mut buf = [0; 6];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"origin");
+    }
+
+    #[tokio::test]
+    async fn test_connection_requires_credentials() {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+
+        let internal = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let internal_addr = internal.local_addr().unwrap();
+        let clients = Arc::new(ClientMap::default());
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let users = vec![(
+            "alice".to_string(),
+            "{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=".to_string(),
+        )];
+        let auth = Arc::new(ProxyAuth::from_users(true, "Lab", users).unwrap());
+        let bound = Frontend::from_listen(
+            &ListenConfig::default(),
+            0,
+            clients.clone(),
+            auth,
+            passthrough(),
+            pac(),
+            tx,
+        )
+        .unwrap()
+        .bind(internal_addr)
+        .await
+        .unwrap();
+
+        let connect = "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n";
+        let mut client = TcpStream::connect(bound[0].1).await.unwrap();
+        client
+            .write_all(format!("{}\r\n", connect).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 407"));
+        assert!(response.contains("Proxy-Authenticate: Basic realm=\"Lab\""));
+
+        let head = format!(
+            "{}Proxy-Authorization: Basic {}\r\n\r\n",
+            connect,
+            STANDARD.encode("alice:password")
+        );
+        let mut client = TcpStream::connect(bound[0].1).await.unwrap();
+        client.write_all(head.as_bytes()).await.unwrap();
+        let (mut conn, peer) = internal.accept().await.unwrap();
+        let mut buf = vec![0; head.len()];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, head.as_bytes());
+        assert_eq!(
+            clients.resolve(peer).unwrap().user.as_deref(),
+            Some("alice")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serves_pac_without_credentials() {
+        let internal = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let users = vec![(
+            "alice".to_string(),
+            "{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=".to_string(),
+        )];
+        let auth = Arc::new(ProxyAuth::from_users(true, "Lab", users).unwrap());
+        let clients = Arc::new(ClientMap::default());
+        let listen = ListenConfig::default();
+        let bound = Frontend::from_listen(&listen, 0, clients, auth, passthrough(), pac(), tx)
+            .unwrap()
+            .bind(internal.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let addr = bound[0].1;
+        let fetch = move |path: &'static str| async move {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let request = format!("GET {} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n", path);
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let response = fetch("/proxy.pac").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Content-Type: application/x-ns-proxy-autoconfig"));
+        assert!(response.contains("return \"PROXY 127.0.0.1:8080\";"));
+        assert!(fetch("/").await.starts_with("HTTP/1.1 404"));
+    }
+
+    #[tokio::test]
+    async fn test_socks_streams_are_intercepted() {
+        let internal = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let internal_addr = internal.local_addr().unwrap();
+        let clients = Arc::new(ClientMap::default());
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let listen = ListenConfig {
+            socks_addresses: vec!["127.0.0.1:0".to_string()],
+            ..Default::default()
+        };
+        let bound = Frontend::from_listen(
+            &listen,
+            0,
+            clients.clone(),
+            no_auth(),
+            passthrough(),
+            pac(),
+            tx,
+        )
+        .unwrap()
+        .bind(internal_addr)
+        .await
+        .unwrap();
+        assert_eq!(bound[1].0, Protocol::Socks5);
+
+        async fn socks_connect(socks: SocketAddr, host: &str, port: u16) -> TcpStream {
+            let mut client = TcpStream::connect(socks).await.unwrap();
+            let mut request = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x03, host.len() as u8];
+            request.extend_from_slice(host.as_bytes());
+            request.extend_from_slice(&port.to_be_bytes());
+            client.write_all(&request).await.unwrap();
+            let mut replies = [0; 12];
+            client.read_exact(&mut replies).await.unwrap();
+            assert_eq!(replies[3], 0x00);
+            client
+        }
+
+        // TLS is tunnelled into the internal proxy with CONNECT
+        let mut client = socks_connect(bound[1].1, "example.com", 443).await;
+        client.write_all(&[0x16, 0x03, 0x01]).await.unwrap();
+        let (mut conn, peer) = internal.accept().await.unwrap();
+        let mut head = Vec::new();
+        read_head(&mut conn, &mut head).await.unwrap();
+        assert!(head.starts_with(b"CONNECT example.com:443 HTTP/1.1\r\n"));
+        conn.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+        let mut hello = [0; 3];
+        conn.read_exact(&mut hello).await.unwrap();
+        assert_eq!(hello, [0x16, 0x03, 0x01]);
+        assert_eq!(
+            clients.resolve(peer).map(|c| c.addr),
+            Some(client.local_addr().unwrap())
+        );
+
+        // Plain HTTP becomes a proxy request
+        let mut client = socks_connect(bound[1].1, "example.com", 80).await;
+        client
+            .write_all(b"GET /path?q=1 HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let (mut conn, _) = internal.accept().await.unwrap();
+        let mut head = Vec::new();
+        read_head(&mut conn, &mut head).await.unwrap();
+        assert!(head.starts_with(b"GET http://example.com/path?q=1 HTTP/1.1\r\n"));
+        conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"ok") {
+            let mut chunk = [0; 64];
+            let n = client.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "connection closed early");
+            response.extend_from_slice(&chunk[..n]);
+        }
+        assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_transparent_listener_refuses_itself() {
+        let internal = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let internal_addr = internal.local_addr().unwrap();
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let listen = ListenConfig {
+            transparent_addresses: vec!["127.0.0.1:0".to_string()],
+            ..Default::default()
+        };
+        let clients = Arc::new(ClientMap::default());
+        let bound = Frontend::from_listen(&listen, 0, clients, no_auth(), passthrough(), pac(), tx)
+            .unwrap()
+            .bind(internal_addr)
+            .await
+            .unwrap();
+        assert_eq!(bound[1].0, Protocol::Transparent);
+
+        // Without a redirect the original destination is the listener itself
+        let mut client = TcpStream::connect(bound[1].1).await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+        let accepted =
+            tokio::time::timeout(std::time::Duration::from_millis(100), internal.accept()).await;
+        assert!(accepted.is_err());
+    }
+}
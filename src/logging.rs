@@ -0,0 +1,141 @@
+use crate::ca::CaStore;
+use crate::config::{Config, CookieValueLogging, RequestLogLevel};
+use hmac::{Hmac, Mac};
+use hyper::Uri;
+use sha2::Sha256;
+
+/// Marker stored in place of redacted cookie values.
+const REDACTED: &str = "<redacted>";
+
+/// Applies the logging privacy settings to data before it becomes a `LogEvent`.
+#[derive(Clone)]
+pub struct LogPolicy {
+    requests: RequestLogLevel,
+    cookie_values: CookieValueLogging,
+    /// Per-install key for hashed cookie values.
+    hash_key: Vec<u8>,
+}
+
+impl LogPolicy {
+    /// Creates a new LogPolicy.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg` - Application configuration.
+    /// * `store` - CA directory holding the key cookie values are hashed with.
+    pub fn new(cfg: &Config, store: &CaStore) -> anyhow::Result<Self> {
+        let hash_key = match cfg.logging.cookie_values {
+            CookieValueLogging::Hash => store.log_key()?,
+            _ => Vec::new(),
+        };
+        Ok(Self {
+            requests: cfg.logging.requests,
+            cookie_values: cfg.logging.cookie_values,
+            hash_key,
+        })
+    }
+
+    /// Returns the path to log for a request, or None if requests are not logged.
+    ///
+    /// The path is empty when only domains are logged.
+    pub fn request_path(&self, uri: &Uri) -> Option<String> {
+        match self.requests {
+            RequestLogLevel::Off => None,
+            RequestLogLevel::Domains => Some(String::new()),
+            RequestLogLevel::Paths => Some(uri.path().to_string()),
+            RequestLogLevel::Full => Some(
+                uri.path_and_query()
+                    .map(|pq| pq.as_str())
+                    .unwrap_or_else(|| uri.path())
+                    .to_string(),
+            ),
+        }
+    }
+
+    /// Rewrites a `Cookie` pair or `Set-Cookie` value for logging.
+    ///
+    /// Attributes after the first `;` are kept; only the value is hashed or
+    /// redacted.
+    pub fn cookie(&self, raw: &str) -> String {
+        let (pair, attributes) = match raw.split_once(';') {
+            Some((pair, rest)) => (pair, Some(rest)),
+            None => (raw, None),
+        };
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let name = name.trim();
+        let logged = match self.cookie_values {
+            CookieValueLogging::Full => return raw.to_string(),
+            CookieValueLogging::Names => return name.to_string(),
+            CookieValueLogging::Redact => format!("{}={}", name, REDACTED),
+            CookieValueLogging::Hash => {
+                format!("{}={}", name, hash_value(&self.hash_key, value.trim()))
+            }
+        };
+        match attributes {
+            Some(rest) => format!("{};{}", logged, rest),
+            None => logged,
+        }
+    }
+}
+
+/// Truncated HMAC-SHA256 of a cookie value, enough to tell values apart.
+///
+/// Keyed so that logged hashes cannot be matched against guessed values
+/// without the install's key.
+fn hash_value(key: &[u8], value: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(value.as_bytes());
+    let digest = mac.finalize().into_bytes();
+    let hex: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("hmac:{}", hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(requests: RequestLogLevel, cookie_values: CookieValueLogging) -> LogPolicy {
+        LogPolicy {
+            requests,
+            cookie_values,
+            hash_key: b"test key".to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_request_levels() {
+        let uri: Uri = "https://example.com/search?q=secret".parse().unwrap();
+        let path = |level| policy(level, CookieValueLogging::Hash).request_path(&uri);
+        assert_eq!(path(RequestLogLevel::Off), None);
+        assert_eq!(path(RequestLogLevel::Domains).unwrap(), "");
+        assert_eq!(path(RequestLogLevel::Paths).unwrap(), "/search");
+        assert_eq!(path(RequestLogLevel::Full).unwrap(), "/search?q=secret");
+    }
+
+    #[test]
+    fn test_cookie_values() {
+        let raw = "sid=token123; Path=/; Secure";
+        let cookie = |mode| policy(RequestLogLevel::Paths, mode).cookie(raw);
+        assert_eq!(cookie(CookieValueLogging::Full), raw);
+        assert_eq!(cookie(CookieValueLogging::Names), "sid");
+        assert_eq!(
+            cookie(CookieValueLogging::Redact),
+            "sid=<redacted>; Path=/; Secure"
+        );
+
+        let hashed = cookie(CookieValueLogging::Hash);
+        assert!(hashed.starts_with("sid=hmac:"));
+        assert!(hashed.ends_with("; Path=/; Secure"));
+        assert!(!hashed.contains("token123"));
+        // Equal values hash alike, different values differ
+        let hash = policy(RequestLogLevel::Paths, CookieValueLogging::Hash);
+        assert_eq!(hash.cookie("sid=token123"), hash.cookie("sid=token123"));
+        assert_ne!(hash.cookie("sid=other"), hash.cookie("sid=token123"));
+        // Another install's key gives other hashes
+        let other = LogPolicy {
+            hash_key: b"other key".to_vec(),
+            ..hash.clone()
+        };
+        assert_ne!(other.cookie("sid=token123"), hash.cookie("sid=token123"));
+    }
+}
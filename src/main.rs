@@ -5,23 +5,56 @@ use anyhow::Result;
 use clap::Parser;
 use log::info;
 
+mod auth;
 mod blocker;
+mod ca;
+mod cache;
+mod catalogue;
 mod cli;
 mod config;
 mod cookie;
 mod db;
+mod ephemeral;
+mod headers;
+mod jar;
+mod learned;
+mod listener;
+mod logging;
+mod pac;
+mod passthrough;
+mod pattern;
 mod proxy;
+mod query;
 mod randomizer;
+mod redirect;
+mod referer;
+mod sniff;
+mod socks;
+mod upstream;
 
 // Export modules for examples and tests
 pub use config::FingerprintConfig;
 pub use randomizer::Randomizer;
 
+use crate::auth::ProxyAuth;
+use crate::ca::CaStore;
+use crate::cache::CacheDefence;
 use crate::cli::{handle_management_cmd, Cli};
 use crate::config::load;
 use crate::cookie::CookieHandler;
 use crate::db::spawn_logger;
+use crate::ephemeral::EphemeralSessions;
+use crate::headers::HeaderRewriter;
+use crate::jar::CookieJar;
+use crate::learned::LearnedHosts;
+use crate::listener::{ClientMap, Frontend};
+use crate::logging::LogPolicy;
+use crate::passthrough::Passthrough;
 use crate::proxy::{run_proxy, ProxyState};
+use crate::query::QueryStripper;
+use crate::redirect::RedirectUnwrapper;
+use crate::referer::RefererFilter;
+use crate::upstream::Upstream;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -36,10 +69,14 @@ async fn main() -> Result<()> {
 
     // If a management subcommand was provided, handle it and exit
     if let Some(command) = cli.command {
-        handle_management_cmd(command, cfg.db_path.clone()).await?;
+        handle_management_cmd(command, &cfg).await?;
         return Ok(());
     }
 
+    // Load the interception CA; a key other users can read stops startup
+    let ca_store = CaStore::new(&cfg)?;
+    let ca = ca_store.load_or_generate()?.authority()?;
+
     // Set up async logger channel
     let (db_tx, db_rx) = tokio::sync::mpsc::channel(1024);
     tokio::spawn(spawn_logger(cfg.db_path.clone(), db_rx));
@@ -49,13 +86,59 @@ async fn main() -> Result<()> {
     let randomizer = Arc::new(tokio::sync::Mutex::new(randomizer::Randomizer::new(
         &cfg.fingerprint,
     )));
-    let cookie_handler = Arc::new(CookieHandler::new(cfg.clone()));
+    let cookie_handler = Arc::new(CookieHandler::new(cfg.clone())?);
+    let purged = db.lock().await.jar_purge_session().await?;
+    if purged > 0 {
+        info!("Purged {} session cookies from the cookie jar", purged);
+    }
+    let cookie_jar = Arc::new(CookieJar::new(&cfg, db.clone()));
+    let ephemeral = Arc::new(EphemeralSessions::new(&cfg));
+    if !cfg.cookies.ephemeral.domains.is_empty() {
+        tokio::spawn(ephemeral.clone().run_sweeper());
+    }
     let blocker = Arc::new(blocker::Blocker::new(&cfg, db.clone(), db_tx.clone()));
+    let cache_defence = Arc::new(CacheDefence::new(&cfg));
+    let referer_filter = Arc::new(RefererFilter::new(&cfg));
+    let query_stripper = Arc::new(QueryStripper::new(&cfg)?);
+    let upstream = Arc::new(Upstream::new(&cfg)?);
+    // Refuse to start rather than silently connecting directly
+    upstream.check().await?;
+    for (name, proxy) in upstream.proxies() {
+        info!("Upstream proxy {}: {}", name, proxy);
+    }
+    let redirect_unwrapper = Arc::new(RedirectUnwrapper::new(
+        &cfg,
+        query_stripper.clone(),
+        upstream.clone(),
+    )?);
+    let header_rewriter = Arc::new(HeaderRewriter::new(&cfg)?);
+    let log_policy = Arc::new(LogPolicy::new(&cfg, &ca_store)?);
+    let clients = Arc::new(ClientMap::default());
+    let auth = Arc::new(ProxyAuth::new(&cfg)?);
+    let learned = Arc::new(LearnedHosts::new(&cfg, db.clone()));
+    let passthrough = Arc::new(Passthrough::new(
+        &cfg,
+        blocker.clone(),
+        upstream.clone(),
+        learned,
+        db_tx.clone(),
+    )?);
+    let frontend = Frontend::new(&cfg, clients.clone(), auth, passthrough, db_tx.clone())?;
 
     let state = ProxyState {
         randomizer,
         cookie_handler,
+        cookie_jar,
+        ephemeral,
         blocker,
+        cache_defence,
+        referer_filter,
+        query_stripper,
+        redirect_unwrapper,
+        header_rewriter,
+        log_policy,
+        clients,
+        upstream,
         db_logger: db_tx,
     };
 
@@ -84,9 +167,8 @@ async fn main() -> Result<()> {
         });
     }
 
-    let port = cfg.port.unwrap_or(8080);
-    info!("Starting proxy on 127.0.0.1:{}", port);
-    run_proxy(state, port).await?;
+    info!("Starting proxy on {:?}", frontend.addresses());
+    run_proxy(state, frontend, ca).await?;
 
     Ok(())
 }
@@ -0,0 +1,322 @@
+use crate::config::{Config, PacConfig, PacProfile};
+use anyhow::{bail, Context};
+use ipnet::IpNet;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+
+/// MIME type browsers expect for PAC files.
+pub const PAC_CONTENT_TYPE: &str = "application/x-ns-proxy-autoconfig";
+
+/// Networks sent direct with `pac.bypass_local`.
+const LOCAL_NETWORKS: [&str; 9] = [
+    "127.0.0.0/8",
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "169.254.0.0/16",
+    "100.64.0.0/10",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+];
+
+/// Bypass lists and proxy directive of one PAC variant.
+struct Profile {
+    /// Configured `host:port`, or None to use the listener address.
+    proxy: Option<String>,
+    socks: bool,
+    hosts: Vec<String>,
+    networks: Vec<IpNet>,
+}
+
+/// Generates proxy auto-config files from the `pac` settings.
+///
+/// Networks only match hosts given as IP literals, so the generated PAC never
+/// makes the browser resolve names itself.
+pub struct PacGenerator {
+    enabled: bool,
+    bypass_local: bool,
+    http: SocketAddr,
+    socks: Option<SocketAddr>,
+    default: Profile,
+    profiles: BTreeMap<String, Profile>,
+}
+
+impl PacGenerator {
+    /// Creates a new PacGenerator.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg` - Application configuration.
+    pub fn new(cfg: &Config) -> anyhow::Result<Self> {
+        let parse = |a: &String| {
+            a.parse::<SocketAddr>()
+                .with_context(|| format!("invalid listen address '{}'", a))
+        };
+        let http = match cfg.listen.addresses.first() {
+            Some(addr) => parse(addr)?,
+            None => SocketAddr::from(([127, 0, 0, 1], cfg.port.unwrap_or(8080))),
+        };
+        let socks = cfg.listen.socks_addresses.first().map(parse).transpose()?;
+        Self::from_pac(&cfg.pac, http, socks)
+    }
+
+    /// Creates a PacGenerator for the given HTTP and SOCKS5 listen addresses.
+    pub fn from_pac(
+        pac: &PacConfig,
+        http: SocketAddr,
+        socks: Option<SocketAddr>,
+    ) -> anyhow::Result<Self> {
+        let global = PacProfile {
+            proxy: pac.proxy.clone(),
+            socks: false,
+            bypass: Vec::new(),
+        };
+        let default = compile(&global, &pac.bypass)?;
+        let profiles = pac
+            .profiles
+            .iter()
+            .map(|(name, profile)| {
+                if !is_pac_token(name) {
+                    bail!("invalid PAC profile name '{}'", name);
+                }
+                if profile.socks && socks.is_none() {
+                    bail!(
+                        "PAC profile '{}' uses SOCKS5 but listen.socks_addresses is empty",
+                        name
+                    );
+                }
+                let mut profile = profile.clone();
+                profile.proxy = profile.proxy.or_else(|| pac.proxy.clone());
+                Ok((name.clone(), compile(&profile, &pac.bypass)?))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            enabled: pac.enabled,
+            bypass_local: pac.bypass_local,
+            http,
+            socks,
+            default,
+            profiles,
+        })
+    }
+
+    /// Whether the listeners serve the PAC file.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns the PAC file for a request path, or None for unknown paths.
+    ///
+    /// `/proxy.pac` and `/wpad.dat` serve the default variant and
+    /// `/proxy-<name>.pac` a profile.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Request path; a query string is ignored.
+    /// * `local` - Address the client reached the proxy on.
+    pub fn serve(&self, path: &str, local: IpAddr) -> Option<String> {
+        let path = path.split('?').next().unwrap_or_default();
+        let profile = match path {
+            "/proxy.pac" | "/wpad.dat" => None,
+            _ => Some(path.strip_prefix("/proxy-")?.strip_suffix(".pac")?),
+        };
+        self.render(profile, Some(local)).ok()
+    }
+
+    /// Renders the PAC file of a profile, or of the default variant.
+    ///
+    /// Without a configured proxy address the listener address is used, with
+    /// `local` standing in for an unspecified listen address.
+    pub fn render(&self, profile: Option<&str>, local: Option<IpAddr>) -> anyhow::Result<String> {
+        let (name, profile) = match profile {
+            None => ("default", &self.default),
+            Some(name) => (
+                name,
+                self.profiles
+                    .get(name)
+                    .with_context(|| format!("unknown PAC profile '{}'", name))?,
+            ),
+        };
+        let proxy = match &profile.proxy {
+            Some(proxy) => proxy.clone(),
+            None => {
+                let listener = match (profile.socks, self.socks) {
+                    (true, Some(socks)) => socks,
+                    _ => self.http,
+                };
+                let ip = match (listener.ip().is_unspecified(), local) {
+                    (false, _) => listener.ip(),
+                    (true, Some(local)) => local.to_canonical(),
+                    (true, None) => bail!(
+                        "listen address {} is unspecified: set pac.proxy to the address clients should use",
+                        listener
+                    ),
+                };
+                SocketAddr::new(ip, listener.port()).to_string()
+            }
+        };
+        let directive = if profile.socks {
+            format!("SOCKS5 {}", proxy)
+        } else {
+            format!("PROXY {}", proxy)
+        };
+
+        let mut networks: Vec<IpNet> = profile.networks.clone();
+        if self.bypass_local {
+            networks.extend(LOCAL_NETWORKS.iter().map(|n| n.parse::<IpNet>().unwrap()));
+        }
+        let (v4, v6): (Vec<IpNet>, Vec<IpNet>) =
+            networks.into_iter().partition(|n| n.addr().is_ipv4());
+
+        let mut pac = format!("// BlankTrace proxy auto-config ({})\n", name);
+        pac.push_str("function FindProxyForURL(url, host) {\n");
+        pac.push_str("    host = host.toLowerCase();\n");
+        if self.bypass_local {
+            pac.push_str(
+                "    if (isPlainHostName(host) || host == \"localhost\" || shExpMatch(host, \"*.localhost\"))\n        return \"DIRECT\";\n",
+            );
+        }
+        if !profile.hosts.is_empty() {
+            let tests: Vec<String> = profile
+                .hosts
+                .iter()
+                .map(|h| format!("shExpMatch(host, \"{}\")", h))
+                .collect();
+            let _ = writeln!(
+                pac,
+                "    if ({})\n        return \"DIRECT\";",
+                tests.join(" ||\n        ")
+            );
+        }
+        if !v4.is_empty() {
+            let tests: Vec<String> = v4
+                .iter()
+                .map(|n| format!("isInNet(host, \"{}\", \"{}\")", n.network(), n.netmask()))
+                .collect();
+            let _ = writeln!(
+                pac,
+                "    if (/^\\d+\\.\\d+\\.\\d+\\.\\d+$/.test(host) && (\n        {}))\n        return \"DIRECT\";",
+                tests.join(" ||\n        ")
+            );
+        }
+        if !v6.is_empty() {
+            let tests: Vec<String> = v6
+                .iter()
+                .map(|n| format!("isInNetEx(host, \"{}\")", n.trunc()))
+                .collect();
+            let _ = writeln!(
+                pac,
+                "    if (host.indexOf(\":\") >= 0 && typeof isInNetEx == \"function\" && (\n        {}))\n        return \"DIRECT\";",
+                tests.join(" ||\n        ")
+            );
+        }
+        let _ = writeln!(pac, "    return \"{}\";\n}}", directive);
+        Ok(pac)
+    }
+}
+
+/// Validates a profile and splits its bypass entries into host globs and
+/// networks.
+fn compile(profile: &PacProfile, global: &[String]) -> anyhow::Result<Profile> {
+    if let Some(proxy) = &profile.proxy {
+        if !is_pac_token(proxy) || !proxy.contains(':') {
+            bail!("invalid PAC proxy address '{}': use host:port", proxy);
+        }
+    }
+    let mut hosts = Vec::new();
+    let mut networks = Vec::new();
+    for entry in global.iter().chain(&profile.bypass) {
+        if let Ok(net) = entry.parse::<IpNet>() {
+            networks.push(net);
+        } else if let Ok(ip) = entry.parse::<IpAddr>() {
+            networks.push(IpNet::from(ip));
+        } else if is_pac_token(entry) {
+            hosts.push(entry.to_ascii_lowercase());
+        } else {
+            bail!("invalid PAC bypass entry '{}'", entry);
+        }
+    }
+    Ok(Profile {
+        proxy: profile.proxy.clone(),
+        socks: profile.socks,
+        hosts,
+        networks,
+    })
+}
+
+/// Host names, globs and addresses are written into JavaScript strings, so
+/// only allow characters that cannot escape them.
+fn is_pac_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || ".-_*?:[]".contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator(yaml: &str) -> anyhow::Result<PacGenerator> {
+        let pac: PacConfig = serde_yaml::from_str(yaml).unwrap();
+        PacGenerator::from_pac(
+            &pac,
+            "0.0.0.0:8080".parse().unwrap(),
+            Some("127.0.0.1:1080".parse().unwrap()),
+        )
+    }
+
+    #[test]
+    fn test_render_default_and_profiles() {
+        let pac = generator(
+            r#"
+bypass: ["*.corp.example", "10.20.0.0/16", "fd00::/8"]
+profiles:
+  tor:
+    socks: true
+  lab:
+    proxy: "proxy.lab:3128"
+    bypass: ["*.lab.example"]
+"#,
+        )
+        .unwrap();
+
+        let local: IpAddr = "192.168.10.1".parse().unwrap();
+        let default = pac.serve("/proxy.pac", local).unwrap();
+        assert!(default.contains("isPlainHostName(host)"));
+        assert!(default.contains("shExpMatch(host, \"*.corp.example\")"));
+        assert!(default.contains("isInNet(host, \"10.20.0.0\", \"255.255.0.0\")"));
+        assert!(default.contains("isInNet(host, \"192.168.0.0\", \"255.255.0.0\")"));
+        assert!(default.contains("isInNetEx(host, \"fd00::/8\")"));
+        assert!(!default.contains("dnsResolve"));
+        assert!(default.ends_with("    return \"PROXY 192.168.10.1:8080\";\n}\n"));
+        assert_eq!(pac.serve("/wpad.dat?x=1", local), Some(default));
+
+        let tor = pac.serve("/proxy-tor.pac", local).unwrap();
+        assert!(tor.contains("return \"SOCKS5 127.0.0.1:1080\";"));
+
+        let lab = pac.render(Some("lab"), None).unwrap();
+        assert!(lab.contains("shExpMatch(host, \"*.lab.example\")"));
+        assert!(lab.contains("shExpMatch(host, \"*.corp.example\")"));
+        assert!(lab.contains("return \"PROXY proxy.lab:3128\";"));
+
+        assert_eq!(pac.serve("/proxy-missing.pac", local), None);
+        assert_eq!(pac.serve("/index.html", local), None);
+        // An unspecified listen address needs pac.proxy outside a request
+        assert!(pac.render(None, None).is_err());
+    }
+
+    #[test]
+    fn test_invalid_entries_rejected() {
+        assert!(generator("bypass: [\"evil\\\"); alert(1); //\"]").is_err());
+        assert!(generator("proxy: \"proxy.lab\"").is_err());
+        let pac: PacConfig = serde_yaml::from_str("profiles: {tor: {socks: true}}").unwrap();
+        assert!(PacGenerator::from_pac(&pac, "127.0.0.1:8080".parse().unwrap(), None).is_err());
+
+        let pac = generator("bypass_local: false").unwrap();
+        let rendered = pac.render(None, Some("10.0.0.1".parse().unwrap())).unwrap();
+        assert!(!rendered.contains("isPlainHostName"));
+        assert!(!rendered.contains("isInNet"));
+    }
+}
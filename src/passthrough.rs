@@ -0,0 +1,433 @@
+use crate::blocker::Blocker;
+use crate::config::{Config, RequestLogLevel};
+use crate::db::{LogEvent, TunnelRecord};
+use crate::learned::LearnedHosts;
+use crate::listener::ClientInfo;
+use crate::pattern::glob;
+use crate::upstream::Upstream;
+use log::{debug, info};
+use regex::Regex;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{copy_bidirectional, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::Sender;
+
+/// Why a stream is tunnelled rather than intercepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelReason {
+    /// The destination is listed in `no_intercept`.
+    NoIntercept,
+    /// The destination is on the learned passthrough list.
+    Learned,
+    /// The stream carries neither TLS nor HTTP.
+    Protocol,
+    /// The destination is blocked, so the stream was never looked at.
+    Blocked,
+}
+
+impl TunnelReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TunnelReason::NoIntercept => "no_intercept",
+            TunnelReason::Learned => "learned",
+            TunnelReason::Protocol => "protocol",
+            TunnelReason::Blocked => "blocked",
+        }
+    }
+}
+
+/// Tunnels streams the proxy does not intercept straight to their
+/// destination: hosts listed in `no_intercept` or learned from failed
+/// handshakes, whose TLS is never terminated, and non-HTTP protocols reached
+/// through the SOCKS listener.
+///
+/// Blocked hosts are refused outright; everything else follows the route the
+/// upstream rules pick for the host. Tunnels are logged as opaque
+/// connections with their byte counts.
+pub struct Passthrough {
+    blocker: Arc<Blocker>,
+    upstream: Arc<Upstream>,
+    no_intercept: Vec<Regex>,
+    learned: Arc<LearnedHosts>,
+    log_tunnels: bool,
+    db_logger: Sender<LogEvent>,
+}
+
+impl Passthrough {
+    /// Creates a new Passthrough.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg` - Application configuration.
+    /// * `blocker` - Decides which destinations are refused.
+    /// * `upstream` - Routes the tunnels.
+    /// * `learned` - Hosts learned from failed handshakes.
+    /// * `db_logger` - Channel for tunnel events.
+    pub fn new(
+        cfg: &Config,
+        blocker: Arc<Blocker>,
+        upstream: Arc<Upstream>,
+        learned: Arc<LearnedHosts>,
+        db_logger: Sender<LogEvent>,
+    ) -> anyhow::Result<Self> {
+        let mut passthrough =
+            Self::from_hosts(&cfg.no_intercept, blocker, upstream, learned, db_logger)?;
+        passthrough.log_tunnels = cfg.logging.requests != RequestLogLevel::Off;
+        Ok(passthrough)
+    }
+
+    /// Creates a Passthrough from `no_intercept` host globs alone.
+    pub fn from_hosts(
+        no_intercept: &[String],
+        blocker: Arc<Blocker>,
+        upstream: Arc<Upstream>,
+        learned: Arc<LearnedHosts>,
+        db_logger: Sender<LogEvent>,
+    ) -> anyhow::Result<Self> {
+        let no_intercept = no_intercept
+            .iter()
+            .map(|h| glob(h))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            blocker,
+            upstream,
+            no_intercept,
+            learned,
+            log_tunnels: true,
+            db_logger,
+        })
+    }
+
+    /// Why connections to `host` must be tunnelled without interception, or
+    /// None when they are intercepted.
+    pub async fn no_intercept(&self, host: &str) -> Option<TunnelReason> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if self.no_intercept.iter().any(|h| h.is_match(&host)) {
+            Some(TunnelReason::NoIntercept)
+        } else if self.learned.contains(&host).await {
+            Some(TunnelReason::Learned)
+        } else {
+            None
+        }
+    }
+
+    /// Records a client that failed the TLS handshake with the proxy for
+    /// `host`.
+    pub async fn handshake_failed(&self, host: &str) {
+        self.learned
+            .handshake_failed(&host.trim_end_matches('.').to_ascii_lowercase())
+            .await;
+    }
+
+    /// Checks the destination a client asked for against the blocker, before
+    /// the client is told that the connection is established. Returns true,
+    /// having counted the tracker hit and recorded a blocked tunnel, when the
+    /// connection is to be refused.
+    ///
+    /// Connections that are let through are not counted here: the handler or
+    /// `tunnel` counts them.
+    pub async fn refuse(
+        &self,
+        client: &ClientInfo,
+        host: &str,
+        port: u16,
+        reason: TunnelReason,
+    ) -> bool {
+        if !self.blocker.is_blocked(host).await {
+            return false;
+        }
+        self.blocker.check_and_track(host).await;
+        info!(
+            "Blocked connection from {} to {}:{}",
+            client.addr, host, port
+        );
+        let mut record = self.record(client, host, port, reason);
+        record.blocked = true;
+        self.log(record).await;
+        true
+    }
+
+    /// Connects a client stream to `host:port` and copies data both ways
+    /// until either side closes.
+    ///
+    /// `host` is expected to have passed `refuse`. The TLS server name, or
+    /// else `host`, is checked against the blocker here, counting the tracker
+    /// hit; the connection is closed unanswered when it is blocked.
+    pub async fn tunnel(
+        &self,
+        inbound: TcpStream,
+        client: &ClientInfo,
+        host: &str,
+        port: u16,
+        server_name: Option<&str>,
+        reason: TunnelReason,
+    ) -> io::Result<()> {
+        let started = Instant::now();
+        let name = server_name.unwrap_or(host);
+        let mut record = self.record(client, name, port, reason);
+        record.blocked = self.blocker.check_and_track(name).await;
+        if record.blocked {
+            info!(
+                "Blocked connection from {} to {}:{}",
+                client.addr, name, port
+            );
+            self.log(record).await;
+            return Ok(());
+        }
+
+        let route = self.upstream.route(name, name);
+        debug!("Passing through {}:{} via {}", name, port, route.name);
+        let outbound = route.connect(host, port).await?;
+        record.route = Some(route.name);
+        let mut inbound = Counted::new(inbound);
+        let mut outbound = Counted::new(outbound);
+        let result = copy_bidirectional(&mut inbound, &mut outbound).await;
+        record.bytes_sent = inbound.read;
+        record.bytes_received = outbound.read;
+        record.duration_ms = started.elapsed().as_millis() as u64;
+        debug!(
+            "Tunnel from {} to {}:{} closed ({} bytes sent, {} received)",
+            client.addr, name, port, record.bytes_sent, record.bytes_received
+        );
+        self.log(record).await;
+        result.map(|_| ())
+    }
+
+    fn record(
+        &self,
+        client: &ClientInfo,
+        domain: &str,
+        port: u16,
+        reason: TunnelReason,
+    ) -> TunnelRecord {
+        TunnelRecord {
+            domain: domain.to_string(),
+            port,
+            client_ip: client.addr.ip().to_canonical().to_string(),
+            client_port: Some(client.addr.port()),
+            user: client.user.clone(),
+            reason: reason.as_str().to_string(),
+            ..Default::default()
+        }
+    }
+
+    async fn log(&self, record: TunnelRecord) {
+        if self.log_tunnels {
+            let _ = self.db_logger.send(LogEvent::Tunnel(record)).await;
+        }
+    }
+}
+
+/// Stream wrapper counting the bytes read from it, which stay available
+/// when a copy ends with an error such as a reset.
+struct Counted<S> {
+    inner: S,
+    read: u64,
+}
+
+impl<S> Counted<S> {
+    fn new(inner: S) -> Self {
+        Self { inner, read: 0 }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.read += (buf.filled().len() - before) as u64;
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BlockingConfig, TlsFallbackConfig};
+    use crate::db::Database;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc::Receiver;
+
+    /// Passthrough blocking hosts that contain "tracker", with the tunnel
+    /// events it logs.
+    fn passthrough() -> (Arc<Passthrough>, Receiver<LogEvent>) {
+        let db = Arc::new(tokio::sync::Mutex::new(Database::new(":memory:").unwrap()));
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        tokio::spawn(async move { while rx.recv().await.is_some() {} });
+        let blocking = BlockingConfig {
+            auto_block: true,
+            auto_block_threshold: 1,
+            block_patterns: vec!["tracker".to_string()],
+        };
+        let blocker = Arc::new(Blocker::from_blocking(&blocking, db.clone(), tx));
+        let fallback = TlsFallbackConfig {
+            auto_apply: true,
+            ..Default::default()
+        };
+        let learned = Arc::new(LearnedHosts::from_fallback(&fallback, db));
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let hosts = vec!["*.bank.example".to_string()];
+        let upstream = Arc::new(Upstream::default());
+        let passthrough = Passthrough::from_hosts(&hosts, blocker, upstream, learned, tx).unwrap();
+        (Arc::new(passthrough), rx)
+    }
+
+    /// Runs `tunnel` for one connection and returns the client side.
+    async fn tunnelled(
+        passthrough: Arc<Passthrough>,
+        host: &str,
+        port: u16,
+        server_name: Option<&str>,
+    ) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let host = host.to_string();
+        let server_name = server_name.map(str::to_string);
+        tokio::spawn(async move {
+            let (inbound, addr) = listener.accept().await.unwrap();
+            let client = ClientInfo {
+                addr,
+                user: None,
+                via_connect: true,
+                destination: None,
+            };
+            let _ = passthrough
+                .tunnel(
+                    inbound,
+                    &client,
+                    &host,
+                    port,
+                    server_name.as_deref(),
+                    TunnelReason::NoIntercept,
+                )
+                .await;
+        });
+        TcpStream::connect(addr).await.unwrap()
+    }
+
+    async fn tunnel_record(rx: &mut Receiver<LogEvent>) -> TunnelRecord {
+        match rx.recv().await {
+            Some(LogEvent::Tunnel(record)) => record,
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_no_intercept_hosts() {
+        let (passthrough, _rx) = passthrough();
+        // Learned after the default three failures
+        for host in ["pinned.example", "Pinned.Example.", "pinned.example"] {
+            passthrough.handshake_failed(host).await;
+        }
+        let reason = |host: &'static str| {
+            let passthrough = passthrough.clone();
+            async move { passthrough.no_intercept(host).await }
+        };
+        assert_eq!(
+            reason("www.bank.example").await,
+            Some(TunnelReason::NoIntercept)
+        );
+        assert_eq!(
+            reason("WWW.Bank.Example.").await,
+            Some(TunnelReason::NoIntercept)
+        );
+        assert_eq!(reason("pinned.example").await, Some(TunnelReason::Learned));
+        assert_eq!(reason("bank.example").await, None);
+        assert_eq!(reason("news.example").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_and_block() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut conn, _) = echo.accept().await.unwrap();
+            let mut buf = [0; 4];
+            conn.read_exact(&mut buf).await.unwrap();
+            conn.write_all(&buf).await.unwrap();
+            conn.write_all(b"!").await.unwrap();
+        });
+
+        let (passthrough, mut rx) = passthrough();
+        let mut client = tunnelled(
+            passthrough.clone(),
+            "127.0.0.1",
+            port,
+            Some("www.bank.example"),
+        )
+        .await;
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"ping!");
+        drop(client);
+        let record = tunnel_record(&mut rx).await;
+        assert_eq!(record.domain, "www.bank.example");
+        assert_eq!(record.reason, "no_intercept");
+        assert_eq!(record.route.as_deref(), Some("direct"));
+        assert!(!record.blocked);
+        assert_eq!((record.bytes_sent, record.bytes_received), (4, 5));
+
+        // Blocked by the server name
+        let mut client = tunnelled(
+            passthrough.clone(),
+            "127.0.0.1",
+            port,
+            Some("tracker.example"),
+        )
+        .await;
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+        let record = tunnel_record(&mut rx).await;
+        assert!(record.blocked);
+        assert_eq!(record.domain, "tracker.example");
+        assert_eq!(record.route, None);
+
+        // Refused by the requested host before the client is answered
+        let client = ClientInfo {
+            addr: "127.0.0.1:40000".parse().unwrap(),
+            user: None,
+            via_connect: true,
+            destination: None,
+        };
+        let reason = TunnelReason::Blocked;
+        assert!(!passthrough.refuse(&client, "127.0.0.1", port, reason).await);
+        assert!(
+            passthrough
+                .refuse(&client, "tracker.example", port, reason)
+                .await
+        );
+        let record = tunnel_record(&mut rx).await;
+        assert!(record.blocked);
+        assert_eq!(record.domain, "tracker.example");
+        assert_eq!(record.reason, "blocked");
+    }
+}
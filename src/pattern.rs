@@ -0,0 +1,106 @@
+use anyhow::Context;
+use publicsuffix::{List, Psl};
+use regex::Regex;
+use std::sync::LazyLock;
+
+/// Compiles a case-insensitive glob where `*` matches any run of characters.
+pub fn glob(pattern: &str) -> anyhow::Result<Regex> {
+    let escaped: Vec<String> = pattern.split('*').map(regex::escape).collect();
+    Regex::new(&format!("(?i)^{}$", escaped.join(".*")))
+        .with_context(|| format!("invalid pattern '{}'", pattern))
+}
+
+/// Checks whether `host` is `domain` or one of its subdomains.
+pub fn domain_matches(host: &str, domain: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    let domain = domain.trim_start_matches('.');
+    host.eq_ignore_ascii_case(domain)
+        || (host.len() > domain.len()
+            && host.as_bytes()[host.len() - domain.len() - 1] == b'.'
+            && host[host.len() - domain.len()..].eq_ignore_ascii_case(domain))
+}
+
+/// The bundled public suffix list.
+const PUBLIC_SUFFIX_LIST: &str = include_str!("../public_suffix_list.dat");
+
+/// The parsed public suffix list, including the private section (`github.io`).
+static PUBLIC_SUFFIXES: LazyLock<List> = LazyLock::new(|| {
+    PUBLIC_SUFFIX_LIST
+        .parse()
+        .expect("bundled public suffix list")
+});
+
+/// Checks whether `domain` is listed in the public suffix list (`co.uk`, `github.io`).
+pub fn is_public_suffix(domain: &str) -> bool {
+    let domain = domain.trim_matches('.').to_ascii_lowercase();
+    PUBLIC_SUFFIXES
+        .suffix(domain.as_bytes())
+        .is_some_and(|s| s.is_known() && s.as_bytes() == domain.as_bytes())
+}
+
+/// Returns the registrable domain (eTLD+1) of a host.
+///
+/// Hosts that are themselves public suffixes, or have no known suffix, and IP
+/// addresses are returned unchanged.
+pub fn registrable_domain(host: &str) -> String {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if host
+        .trim_matches(|c| c == '[' || c == ']')
+        .parse::<std::net::IpAddr>()
+        .is_ok()
+    {
+        return host;
+    }
+    match PUBLIC_SUFFIXES.domain(host.as_bytes()) {
+        Some(d) => String::from_utf8_lossy(d.as_bytes()).into_owned(),
+        None => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob() {
+        let re = glob("utm_*").unwrap();
+        assert!(re.is_match("utm_source"));
+        assert!(re.is_match("UTM_medium"));
+        assert!(!re.is_match("xutm_source"));
+        assert!(glob("*.example.com").unwrap().is_match("a.b.example.com"));
+        assert!(glob("a.b").unwrap().is_match("a.b"));
+        assert!(!glob("a.b").unwrap().is_match("axb"));
+    }
+
+    #[test]
+    fn test_domain_matches() {
+        assert!(domain_matches("example.com", "example.com"));
+        assert!(domain_matches("www.example.com", "example.com"));
+        assert!(domain_matches("WWW.Example.com", ".example.com"));
+        assert!(!domain_matches("badexample.com", "example.com"));
+        assert!(!domain_matches("com", "example.com"));
+    }
+
+    #[test]
+    fn test_registrable_domain() {
+        assert_eq!(registrable_domain("www.example.com"), "example.com");
+        assert_eq!(registrable_domain("a.b.example.co.uk"), "example.co.uk");
+        assert_eq!(registrable_domain("Example.COM."), "example.com");
+        assert_eq!(registrable_domain("localhost"), "localhost");
+        assert_eq!(registrable_domain("192.168.1.10"), "192.168.1.10");
+        assert_eq!(registrable_domain("alice.github.io"), "alice.github.io");
+        assert_eq!(registrable_domain("www.example.co.jp"), "example.co.jp");
+        assert_eq!(registrable_domain("github.io"), "github.io");
+    }
+
+    #[test]
+    fn test_is_public_suffix() {
+        assert!(is_public_suffix("com"));
+        assert!(is_public_suffix("co.jp"));
+        assert!(is_public_suffix("github.io"));
+        assert!(is_public_suffix(".GitHub.io"));
+        assert!(!is_public_suffix("example.co.jp"));
+        assert!(!is_public_suffix("alice.github.io"));
+        assert!(!is_public_suffix("localhost"));
+    }
+}
@@ -1,13 +1,45 @@
-use crate::{blocker::Blocker, cookie::CookieHandler, db::LogEvent, randomizer::Randomizer};
+use crate::{
+    blocker::Blocker,
+    cache::CacheDefence,
+    cookie::{
+        first_party_site, CookieDecision, CookieHandler, DIRECTION_REQUEST, DIRECTION_RESPONSE,
+    },
+    db::{CookieRecord, LogEvent, RequestRecord, ResponseRecord},
+    ephemeral::EphemeralSessions,
+    headers::HeaderRewriter,
+    jar::CookieJar,
+    listener::{internal_address, ClientInfo, ClientMap, Frontend},
+    logging::LogPolicy,
+    query::QueryStripper,
+    randomizer::Randomizer,
+    redirect::RedirectUnwrapper,
+    referer::RefererFilter,
+    upstream::Upstream,
+};
 use hudsucker::{
     start_proxy, CertificateAuthority, HttpContext, HttpHandler, NoopMessageHandler, ProxyConfig,
     RequestOrResponse,
 };
-use hyper::{Body, Request, Response, StatusCode};
-use log::info;
-use std::sync::Arc;
+use hyper::{
+    body::HttpBody,
+    header::{CONTENT_LENGTH, CONTENT_TYPE, PROXY_AUTHORIZATION},
+    http::uri::Scheme,
+    Body, Method, Request, Response, StatusCode,
+};
+use log::{debug, info, warn};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use std::time::Instant;
 use tokio::sync::{mpsc::Sender, Mutex};
 
+/// Body of the response sent for blocked requests.
+const BLOCKED_BODY: &str = "Blocked by privacy proxy";
+
+/// Source of exchange ids linking logged responses to their requests.
+static NEXT_EXCHANGE: AtomicU64 = AtomicU64::new(1);
+
 /// Shared state for the proxy handler.
 #[derive(Clone)]
 pub struct ProxyState {
@@ -15,54 +47,319 @@ pub struct ProxyState {
     pub randomizer: Arc<Mutex<Randomizer>>,
     /// Handler for cookie stripping.
     pub cookie_handler: Arc<CookieHandler>,
+    /// Partitioned cookie jar.
+    pub cookie_jar: Arc<CookieJar>,
+    /// In-memory cookie sessions for ephemeral domains.
+    pub ephemeral: Arc<EphemeralSessions>,
     /// Blocker for tracking domains.
     pub blocker: Arc<Blocker>,
+    /// ETag/Last-Modified tracking defence.
+    pub cache_defence: Arc<CacheDefence>,
+    /// Referer/Origin policy filter.
+    pub referer_filter: Arc<RefererFilter>,
+    /// Tracking query parameter stripper.
+    pub query_stripper: Arc<QueryStripper>,
+    /// Bounce-tracking redirect unwrapper.
+    pub redirect_unwrapper: Arc<RedirectUnwrapper>,
+    /// User-defined header rewrite rules.
+    pub header_rewriter: Arc<HeaderRewriter>,
+    /// Privacy policy applied to logged requests and cookies.
+    pub log_policy: Arc<LogPolicy>,
+    /// Real client addresses of front-end connections.
+    pub clients: Arc<ClientMap>,
+    /// Upstream proxy requests are sent through, if any.
+    pub upstream: Arc<Upstream>,
     /// Channel for async database logging.
     pub db_logger: Sender<LogEvent>,
 }
 
+/// Request details carried from `handle_request` to `handle_response`.
+///
+/// hudsucker hands each request/response pair to the same handler instance,
+/// so this is populated per exchange.
+#[derive(Clone, Default)]
+pub struct Exchange {
+    /// Client that made the request: its address and authenticated user.
+    pub client: Option<ClientInfo>,
+    /// Hostname of the request.
+    pub host: String,
+    /// Path of the request, which cookies without `Path` are scoped to.
+    pub path: String,
+    /// First-party site the request was made from.
+    pub site: String,
+    /// Method of the request.
+    pub method: Method,
+    /// Whether the request was made over HTTPS.
+    pub https: bool,
+    /// Whether cache validators are removed from this exchange.
+    pub neutralise_cache: bool,
+    /// Name of the route the request was sent along.
+    pub route: Option<String>,
+    /// Exchange id under which the request was logged, if it was.
+    pub logged_as: Option<u64>,
+    /// When the request was handed on to the upstream server.
+    pub forwarded_at: Option<Instant>,
+}
+
 /// HTTP handler for the privacy proxy.
 #[derive(Clone)]
 pub struct PrivacyHandler {
     /// Shared state.
     pub state: ProxyState,
+    /// The exchange currently being handled.
+    pub exchange: Exchange,
+}
+
+impl PrivacyHandler {
+    /// Authenticated user of the current exchange, if any.
+    fn user(&self) -> Option<String> {
+        self.exchange.client.as_ref().and_then(|c| c.user.clone())
+    }
+
+    /// Sends a cookie decision of the current exchange to the database logger,
+    /// classified by the cookie catalogue.
+    async fn log_cookie(&self, decision: CookieDecision, direction: &str) {
+        let entry = self.state.cookie_handler.classify(&decision.name);
+        let record = CookieRecord {
+            domain: self.exchange.host.clone(),
+            site: self.exchange.site.clone(),
+            vendor: entry.map(|e| e.vendor.clone()),
+            category: entry.map(|e| e.category.as_str().to_string()),
+            name: decision.name,
+            cookie: self.state.log_policy.cookie(&decision.raw),
+            direction: direction.to_string(),
+            blocked: decision.blocked,
+            user: self.user(),
+        };
+        let _ = self.state.db_logger.send(LogEvent::Cookie(record)).await;
+    }
+
+    /// Sends the request of the current exchange to the database logger, as
+    /// far as the logging policy allows.
+    async fn log_request(&mut self, request: &Request<Body>, blocked: bool) {
+        let Some(path) = self.state.log_policy.request_path(request.uri()) else {
+            return;
+        };
+        let user_agent = request
+            .headers()
+            .get(hyper::header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("unknown")
+            .to_string();
+        let exchange = NEXT_EXCHANGE.fetch_add(1, Ordering::Relaxed);
+        self.exchange.logged_as = Some(exchange);
+        let client = self.exchange.client.as_ref().map(|c| c.addr);
+
+        let _ = self
+            .state
+            .db_logger
+            .send(LogEvent::Request(RequestRecord {
+                domain: self.exchange.host.clone(),
+                path,
+                user_agent,
+                client_ip: client
+                    .map(|c| c.ip().to_canonical().to_string())
+                    .unwrap_or_else(|| "unknown".to_string()),
+                client_port: client.map(|c| c.port()),
+                method: request.method().to_string(),
+                scheme: if self.exchange.https { "https" } else { "http" }.to_string(),
+                via_connect: self.exchange.client.as_ref().is_some_and(|c| c.via_connect),
+                blocked,
+                user: self.user(),
+                route: self.exchange.route.clone(),
+                exchange,
+            }))
+            .await;
+    }
+
+    /// Sends the response metadata of the current exchange to the database logger.
+    ///
+    /// Without a `Content-Length` the body is counted while it streams to the
+    /// client and the metadata is sent once it ends.
+    async fn log_response(&self, response: &mut Response<Body>) {
+        let Some(exchange) = self.exchange.logged_as else {
+            return;
+        };
+        let mut record = ResponseRecord {
+            exchange,
+            status: response.status().as_u16(),
+            content_type: response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string),
+            bytes: response
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok()),
+            latency_ms: self
+                .exchange
+                .forwarded_at
+                .map(|t| t.elapsed().as_millis() as u64),
+        };
+        if record.bytes.is_some() {
+            let _ = self.state.db_logger.send(LogEvent::Response(record)).await;
+            return;
+        }
+
+        let mut body = std::mem::take(response.body_mut());
+        let (mut sender, counted) = Body::channel();
+        *response.body_mut() = counted;
+        let logger = self.state.db_logger.clone();
+        tokio::spawn(async move {
+            let mut bytes = 0;
+            let mut complete = true;
+            while let Some(chunk) = body.data().await {
+                let Ok(chunk) = chunk else {
+                    complete = false;
+                    break;
+                };
+                bytes += chunk.len() as u64;
+                if sender.send_data(chunk).await.is_err() {
+                    break;
+                }
+            }
+            if !complete {
+                sender.abort();
+            } else if let Ok(Some(trailers)) = body.trailers().await {
+                let _ = sender.send_trailers(trailers).await;
+            }
+            record.bytes = Some(bytes);
+            let _ = logger.send(LogEvent::Response(record)).await;
+        });
+    }
+
+    /// Sends cache validators removed from the current exchange to the database logger.
+    async fn log_cache_validators(&self, headers: Vec<String>) {
+        if headers.is_empty() {
+            return;
+        }
+        debug!(
+            "Neutralised cache validators for {}: {:?}",
+            self.exchange.host, headers
+        );
+        let _ = self
+            .state
+            .db_logger
+            .send(LogEvent::CacheValidators {
+                domain: self.exchange.host.clone(),
+                headers,
+            })
+            .await;
+    }
 }
 
 #[async_trait::async_trait]
 impl HttpHandler for PrivacyHandler {
     async fn handle_request(
         &mut self,
-        _context: &HttpContext,
+        context: &HttpContext,
         mut request: Request<Body>,
     ) -> RequestOrResponse {
+        // Only serve connections that came through a front-end listener
+        let Some(client) = self.state.clients.resolve(context.client_addr) else {
+            warn!(
+                "Refusing request from {} bypassing the listeners",
+                context.client_addr
+            );
+            let response = Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::empty())
+                .unwrap();
+            return RequestOrResponse::Response(response);
+        };
+
+        // Credentials are checked by the front-end and never go upstream
+        request.headers_mut().remove(PROXY_AUTHORIZATION);
+
         // Extract host for blocking
         let host = request.uri().host().unwrap_or("unknown").to_string();
+        self.exchange = Exchange {
+            client: Some(client),
+            host: host.clone(),
+            path: request.uri().path().to_string(),
+            site: first_party_site(&request, &host),
+            method: request.method().clone(),
+            https: request.uri().scheme() == Some(&Scheme::HTTPS),
+            neutralise_cache: false,
+            route: None,
+            logged_as: None,
+            forwarded_at: None,
+        };
+
+        // Answer known bounce-tracking wrappers without contacting the tracker
+        if let Some(target) = self.state.redirect_unwrapper.unwrap_uri(request.uri()) {
+            info!("Unwrapped bounce redirect via {} to {}", host, target);
+            let mut response = RedirectUnwrapper::redirect_response(&target);
+            self.log_request(&request, false).await;
+            self.log_response(&mut response).await;
+            return RequestOrResponse::Response(response);
+        }
 
         // Check if domain should be blocked (handles tracking logic internally)
         if self.state.blocker.check_and_track(&host).await {
             info!("Blocking request to: {}", host);
-            let response = Response::builder()
+            let mut response = Response::builder()
                 .status(StatusCode::FORBIDDEN)
-                .body(Body::from("Blocked by privacy proxy"))
+                .header(CONTENT_LENGTH, BLOCKED_BODY.len())
+                .body(Body::from(BLOCKED_BODY))
                 .unwrap();
+            self.log_request(&request, true).await;
+            self.log_response(&mut response).await;
             return RequestOrResponse::Response(response);
         }
 
-        // Strip cookies from request and log if configured
-        if let Some(cookie) = self
+        // Drop conditional headers that would echo a cache identifier back
+        self.exchange.neutralise_cache = self.state.cache_defence.applies(
+            &host,
+            &self.exchange.site,
+            self.state.blocker.is_tracker(&host),
+        );
+        if self.exchange.neutralise_cache {
+            let removed = self.state.cache_defence.strip_request(&mut request);
+            self.log_cache_validators(removed).await;
+        }
+
+        // Replay ephemeral session or partitioned jar cookies, or strip
+        // cookies from the request and log if configured
+        if self.state.ephemeral.domain_for(&host).is_some() {
+            let replayed = self
+                .state
+                .ephemeral
+                .replay(&mut request, &host, self.exchange.https);
+            if !replayed.is_empty() {
+                debug!("Replayed ephemeral cookies to {}: {:?}", host, replayed);
+            }
+        } else if self
             .state
-            .cookie_handler
-            .strip_cookies_request(&mut request, &host)
+            .cookie_jar
+            .is_partitioned(&host, &self.exchange.site)
         {
-            let _ = self
+            let replayed = self
                 .state
-                .db_logger
-                .send(LogEvent::Cookie {
-                    domain: host.clone(),
-                    cookie,
-                    blocked: true,
-                })
+                .cookie_jar
+                .replay(
+                    &mut request,
+                    &host,
+                    &self.exchange.site,
+                    self.exchange.https,
+                )
                 .await;
+            if !replayed.is_empty() {
+                debug!(
+                    "Replayed jar cookies to {} under {}: {:?}",
+                    host, self.exchange.site, replayed
+                );
+            }
+        } else {
+            for decision in self
+                .state
+                .cookie_handler
+                .strip_cookies_request(&mut request, &host)
+            {
+                self.log_cookie(decision, DIRECTION_REQUEST).await;
+            }
         }
 
         // Apply fingerprint randomization
@@ -90,10 +387,6 @@ impl HttpHandler for PrivacyHandler {
                     rotated = true;
                 }
             }
-            if rand.strip_referer {
-                request.headers_mut().remove(hyper::header::REFERER);
-            }
-
             if rotated {
                 let _ = self
                     .state
@@ -102,32 +395,68 @@ impl HttpHandler for PrivacyHandler {
                         user_agent: ua,
                         accept_language: lang,
                         mode: rand.mode.clone(),
+                        user: self.user(),
                     })
                     .await;
             }
         }
 
-        // Log request (non-blocking)
-        let path = request.uri().path().to_string();
-        let user_agent = request
-            .headers()
-            .get(hyper::header::USER_AGENT)
-            .and_then(|v| v.to_str().ok())
-            .unwrap_or("unknown")
-            .to_string();
+        // Apply Referer/Origin policy
+        self.state.referer_filter.apply(&mut request, &host);
 
-        let _ = self
+        // Strip tracking query parameters
+        let stripped = self.state.query_stripper.strip_request(&mut request, &host);
+        if !stripped.is_empty() {
+            debug!("Stripped query parameters from {}: {:?}", host, stripped);
+            let _ = self
+                .state
+                .db_logger
+                .send(LogEvent::StrippedParams {
+                    domain: host.clone(),
+                    params: stripped,
+                })
+                .await;
+        }
+
+        // Apply user-defined header rules
+        let applied = self
             .state
-            .db_logger
-            .send(LogEvent::Request {
-                domain: host,
-                path,
-                user_agent,
-                client_ip: "unknown".to_string(),
-            })
-            .await;
+            .header_rewriter
+            .apply_request(&mut request, &host);
+        if !applied.is_empty() {
+            debug!(
+                "Header rules applied to request for {}: {:?}",
+                host, applied
+            );
+        }
+
+        let mut route = self.state.upstream.route(&host, &self.exchange.site);
+        // Transparent clients already chose the server; the name they sent
+        // only selects the certificate and policies
+        route.address = self.exchange.client.as_ref().and_then(|c| c.destination);
+        self.exchange.route = Some(route.name.clone());
+
+        // Log request (non-blocking)
+        self.log_request(&request, false).await;
+        self.exchange.forwarded_at = Some(Instant::now());
 
-        RequestOrResponse::Request(request)
+        if route.proxy.is_none() && route.address.is_none() {
+            return RequestOrResponse::Request(request);
+        }
+        // hudsucker's client cannot speak SOCKS or connect to a given address,
+        // so those requests are sent here; a failing upstream never falls
+        // back to direct
+        let response = match self.state.upstream.send(&route, request).await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Request to {} via {} failed: {}", host, route.name, e);
+                Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        };
+        RequestOrResponse::Response(self.handle_response(context, response).await)
     }
 
     async fn handle_response(
@@ -135,109 +464,127 @@ impl HttpHandler for PrivacyHandler {
         _context: &HttpContext,
         mut response: Response<Body>,
     ) -> Response<Body> {
-        // Strip Set-Cookie headers from response
-        if let Some(cookie) = self
+        // Collapse redirect chains through opaque redirectors
+        self.state
+            .redirect_unwrapper
+            .follow_response(&self.exchange.host, &mut response)
+            .await;
+
+        // Capture ephemeral or partitioned cookies, or strip Set-Cookie headers
+        let decisions = if self
             .state
-            .cookie_handler
-            .strip_cookies_response(&mut response, None)
+            .ephemeral
+            .domain_for(&self.exchange.host)
+            .is_some()
         {
-            // We don't easily have the domain here in handle_response without context
-            // For now, we log "unknown" or skip logging response cookies if domain is critical
-            // Or we could store domain in context? Hudsucker context is immutable.
-            // Let's log with "response" as domain for now or skip.
-            // Better: skip logging response cookies for now to avoid noise/inaccuracy
-            // OR: LogEvent::Cookie { domain: "response".to_string(), ... }
+            let handler = &self.state.cookie_handler;
+            let host = &self.exchange.host;
+            let path = &self.exchange.path;
+            self.state
+                .ephemeral
+                .capture(&mut response, host, path, |name| {
+                    handler.blocked_by_rule(name, host)
+                })
+        } else if self
+            .state
+            .cookie_jar
+            .is_partitioned(&self.exchange.host, &self.exchange.site)
+        {
+            let handler = &self.state.cookie_handler;
+            let host = &self.exchange.host;
+            self.state
+                .cookie_jar
+                .capture(
+                    &mut response,
+                    host,
+                    &self.exchange.path,
+                    &self.exchange.site,
+                    |name| handler.blocked_by_rule(name, host),
+                )
+                .await
+        } else {
+            self.state
+                .cookie_handler
+                .strip_cookies_response(&mut response, &self.exchange.host)
+        };
+        for decision in decisions {
+            self.log_cookie(decision, DIRECTION_RESPONSE).await;
+        }
+
+        // Harden the attributes of the cookies that were let through
+        for rewrite in self.state.cookie_handler.harden_set_cookies(
+            &mut response,
+            Some(&self.exchange.host),
+            self.exchange.https,
+        ) {
+            debug!(
+                "Hardened cookie {} from {}: {:?}",
+                rewrite.name, self.exchange.host, rewrite.changes
+            );
             let _ = self
                 .state
                 .db_logger
-                .send(LogEvent::Cookie {
-                    domain: "response".to_string(),
-                    cookie,
-                    blocked: true,
+                .send(LogEvent::CookieRewrite {
+                    domain: self.exchange.host.clone(),
+                    name: rewrite.name,
+                    changes: rewrite.changes,
                 })
                 .await;
         }
-        response
-    }
-}
-
-/// Generates or loads the Certificate Authority for HTTPS interception.
-fn generate_ca() -> anyhow::Result<CertificateAuthority> {
-    let cert_path = "ca_cert.pem";
-    let key_path = "ca_key.pem";
-
-    // Try to load existing CA
-    if std::path::Path::new(cert_path).exists() && std::path::Path::new(key_path).exists() {
-        info!("Loading existing CA certificate from disk");
 
-        let cert_pem = std::fs::read_to_string(cert_path)?;
-        let key_pem = std::fs::read_to_string(key_path)?;
+        // Remove validators a tracker could store an identifier in
+        if self.exchange.neutralise_cache {
+            let removed = self.state.cache_defence.strip_response(&mut response);
+            self.log_cache_validators(removed).await;
+        }
 
-        // Parse PEM to DER
-        let cert_der = pem::parse(&cert_pem)?.into_contents();
-        let key_der = pem::parse(&key_pem)?.into_contents();
+        // Apply user-defined header rules
+        let applied = self.state.header_rewriter.apply_response(
+            &mut response,
+            &self.exchange.host,
+            &self.exchange.method,
+        );
+        if !applied.is_empty() {
+            debug!(
+                "Header rules applied to response from {}: {:?}",
+                self.exchange.host, applied
+            );
+        }
 
-        let private_key = hudsucker::rustls::PrivateKey(key_der);
-        let ca_cert = hudsucker::rustls::Certificate(cert_der);
+        // Record status, type, size and latency against the logged request
+        self.log_response(&mut response).await;
 
-        return Ok(CertificateAuthority::new(private_key, ca_cert, 1000)?);
+        response
     }
-
-    // Generate new CA certificate
-    info!("Generating new CA certificate");
-    let mut params = rcgen::CertificateParams::new(vec!["BlankTrace CA".to_string()]);
-    params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
-    params.key_usages = vec![
-        rcgen::KeyUsagePurpose::KeyCertSign,
-        rcgen::KeyUsagePurpose::CrlSign,
-    ];
-
-    let key_pair = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256)?;
-    params.key_pair = Some(key_pair);
-
-    let cert = rcgen::Certificate::from_params(params)?;
-    let cert_der = cert.serialize_der()?;
-    let key_der = cert.serialize_private_key_der();
-
-    // Save to disk as PEM
-    let cert_pem = pem::encode(&pem::Pem::new("CERTIFICATE", cert_der.clone()));
-    let key_pem = pem::encode(&pem::Pem::new("PRIVATE KEY", key_der.clone()));
-
-    std::fs::write(cert_path, cert_pem)?;
-    std::fs::write(key_path, key_pem)?;
-
-    info!("CA certificate saved to {} and {}", cert_path, key_path);
-    info!(
-        "Install {} in your browser to trust HTTPS connections",
-        cert_path
-    );
-
-    let private_key = hudsucker::rustls::PrivateKey(key_der);
-    let ca_cert = hudsucker::rustls::Certificate(cert_der);
-
-    Ok(CertificateAuthority::new(private_key, ca_cert, 1000)?)
 }
 
 /// Starts the proxy server.
 ///
+/// hudsucker serves an internal loopback address; the front-end listeners
+/// accept clients on the configured addresses and pipe them to it.
+///
 /// # Arguments
 ///
 /// * `state` - Initial proxy state.
-/// * `port` - Port to listen on.
-pub async fn run_proxy(state: ProxyState, port: u16) -> anyhow::Result<()> {
-    info!("Initializing privacy proxy on port {}", port);
-
-    // Generate CA for HTTPS interception
-    let ca = generate_ca()?;
-    info!("Generated CA certificate for HTTPS interception");
-    info!("Note: You'll need to trust the CA certificate in your browser");
+/// * `frontend` - Client-facing listeners.
+/// * `ca` - Authority signing certificates for intercepted hosts.
+pub async fn run_proxy(
+    state: ProxyState,
+    frontend: Frontend,
+    ca: CertificateAuthority,
+) -> anyhow::Result<()> {
+    let internal = internal_address()?;
+    info!("Initializing privacy proxy (internal address {})", internal);
 
     // Create handler
-    let handler = PrivacyHandler { state };
+    let handler = PrivacyHandler {
+        state,
+        exchange: Exchange::default(),
+    };
 
     // Create proxy configuration
     let config = ProxyConfig {
-        listen_addr: std::net::SocketAddr::from(([127, 0, 0, 1], port)),
+        listen_addr: internal,
         shutdown_signal: Box::pin(async {
             tokio::signal::ctrl_c()
                 .await
@@ -250,7 +597,9 @@ pub async fn run_proxy(state: ProxyState, port: u16) -> anyhow::Result<()> {
         upstream_proxy: None,
     };
 
-    info!("Privacy proxy listening on 127.0.0.1:{}", port);
+    for (protocol, addr) in frontend.bind(internal).await? {
+        info!("Privacy proxy listening on {} ({})", addr, protocol);
+    }
     info!("Configure your browser to use this proxy for HTTP/HTTPS traffic");
     info!("Press Ctrl+C to stop the proxy");
 
@@ -0,0 +1,317 @@
+use crate::config::{Config, QueryParamsConfig};
+use crate::pattern::{domain_matches, glob};
+use anyhow::Context;
+use hyper::{Body, Request, Uri};
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+
+/// Tracking parameters stripped on every domain, modelled on the ClearURLs global rules.
+const BUILTIN_PARAMS: &[&str] = &[
+    "utm_*",
+    "ga_*",
+    "_ga",
+    "_gl",
+    "gclid",
+    "gclsrc",
+    "dclid",
+    "gbraid",
+    "wbraid",
+    "fbclid",
+    "msclkid",
+    "yclid",
+    "twclid",
+    "ttclid",
+    "igshid",
+    "li_fat_id",
+    "mc_eid",
+    "mc_cid",
+    "_hsenc",
+    "_hsmi",
+    "__hssc",
+    "__hstc",
+    "__hsfp",
+    "hsctatracking",
+    "mkt_tok",
+    "oly_anon_id",
+    "oly_enc_id",
+    "vero_id",
+    "vero_conv",
+    "wickedid",
+    "_openstat",
+    "pk_*",
+    "piwik_*",
+    "mtm_*",
+    "matomo_*",
+    "s_cid",
+    "srsltid",
+];
+
+/// Built-in per-domain rules: `(domain, extra params to strip, params to keep)`.
+const BUILTIN_RULES: &[(&str, &[&str], &[&str])] = &[
+    (
+        "amazon.com",
+        &["pd_rd_*", "pf_rd_*", "_encoding", "psc", "ref_"],
+        &[],
+    ),
+    ("youtube.com", &["si", "pp", "feature"], &[]),
+    ("youtu.be", &["si", "feature"], &[]),
+    ("twitter.com", &["ref_src", "ref_url", "s", "t"], &[]),
+    ("x.com", &["ref_src", "ref_url", "s", "t"], &[]),
+    ("open.spotify.com", &["si", "context"], &[]),
+    ("instagram.com", &["igsh"], &[]),
+    ("reddit.com", &["share_id", "ref", "ref_source"], &[]),
+    // Mailchimp needs the campaign/subscriber IDs for unsubscribe and preference links
+    ("list-manage.com", &[], &["mc_eid", "mc_cid"]),
+    ("mailchimp.com", &[], &["mc_eid", "mc_cid"]),
+];
+
+/// A compiled per-domain rule.
+#[derive(Clone)]
+struct DomainRule {
+    domain: String,
+    strip: Vec<Regex>,
+    keep: Vec<Regex>,
+}
+
+impl DomainRule {
+    fn compile(domain: &str, strip: &[String], keep: &[String]) -> anyhow::Result<Self> {
+        Ok(Self {
+            domain: domain.to_string(),
+            strip: strip
+                .iter()
+                .map(|p| glob(p))
+                .collect::<anyhow::Result<_>>()?,
+            keep: keep
+                .iter()
+                .map(|p| glob(p))
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+}
+
+/// Removes tracking parameters from request URIs.
+#[derive(Clone)]
+pub struct QueryStripper {
+    enabled: bool,
+    global: Vec<Regex>,
+    rules: Vec<DomainRule>,
+}
+
+impl QueryStripper {
+    /// Creates a new QueryStripper.
+    ///
+    /// User rules are evaluated alongside the built-in ones; a `keep` match from any
+    /// applicable rule wins over every `strip` pattern.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg` - Application configuration.
+    pub fn new(cfg: &Config) -> anyhow::Result<Self> {
+        Self::from_config(&cfg.query_params)
+    }
+
+    /// Creates a new QueryStripper from the `query_params` section.
+    ///
+    /// # Arguments
+    ///
+    /// * `qp` - Query parameter configuration.
+    pub fn from_config(qp: &QueryParamsConfig) -> anyhow::Result<Self> {
+        let to_strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let mut global_patterns = Vec::new();
+        let mut rules = Vec::new();
+        if qp.builtin {
+            global_patterns.extend(to_strings(BUILTIN_PARAMS));
+            for (domain, strip, keep) in BUILTIN_RULES {
+                rules.push(DomainRule::compile(
+                    domain,
+                    &to_strings(strip),
+                    &to_strings(keep),
+                )?);
+            }
+        }
+        global_patterns.extend(qp.strip.iter().cloned());
+        for rule in &qp.rules {
+            rules.push(
+                DomainRule::compile(&rule.domain, &rule.strip, &rule.keep)
+                    .with_context(|| format!("query_params rule for '{}'", rule.domain))?,
+            );
+        }
+
+        Ok(Self {
+            enabled: qp.enabled,
+            global: global_patterns
+                .iter()
+                .map(|p| glob(p))
+                .collect::<anyhow::Result<_>>()?,
+            rules,
+        })
+    }
+
+    /// Checks whether `param` should be removed from URLs on `host`.
+    fn should_strip(&self, param: &str, host: &str) -> bool {
+        let applicable = || {
+            self.rules
+                .iter()
+                .filter(|r| domain_matches(host, &r.domain))
+        };
+        if applicable().any(|r| r.keep.iter().any(|k| k.is_match(param))) {
+            return false;
+        }
+        self.global.iter().any(|g| g.is_match(param))
+            || applicable().any(|r| r.strip.iter().any(|s| s.is_match(param)))
+    }
+
+    /// Removes tracking parameters from a URI.
+    ///
+    /// Returns the rewritten URI and the names of the removed parameters, or None
+    /// if nothing was removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - The URI to clean.
+    /// * `host` - The hostname whose rules apply.
+    pub fn strip_uri(&self, uri: &Uri, host: &str) -> Option<(Uri, Vec<String>)> {
+        if !self.enabled {
+            return None;
+        }
+        let query = uri.query()?;
+
+        let mut removed = Vec::new();
+        let mut kept = Vec::new();
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let raw_name = pair.split('=').next().unwrap_or(pair);
+            let name = percent_decode_str(&raw_name.replace('+', " "))
+                .decode_utf8_lossy()
+                .to_string();
+            if self.should_strip(&name, host) {
+                removed.push(name);
+            } else {
+                kept.push(pair);
+            }
+        }
+        if removed.is_empty() {
+            return None;
+        }
+
+        let path_and_query = if kept.is_empty() {
+            uri.path().to_string()
+        } else {
+            format!("{}?{}", uri.path(), kept.join("&"))
+        };
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = Some(path_and_query.parse().ok()?);
+        let cleaned = Uri::from_parts(parts).ok()?;
+        Some((cleaned, removed))
+    }
+
+    /// Removes tracking parameters from the request URI in place.
+    ///
+    /// Returns the names of the removed parameters.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The mutable HTTP request.
+    /// * `host` - The hostname of the request.
+    pub fn strip_request(&self, req: &mut Request<Body>, host: &str) -> Vec<String> {
+        match self.strip_uri(req.uri(), host) {
+            Some((uri, removed)) => {
+                *req.uri_mut() = uri;
+                removed
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::QueryParamRule;
+
+    fn stripper(qp: QueryParamsConfig) -> QueryStripper {
+        QueryStripper::from_config(&qp).unwrap()
+    }
+
+    fn strip(s: &QueryStripper, uri: &str) -> (String, Vec<String>) {
+        let uri: Uri = uri.parse().unwrap();
+        let host = uri.host().unwrap().to_string();
+        match s.strip_uri(&uri, &host) {
+            Some((u, removed)) => (u.to_string(), removed),
+            None => (uri.to_string(), vec![]),
+        }
+    }
+
+    #[test]
+    fn test_builtin_params() {
+        let s = stripper(QueryParamsConfig::default());
+        let (uri, removed) = strip(
+            &s,
+            "https://example.com/article?id=42&utm_source=news&utm_medium=email&fbclid=abc",
+        );
+        assert_eq!(uri, "https://example.com/article?id=42");
+        assert_eq!(removed, vec!["utm_source", "utm_medium", "fbclid"]);
+
+        let (uri, _) = strip(&s, "https://example.com/?gclid=1");
+        assert_eq!(uri, "https://example.com/");
+
+        let (uri, removed) = strip(&s, "https://example.com/search?q=a%26b&page=2");
+        assert_eq!(uri, "https://example.com/search?q=a%26b&page=2");
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn test_domain_rules_and_exceptions() {
+        let s = stripper(QueryParamsConfig {
+            enabled: true,
+            builtin: true,
+            strip: vec!["campaign".to_string()],
+            rules: vec![
+                QueryParamRule {
+                    domain: "shop.example".to_string(),
+                    strip: vec!["ref".to_string()],
+                    keep: vec![],
+                },
+                QueryParamRule {
+                    domain: "news.example".to_string(),
+                    strip: vec![],
+                    keep: vec!["utm_source".to_string()],
+                },
+            ],
+        });
+
+        let (uri, _) = strip(
+            &s,
+            "https://www.shop.example/item?ref=home&campaign=x&sku=1",
+        );
+        assert_eq!(uri, "https://www.shop.example/item?sku=1");
+
+        let (uri, _) = strip(&s, "https://other.example/item?ref=home");
+        assert_eq!(uri, "https://other.example/item?ref=home");
+
+        let (uri, removed) = strip(&s, "https://news.example/?utm_source=rss&utm_medium=feed");
+        assert_eq!(uri, "https://news.example/?utm_source=rss");
+        assert_eq!(removed, vec!["utm_medium"]);
+
+        let (uri, _) = strip(&s, "https://us1.list-manage.com/unsubscribe?u=1&mc_eid=2");
+        assert_eq!(uri, "https://us1.list-manage.com/unsubscribe?u=1&mc_eid=2");
+    }
+
+    #[test]
+    fn test_disabled_and_no_builtin() {
+        let s = stripper(QueryParamsConfig {
+            enabled: false,
+            ..QueryParamsConfig::default()
+        });
+        let (uri, _) = strip(&s, "https://example.com/?utm_source=x");
+        assert_eq!(uri, "https://example.com/?utm_source=x");
+
+        let s = stripper(QueryParamsConfig {
+            builtin: false,
+            strip: vec!["tracker".to_string()],
+            ..QueryParamsConfig::default()
+        });
+        let (uri, _) = strip(&s, "https://example.com/?utm_source=x&tracker=1");
+        assert_eq!(uri, "https://example.com/?utm_source=x");
+    }
+}
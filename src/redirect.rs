@@ -0,0 +1,433 @@
+use crate::config::{Config, RedirectsConfig};
+use crate::pattern::glob;
+use crate::query::QueryStripper;
+use crate::upstream::Upstream;
+use anyhow::Context;
+use hyper::header::{HeaderValue, LOCATION};
+use hyper::{Body, Request, Response, Uri};
+use log::{debug, info};
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Bundled wrapper and redirector patterns.
+const BUILTIN_RULES: &str = include_str!("../redirect_rules.yaml");
+
+/// Contents of a redirect rule file.
+#[derive(Debug, Deserialize, Default)]
+pub struct RuleFile {
+    /// Redirectors that carry the destination in a query parameter.
+    #[serde(default)]
+    pub wrappers: Vec<WrapperRule>,
+    /// Host globs of redirectors that only reveal the destination via 3xx.
+    #[serde(default)]
+    pub opaque: Vec<String>,
+}
+
+/// A redirector that encodes its destination in the request URL.
+#[derive(Debug, Deserialize)]
+pub struct WrapperRule {
+    /// Host glob, e.g. `l.facebook.com` or `www.google.*`.
+    pub host: String,
+    /// Path glob (default: any path).
+    #[serde(default = "default_path")]
+    pub path: String,
+    /// Query parameter holding the destination URL.
+    pub param: String,
+}
+
+fn default_path() -> String {
+    "*".to_string()
+}
+
+struct CompiledWrapper {
+    host: Regex,
+    path: Regex,
+    param: String,
+}
+
+/// Unwraps bounce-tracking redirects so the tracker is skipped entirely.
+#[derive(Clone)]
+pub struct RedirectUnwrapper {
+    enabled: bool,
+    wrappers: Arc<Vec<CompiledWrapper>>,
+    opaque: Arc<Vec<Regex>>,
+    follow_opaque: bool,
+    max_hops: usize,
+    stripper: Arc<QueryStripper>,
+    upstream: Arc<Upstream>,
+}
+
+impl RedirectUnwrapper {
+    /// Creates a new RedirectUnwrapper from the bundled rules and the optional rule file.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg` - Application configuration.
+    /// * `stripper` - Query stripper applied to every unwrapped destination.
+    /// * `upstream` - Route for the requests made while following redirects.
+    pub fn new(
+        cfg: &Config,
+        stripper: Arc<QueryStripper>,
+        upstream: Arc<Upstream>,
+    ) -> anyhow::Result<Self> {
+        let rc = &cfg.redirects;
+        let mut files = Vec::new();
+        if rc.builtin {
+            files.push(serde_yaml::from_str(BUILTIN_RULES).context("bundled redirect rules")?);
+        }
+        if let Some(path) = &rc.rules_file {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read redirect rules '{}'", path))?;
+            files.push(
+                serde_yaml::from_str(&content)
+                    .with_context(|| format!("invalid redirect rules '{}'", path))?,
+            );
+        }
+        Self::from_rule_files(rc, files, stripper, upstream)
+    }
+
+    /// Creates a new RedirectUnwrapper from already parsed rule files.
+    ///
+    /// # Arguments
+    ///
+    /// * `rc` - Redirect configuration.
+    /// * `files` - Parsed rule files, merged in order.
+    /// * `stripper` - Query stripper applied to every unwrapped destination.
+    /// * `upstream` - Route for the requests made while following redirects.
+    pub fn from_rule_files(
+        rc: &RedirectsConfig,
+        files: Vec<RuleFile>,
+        stripper: Arc<QueryStripper>,
+        upstream: Arc<Upstream>,
+    ) -> anyhow::Result<Self> {
+        let mut wrappers = Vec::new();
+        let mut opaque = Vec::new();
+        for file in files {
+            for w in file.wrappers {
+                wrappers.push(CompiledWrapper {
+                    host: glob(&w.host)?,
+                    path: glob(&w.path)?,
+                    param: w.param,
+                });
+            }
+            for host in file.opaque {
+                opaque.push(glob(&host)?);
+            }
+        }
+
+        Ok(Self {
+            enabled: rc.enabled,
+            wrappers: Arc::new(wrappers),
+            opaque: Arc::new(opaque),
+            follow_opaque: rc.follow_opaque,
+            max_hops: rc.max_hops,
+            stripper,
+            upstream,
+        })
+    }
+
+    /// Checks whether `host` is a known opaque redirector.
+    fn is_opaque(&self, host: &str) -> bool {
+        self.opaque.iter().any(|o| o.is_match(host))
+    }
+
+    /// Decodes the destination of a wrapper URL, without cleaning it.
+    fn decode_wrapper(&self, uri: &Uri) -> Option<Uri> {
+        let host = uri.host()?;
+        let query = uri.query()?;
+        for w in self
+            .wrappers
+            .iter()
+            .filter(|w| w.host.is_match(host) && w.path.is_match(uri.path()))
+        {
+            for pair in query.split('&') {
+                let (name, value) = match pair.split_once('=') {
+                    Some(kv) => kv,
+                    None => continue,
+                };
+                if percent_decode_str(name).decode_utf8_lossy() != w.param {
+                    continue;
+                }
+                let decoded = percent_decode_str(value).decode_utf8_lossy();
+                if let Ok(target) = decoded.parse::<Uri>() {
+                    let web = matches!(target.scheme_str(), Some("http") | Some("https"));
+                    if web && target.host().is_some() {
+                        return Some(target);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Removes tracking parameters from a destination URI.
+    fn clean(&self, uri: Uri) -> Uri {
+        let host = uri.host().unwrap_or_default().to_string();
+        match self.stripper.strip_uri(&uri, &host) {
+            Some((cleaned, _)) => cleaned,
+            None => uri,
+        }
+    }
+
+    /// Returns the cleaned destination if the request URI is a known wrapper.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - The request URI.
+    pub fn unwrap_uri(&self, uri: &Uri) -> Option<Uri> {
+        if !self.enabled {
+            return None;
+        }
+        let mut target = self.decode_wrapper(uri)?;
+        // Wrappers are sometimes nested (e.g. a Google link to a Facebook link)
+        for _ in 0..self.max_hops {
+            match self.decode_wrapper(&target) {
+                Some(inner) => target = inner,
+                None => break,
+            }
+        }
+        Some(self.clean(target))
+    }
+
+    /// Builds the 302 response sent instead of contacting a wrapper host.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - The unwrapped destination.
+    pub fn redirect_response(target: &Uri) -> Response<Body> {
+        Response::builder()
+            .status(hyper::StatusCode::FOUND)
+            .header(LOCATION, target.to_string())
+            .header(hyper::header::CACHE_CONTROL, "no-store")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    /// Follows the redirect chain of an opaque redirector's 3xx response.
+    ///
+    /// Hops are only requested while they point at other known redirectors, so the
+    /// final destination is never fetched twice. Tracking parameters are removed
+    /// before every hop, and the response's `Location` is rewritten to the result.
+    ///
+    /// Returns the new location if it changed.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - The host that produced the response.
+    /// * `res` - The mutable HTTP response.
+    pub async fn follow_response(&self, host: &str, res: &mut Response<Body>) -> Option<Uri> {
+        if !self.enabled
+            || !self.follow_opaque
+            || !res.status().is_redirection()
+            || !self.is_opaque(host)
+        {
+            return None;
+        }
+        let original: Uri = res.headers().get(LOCATION)?.to_str().ok()?.parse().ok()?;
+        original.host()?;
+
+        let mut current = original.clone();
+        for _ in 0..self.max_hops {
+            if let Some(target) = self.decode_wrapper(&current) {
+                current = target;
+                continue;
+            }
+            let hop_host = current.host().unwrap_or_default().to_string();
+            if !self.is_opaque(&hop_host) {
+                break;
+            }
+            current = self.clean(current);
+            debug!("Following redirect hop {}", current);
+            let req = Request::get(current.clone()).body(Body::empty()).ok()?;
+            let route = self.upstream.route(&hop_host, &hop_host);
+            let next = match self.upstream.send(&route, req).await {
+                Ok(r) if r.status().is_redirection() => r
+                    .headers()
+                    .get(LOCATION)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|l| resolve(&current, l)),
+                _ => None,
+            };
+            match next {
+                Some(next) => current = next,
+                None => break,
+            }
+        }
+
+        let current = self.clean(current);
+        if current == original {
+            return None;
+        }
+        info!("Resolved redirect chain from {} to {}", host, current);
+        let value = HeaderValue::from_str(&current.to_string()).ok()?;
+        res.headers_mut().insert(LOCATION, value);
+        Some(current)
+    }
+}
+
+/// Resolves a `Location` header value against the URI that produced it.
+fn resolve(base: &Uri, location: &str) -> Option<Uri> {
+    if let Some(rest) = location.strip_prefix("//") {
+        return format!("{}://{}", base.scheme_str()?, rest).parse().ok();
+    }
+    if let Ok(uri) = location.parse::<Uri>() {
+        if uri.scheme().is_some() {
+            return Some(uri);
+        }
+    }
+    let path = if location.starts_with('/') {
+        location.to_string()
+    } else {
+        let dir = base.path().rsplit_once('/').map(|(d, _)| d).unwrap_or("");
+        format!("{}/{}", dir, location)
+    };
+    Uri::builder()
+        .scheme(base.scheme_str()?)
+        .authority(base.authority()?.as_str())
+        .path_and_query(path)
+        .build()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::QueryParamsConfig;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Server, StatusCode};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    fn unwrapper(follow_opaque: bool, extra: &str) -> RedirectUnwrapper {
+        let stripper = Arc::new(QueryStripper::from_config(&QueryParamsConfig::default()).unwrap());
+        let rc = RedirectsConfig {
+            follow_opaque,
+            ..RedirectsConfig::default()
+        };
+        let files = vec![
+            serde_yaml::from_str(BUILTIN_RULES).unwrap(),
+            serde_yaml::from_str(extra).unwrap(),
+        ];
+        RedirectUnwrapper::from_rule_files(&rc, files, stripper, Arc::new(Upstream::default()))
+            .unwrap()
+    }
+
+    async fn redirect_server(location: String) -> SocketAddr {
+        let make = make_service_fn(move |_| {
+            let location = location.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_req| {
+                    let location = location.clone();
+                    async move {
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .status(StatusCode::FOUND)
+                                .header(LOCATION, location)
+                                .body(Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[test]
+    fn test_unwrap_known_wrappers() {
+        let u = unwrapper(false, "{}");
+
+        let uri = "https://l.facebook.com/l.php?u=https%3A%2F%2Fexample.com%2Fpage%3Fid%3D1%26fbclid%3Dabc&h=AT0"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            u.unwrap_uri(&uri).unwrap().to_string(),
+            "https://example.com/page?id=1"
+        );
+
+        let uri = "https://www.google.com/url?sa=t&q=https://example.org/&ved=2ah"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            u.unwrap_uri(&uri).unwrap().to_string(),
+            "https://example.org/"
+        );
+
+        // Not a wrapper path, or a non-web target
+        let uri = "https://www.google.com/search?q=https://example.org/"
+            .parse()
+            .unwrap();
+        assert!(u.unwrap_uri(&uri).is_none());
+        let uri = "https://l.facebook.com/l.php?u=javascript%3Aalert(1)"
+            .parse()
+            .unwrap();
+        assert!(u.unwrap_uri(&uri).is_none());
+    }
+
+    #[test]
+    fn test_unwrap_nested_and_custom_rules() {
+        let u = unwrapper(
+            false,
+            r#"
+wrappers:
+  - host: "click.example.net"
+    path: "/c"
+    param: "dest"
+"#,
+        );
+        let inner =
+            "https%3A%2F%2Fl.facebook.com%2Fl.php%3Fu%3Dhttps%253A%252F%252Fexample.com%252F";
+        let uri = format!("https://click.example.net/c?dest={}", inner)
+            .parse()
+            .unwrap();
+        assert_eq!(
+            u.unwrap_uri(&uri).unwrap().to_string(),
+            "https://example.com/"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_follow_opaque_chain() {
+        let last = redirect_server("https://dest.example/final?utm_medium=email&id=9".into()).await;
+        let first = redirect_server(format!("http://{}/next?utm_source=x", last)).await;
+        let u = unwrapper(true, "opaque: [\"127.0.0.1\"]");
+
+        let mut res = Response::builder()
+            .status(StatusCode::MOVED_PERMANENTLY)
+            .header(LOCATION, format!("http://{}/start", first))
+            .body(Body::empty())
+            .unwrap();
+        let resolved = u.follow_response("t.co", &mut res).await.unwrap();
+        assert_eq!(resolved.to_string(), "https://dest.example/final?id=9");
+        assert_eq!(res.headers()[LOCATION], "https://dest.example/final?id=9");
+
+        // Responses from hosts that are not redirectors are left alone
+        let mut res = Response::builder()
+            .status(StatusCode::FOUND)
+            .header(LOCATION, format!("http://{}/start", first))
+            .body(Body::empty())
+            .unwrap();
+        assert!(u.follow_response("example.com", &mut res).await.is_none());
+    }
+
+    #[test]
+    fn test_resolve_relative_location() {
+        let base: Uri = "https://t.co/a/b".parse().unwrap();
+        assert_eq!(
+            resolve(&base, "/x?y=1").unwrap().to_string(),
+            "https://t.co/x?y=1"
+        );
+        assert_eq!(resolve(&base, "c").unwrap().to_string(), "https://t.co/a/c");
+        assert_eq!(
+            resolve(&base, "https://other.example/")
+                .unwrap()
+                .to_string(),
+            "https://other.example/"
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use crate::config::{Config, RefererPolicy, RefererRule};
+use crate::pattern::domain_matches;
+use hyper::{header, Body, Request, Uri};
+use log::debug;
+
+/// Applies Referrer-Policy style rules to the `Referer` and `Origin` headers.
+#[derive(Clone)]
+pub struct RefererFilter {
+    default_policy: RefererPolicy,
+    rules: Vec<RefererRule>,
+}
+
+impl RefererFilter {
+    /// Creates a new RefererFilter.
+    ///
+    /// When no `referer.default_policy` is configured, the legacy
+    /// `fingerprint.strip_referer` flag selects between `strip` and `keep`.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg` - Application configuration.
+    pub fn new(cfg: &Config) -> Self {
+        let legacy = if cfg.fingerprint.strip_referer {
+            RefererPolicy::Strip
+        } else {
+            RefererPolicy::Keep
+        };
+        let default_policy = cfg.referer.default_policy.unwrap_or(legacy);
+        Self {
+            default_policy,
+            rules: cfg.referer.rules.clone(),
+        }
+    }
+
+    /// Returns the policy that applies to requests for the given host.
+    pub fn policy_for(&self, host: &str) -> RefererPolicy {
+        self.rules
+            .iter()
+            .find(|r| domain_matches(host, &r.domain))
+            .map(|r| r.policy)
+            .unwrap_or(self.default_policy)
+    }
+
+    /// Applies the policy for `host` to the request's `Referer` and `Origin` headers.
+    ///
+    /// `Origin` only carries an origin, so it is removed by the stripping policies
+    /// and left untouched by the trimming ones.
+    ///
+    /// Returns `true` if any header was modified.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The mutable HTTP request.
+    /// * `host` - The hostname of the request.
+    pub fn apply(&self, req: &mut Request<Body>, host: &str) -> bool {
+        let policy = self.policy_for(host);
+        if policy == RefererPolicy::Keep {
+            return false;
+        }
+
+        let target = origin_of(req.uri());
+        let mut changed = false;
+
+        let referer = req
+            .headers()
+            .get(header::REFERER)
+            .and_then(|v| v.to_str().ok())
+            .map(|s| s.to_string());
+        if let Some(referer) = referer {
+            let referer_origin = referer.parse::<Uri>().ok().and_then(|u| origin_of(&u));
+            let cross_origin = referer_origin.is_none() || referer_origin != target;
+
+            let action = match policy {
+                RefererPolicy::Strip => Some(None),
+                RefererPolicy::StripCrossOrigin if cross_origin => Some(None),
+                RefererPolicy::Origin => Some(referer_origin),
+                RefererPolicy::TrimCrossOrigin if cross_origin => Some(referer_origin),
+                _ => None,
+            };
+
+            match action {
+                Some(Some(origin)) => {
+                    let trimmed = format!("{}/", origin);
+                    if trimmed != referer {
+                        if let Ok(value) = header::HeaderValue::from_str(&trimmed) {
+                            req.headers_mut().insert(header::REFERER, value);
+                            changed = true;
+                        }
+                    }
+                }
+                Some(None) => {
+                    req.headers_mut().remove(header::REFERER);
+                    changed = true;
+                }
+                None => {}
+            }
+        }
+
+        let origin = req
+            .headers()
+            .get(header::ORIGIN)
+            .and_then(|v| v.to_str().ok())
+            .map(|s| s.to_string());
+        if let Some(origin) = origin {
+            let cross_origin = Some(origin) != target;
+            let strip = match policy {
+                RefererPolicy::Strip => true,
+                RefererPolicy::StripCrossOrigin => cross_origin,
+                _ => false,
+            };
+            if strip {
+                req.headers_mut().remove(header::ORIGIN);
+                changed = true;
+            }
+        }
+
+        if changed {
+            debug!("Applied referer policy {:?} for {}", policy, host);
+        }
+        changed
+    }
+}
+
+/// Serializes the origin (`scheme://host[:port]`) of a URI, omitting default ports.
+///
+/// Returns None if the URI has no host.
+fn origin_of(uri: &Uri) -> Option<String> {
+    let host = uri.host()?;
+    let scheme = uri.scheme_str().unwrap_or("http");
+    let origin = match (scheme, uri.port_u16()) {
+        ("http", Some(80)) | ("https", Some(443)) | (_, None) => format!("{}://{}", scheme, host),
+        (_, Some(port)) => format!("{}://{}:{}", scheme, host, port),
+    };
+    Some(origin.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(default_policy: RefererPolicy, rules: Vec<RefererRule>) -> RefererFilter {
+        RefererFilter {
+            default_policy,
+            rules,
+        }
+    }
+
+    fn request(uri: &str, referer: &str) -> Request<Body> {
+        let mut req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        req.headers_mut()
+            .insert(header::REFERER, referer.parse().unwrap());
+        req
+    }
+
+    fn referer(req: &Request<Body>) -> Option<&str> {
+        req.headers()
+            .get(header::REFERER)
+            .map(|v| v.to_str().unwrap())
+    }
+
+    #[test]
+    fn test_strip_cross_origin() {
+        let f = filter(RefererPolicy::StripCrossOrigin, vec![]);
+
+        let mut same = request("https://example.com/a", "https://example.com/page?q=1");
+        assert!(!f.apply(&mut same, "example.com"));
+        assert_eq!(referer(&same), Some("https://example.com/page?q=1"));
+
+        let mut cross = request("https://cdn.other.com/img.png", "https://example.com/page");
+        assert!(f.apply(&mut cross, "cdn.other.com"));
+        assert_eq!(referer(&cross), None);
+    }
+
+    #[test]
+    fn test_trim_cross_origin() {
+        let f = filter(RefererPolicy::TrimCrossOrigin, vec![]);
+
+        let mut same = request("https://example.com/a", "https://example.com/page");
+        f.apply(&mut same, "example.com");
+        assert_eq!(referer(&same), Some("https://example.com/page"));
+
+        let mut cross = request(
+            "https://cdn.other.com/x",
+            "https://example.com:8443/page?id=7",
+        );
+        assert!(f.apply(&mut cross, "cdn.other.com"));
+        assert_eq!(referer(&cross), Some("https://example.com:8443/"));
+    }
+
+    #[test]
+    fn test_origin_and_strip() {
+        let f = filter(RefererPolicy::Origin, vec![]);
+        let mut req = request("https://example.com/a", "https://example.com/secret/path");
+        f.apply(&mut req, "example.com");
+        assert_eq!(referer(&req), Some("https://example.com/"));
+
+        let f = filter(RefererPolicy::Strip, vec![]);
+        let mut req = request("https://example.com/a", "https://example.com/page");
+        req.headers_mut()
+            .insert(header::ORIGIN, "https://example.com".parse().unwrap());
+        assert!(f.apply(&mut req, "example.com"));
+        assert_eq!(referer(&req), None);
+        assert!(req.headers().get(header::ORIGIN).is_none());
+    }
+
+    #[test]
+    fn test_origin_header_cross_origin_only() {
+        let f = filter(RefererPolicy::StripCrossOrigin, vec![]);
+
+        let mut same = Request::builder()
+            .uri("https://example.com:443/api")
+            .header(header::ORIGIN, "https://example.com")
+            .body(Body::empty())
+            .unwrap();
+        f.apply(&mut same, "example.com");
+        assert!(same.headers().get(header::ORIGIN).is_some());
+
+        let mut cross = Request::builder()
+            .uri("https://api.other.com/v1")
+            .header(header::ORIGIN, "https://example.com")
+            .body(Body::empty())
+            .unwrap();
+        f.apply(&mut cross, "api.other.com");
+        assert!(cross.headers().get(header::ORIGIN).is_none());
+    }
+
+    #[test]
+    fn test_per_destination_rule() {
+        let f = filter(
+            RefererPolicy::Strip,
+            vec![RefererRule {
+                domain: "images.example.net".to_string(),
+                policy: RefererPolicy::Keep,
+            }],
+        );
+        assert_eq!(f.policy_for("images.example.net"), RefererPolicy::Keep);
+        assert_eq!(f.policy_for("example.net"), RefererPolicy::Strip);
+
+        let mut req = request(
+            "https://images.example.net/a.jpg",
+            "https://blog.example.com/",
+        );
+        assert!(!f.apply(&mut req, "images.example.net"));
+        assert_eq!(referer(&req), Some("https://blog.example.com/"));
+    }
+
+    #[test]
+    fn test_shipped_config_keeps_stripping() {
+        let cfg: Config = serde_yaml::from_str(include_str!("../config.yaml")).unwrap();
+        assert!(cfg.fingerprint.strip_referer);
+        assert_eq!(
+            RefererFilter::new(&cfg).default_policy,
+            RefererPolicy::Strip
+        );
+    }
+
+    #[test]
+    fn test_rule_matches_domain_boundaries() {
+        let f = filter(
+            RefererPolicy::Strip,
+            vec![RefererRule {
+                domain: "example.net".to_string(),
+                policy: RefererPolicy::Keep,
+            }],
+        );
+        assert_eq!(f.policy_for("example.net"), RefererPolicy::Keep);
+        assert_eq!(f.policy_for("cdn.example.net"), RefererPolicy::Keep);
+        assert_eq!(f.policy_for("badexample.net"), RefererPolicy::Strip);
+    }
+}
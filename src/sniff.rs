@@ -0,0 +1,433 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+/// How long to wait for the first bytes of a stream before treating it as a
+/// protocol where the server speaks first.
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Largest TLS record, which bounds the part of a ClientHello looked at.
+const MAX_RECORD_SIZE: usize = 5 + 16 * 1024;
+
+/// Encrypted records up to this size are alerts rather than requests: an
+/// alert is 2 bytes plus content type and a 16-byte tag.
+const MAX_ALERT_RECORD: usize = 24;
+
+/// Request methods recognised at the start of a plain HTTP stream.
+const HTTP_METHODS: [&[u8]; 8] = [
+    b"GET ",
+    b"POST ",
+    b"HEAD ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"TRACE ",
+];
+
+/// What a stream turned out to carry.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Sniffed {
+    /// A TLS handshake, with the server name the client asked for.
+    Tls { server_name: Option<String> },
+    /// A plain HTTP request.
+    Http,
+    /// Anything else, including streams where the client stays silent.
+    Other,
+}
+
+/// Looks at the first bytes of a stream without consuming them.
+///
+/// For TLS the whole first record is awaited, so the server name can be taken
+/// from the ClientHello.
+pub async fn sniff(stream: &TcpStream) -> io::Result<Sniffed> {
+    let deadline = Instant::now() + SNIFF_TIMEOUT;
+    let mut buf = vec![0; MAX_RECORD_SIZE];
+    loop {
+        let n = match tokio::time::timeout_at(deadline, stream.peek(&mut buf)).await {
+            Ok(n) => n?,
+            Err(_) => return Ok(Sniffed::Other),
+        };
+        let data = &buf[..n];
+        if data.first() == Some(&0x16) {
+            let complete = record_len(data).is_some_and(|len| n >= len);
+            if !complete && n < buf.len() && Instant::now() < deadline {
+                // peek returns at once while data is buffered; give the rest
+                // of the record time to arrive
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                continue;
+            }
+            return Ok(Sniffed::Tls {
+                server_name: server_name(data),
+            });
+        }
+        if HTTP_METHODS.iter().any(|m| data.starts_with(m)) {
+            return Ok(Sniffed::Http);
+        }
+        return Ok(Sniffed::Other);
+    }
+}
+
+/// Length of the TLS record at the start of `data`, header included.
+fn record_len(data: &[u8]) -> Option<usize> {
+    let len = u16::from_be_bytes([*data.get(3)?, *data.get(4)?]);
+    Some(5 + len as usize)
+}
+
+/// Extracts the `server_name` extension (RFC 6066) from a TLS record holding
+/// a ClientHello.
+pub fn server_name(record: &[u8]) -> Option<String> {
+    let mut r = Reader(record);
+    // Record header: type, version, length
+    if r.u8()? != 0x16 {
+        return None;
+    }
+    r.skip(4)?;
+    // Handshake header: type, length
+    if r.u8()? != 0x01 {
+        return None;
+    }
+    r.skip(3)?;
+    // Version and random, then session id, cipher suites and compression
+    r.skip(2 + 32)?;
+    let len = r.u8()? as usize;
+    r.skip(len)?;
+    let len = r.u16()? as usize;
+    r.skip(len)?;
+    let len = r.u8()? as usize;
+    r.skip(len)?;
+
+    let len = r.u16()? as usize;
+    let mut extensions = Reader(r.take(len)?);
+    while let Some(kind) = extensions.u16() {
+        let len = extensions.u16()? as usize;
+        let data = extensions.take(len)?;
+        if kind != 0x0000 {
+            continue;
+        }
+        let mut names = Reader(data);
+        let len = names.u16()? as usize;
+        let mut names = Reader(names.take(len)?);
+        while let Some(name_type) = names.u8() {
+            let len = names.u16()? as usize;
+            let name = names.take(len)?;
+            if name_type == 0x00 {
+                return std::str::from_utf8(name)
+                    .ok()
+                    .map(|n| n.trim_end_matches('.').to_ascii_lowercase());
+            }
+        }
+        return None;
+    }
+    None
+}
+
+/// Follows the TLS records a client sends to tell whether it rejected the
+/// handshake with an alert, as clients do when they reject a certificate.
+///
+/// Only record headers are looked at. TLS 1.3 encrypts the client's
+/// Finished and alerts alike, so there only a short first encrypted record
+/// counts as an alert; later short records are close_notify.
+#[derive(Debug)]
+pub struct HandshakeWatch {
+    header: Vec<u8>,
+    /// Body bytes of the current record still to skip.
+    remaining: usize,
+    /// Whether the stream started with a TLS handshake record.
+    tls: Option<bool>,
+    change_cipher_spec: bool,
+    /// A handshake record after ChangeCipherSpec: TLS 1.2's Finished.
+    tls12: bool,
+    encrypted: usize,
+    alert: bool,
+    application_data: bool,
+}
+
+impl Default for HandshakeWatch {
+    fn default() -> Self {
+        Self {
+            header: Vec::with_capacity(5),
+            remaining: 0,
+            tls: None,
+            change_cipher_spec: false,
+            tls12: false,
+            encrypted: 0,
+            alert: false,
+            application_data: false,
+        }
+    }
+}
+
+impl HandshakeWatch {
+    /// Feeds bytes sent by the client.
+    pub fn feed(&mut self, mut data: &[u8]) {
+        while !data.is_empty() && self.tls != Some(false) && !self.application_data {
+            if self.remaining > 0 {
+                let skip = self.remaining.min(data.len());
+                self.remaining -= skip;
+                data = &data[skip..];
+                continue;
+            }
+            let take = (5 - self.header.len()).min(data.len());
+            self.header.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.header.len() == 5 {
+                let len = u16::from_be_bytes([self.header[3], self.header[4]]) as usize;
+                self.record(self.header[0], len);
+                self.remaining = len;
+                self.header.clear();
+            }
+        }
+    }
+
+    fn record(&mut self, kind: u8, len: usize) {
+        if self.tls.is_none() {
+            self.tls = Some(kind == 0x16);
+        }
+        match kind {
+            0x14 => self.change_cipher_spec = true,
+            // After ChangeCipherSpec the client has finished its side of
+            // the handshake, and an alert is close_notify
+            0x15 => self.alert |= !self.change_cipher_spec,
+            0x16 if self.change_cipher_spec => self.tls12 = true,
+            0x17 => {
+                self.encrypted += 1;
+                if self.tls12 {
+                    self.application_data = true;
+                } else if len <= MAX_ALERT_RECORD {
+                    self.alert |= self.encrypted == 1;
+                } else if self.encrypted > 1 {
+                    self.application_data = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the client, now done with the stream, rejected the TLS
+    /// handshake: it sent an alert and no application data. Closing the
+    /// connection without one, as browsers do with unused preconnects, does
+    /// not count.
+    pub fn handshake_failed(&self) -> bool {
+        self.tls == Some(true) && self.alert && !self.application_data
+    }
+}
+
+/// Client stream wrapper feeding what is read from it to a
+/// `HandshakeWatch`.
+pub struct Watched<S> {
+    inner: S,
+    pub watch: HandshakeWatch,
+}
+
+impl<S> Watched<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            watch: HandshakeWatch::default(),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Watched<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.watch.feed(&buf.filled()[before..]);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Watched<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Cursor over a byte slice; every read fails once the data runs out.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    fn with_len16(body: &[u8]) -> Vec<u8> {
+        let mut out = (body.len() as u16).to_be_bytes().to_vec();
+        out.extend_from_slice(body);
+        out
+    }
+
+    /// A minimal ClientHello record with `extensions`.
+    fn client_hello(extensions: &[u8]) -> Vec<u8> {
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[7; 32]);
+        hello.push(0);
+        hello.extend_from_slice(&with_len16(&[0x13, 0x01]));
+        hello.extend_from_slice(&[1, 0]);
+        hello.extend_from_slice(&with_len16(extensions));
+
+        let len = (hello.len() as u32).to_be_bytes();
+        let mut handshake = vec![0x01, len[1], len[2], len[3]];
+        handshake.extend_from_slice(&hello);
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&with_len16(&handshake));
+        record
+    }
+
+    fn sni_extension(host: &str) -> Vec<u8> {
+        let mut name = vec![0x00];
+        name.extend_from_slice(&with_len16(host.as_bytes()));
+        let mut extension = vec![0x00, 0x00];
+        extension.extend_from_slice(&with_len16(&with_len16(&name)));
+        extension
+    }
+
+    #[test]
+    fn test_server_name() {
+        // supported_versions ahead of server_name
+        let mut extensions = vec![0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04];
+        extensions.extend_from_slice(&sni_extension("WWW.Example.com"));
+        assert_eq!(
+            server_name(&client_hello(&extensions)),
+            Some("www.example.com".to_string())
+        );
+
+        assert_eq!(server_name(&client_hello(&[])), None);
+        let record = client_hello(&sni_extension("example.com"));
+        assert_eq!(server_name(&record[..record.len() - 4]), None);
+        assert_eq!(server_name(b"GET / HTTP/1.1\r\n"), None);
+    }
+
+    fn record(kind: u8, len: usize) -> Vec<u8> {
+        let mut record = vec![kind, 0x03, 0x03];
+        record.extend_from_slice(&with_len16(&vec![0; len]));
+        record
+    }
+
+    #[test]
+    fn test_handshake_watch() {
+        let hello = client_hello(&sni_extension("example.com"));
+        let watch = |records: &[Vec<u8>]| {
+            let mut watch = HandshakeWatch::default();
+            // Records split at arbitrary points
+            for chunk in records.concat().chunks(7) {
+                watch.feed(chunk);
+            }
+            watch
+        };
+
+        // TLS 1.3: Finished, then a request
+        let ok13 = [
+            hello.clone(),
+            record(0x14, 1),
+            record(0x17, 53),
+            record(0x17, 300),
+        ];
+        assert!(!watch(&ok13).handshake_failed());
+        // TLS 1.3: encrypted bad_certificate alert
+        let alert13 = [hello.clone(), record(0x14, 1), record(0x17, 19)];
+        assert!(watch(&alert13).handshake_failed());
+        // TLS 1.3: handshake completed, then closed with or without
+        // close_notify
+        let closed13 = [hello.clone(), record(0x14, 1), record(0x17, 53)];
+        assert!(!watch(&closed13).handshake_failed());
+        let close_notify13 = [closed13.concat(), record(0x17, 19)];
+        assert!(!watch(&close_notify13).handshake_failed());
+        // Closed before answering the server at all
+        assert!(!watch(std::slice::from_ref(&hello)).handshake_failed());
+        // TLS 1.2: plaintext alert, or Finished followed by a request
+        assert!(watch(&[hello.clone(), record(0x15, 2)]).handshake_failed());
+        let ok12 = [
+            hello.clone(),
+            record(0x16, 70),
+            record(0x14, 1),
+            record(0x16, 40),
+            record(0x17, 29),
+        ];
+        assert!(!watch(&ok12).handshake_failed());
+        // TLS 1.2: Finished, then close_notify
+        let closed12 = [ok12[..4].concat(), record(0x15, 26)];
+        assert!(!watch(&closed12).handshake_failed());
+        // Not TLS
+        assert!(!watch(&[b"GET / HTTP/1.1\r\n\r\n".to_vec()]).handshake_failed());
+    }
+
+    #[tokio::test]
+    async fn test_sniff() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let record = client_hello(&sni_extension("example.com"));
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        // The ClientHello arrives in two parts
+        client.write_all(&record[..20]).await.unwrap();
+        let rest = record[20..].to_vec();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client.write_all(&rest).await.unwrap();
+            client
+        });
+        assert_eq!(
+            sniff(&server).await.unwrap(),
+            Sniffed::Tls {
+                server_name: Some("example.com".to_string())
+            }
+        );
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        client.write_all(b"OPTIONS * HTTP/1.1\r\n").await.unwrap();
+        assert_eq!(sniff(&server).await.unwrap(), Sniffed::Http);
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        client.write_all(b"SSH-2.0-OpenSSH_9.6\r\n").await.unwrap();
+        assert_eq!(sniff(&server).await.unwrap(), Sniffed::Other);
+    }
+}
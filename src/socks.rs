@@ -0,0 +1,279 @@
+use crate::auth::ProxyAuth;
+use log::warn;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const USER_PASS: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CONNECT: u8 = 0x01;
+
+const SUCCEEDED: u8 = 0x00;
+const NOT_ALLOWED: u8 = 0x02;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// A SOCKS5 CONNECT request accepted from a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocksRequest {
+    /// Destination host name or IP address.
+    pub host: String,
+    /// Destination port.
+    pub port: u16,
+    /// Authenticated proxy user, if authentication is enabled.
+    pub user: Option<String>,
+}
+
+/// Runs the server side of a SOCKS5 handshake (RFC 1928) and confirms the
+/// CONNECT request, so the client starts sending its stream.
+///
+/// With proxy authentication enabled clients must use username/password
+/// authentication (RFC 1929). Requests for which `refuse` returns true get a
+/// "connection not allowed by ruleset" reply. Returns None once a client has
+/// been turned away with the matching reply.
+///
+/// # Arguments
+///
+/// * `stream` - Client connection.
+/// * `client` - Client address, for logging.
+/// * `auth` - Proxy authentication.
+/// * `refuse` - Decides whether the destination is blocked.
+pub async fn accept<S>(
+    stream: &mut S,
+    client: SocketAddr,
+    auth: &ProxyAuth,
+    refuse: impl AsyncFnOnce(&SocksRequest) -> bool,
+) -> io::Result<Option<SocksRequest>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut greeting = [0; 2];
+    stream.read_exact(&mut greeting).await?;
+    if greeting[0] != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a SOCKS5 client",
+        ));
+    }
+    let mut methods = vec![0; greeting[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    let method = if auth.is_enabled() {
+        USER_PASS
+    } else {
+        NO_AUTH
+    };
+    if !methods.contains(&method) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHOD]).await?;
+        return Ok(None);
+    }
+    stream.write_all(&[VERSION, method]).await?;
+
+    let mut user = None;
+    if method == USER_PASS {
+        // VER ULEN UNAME PLEN PASSWD
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).await?;
+        let mut name = vec![0; header[1] as usize];
+        stream.read_exact(&mut name).await?;
+        let mut len = [0; 1];
+        stream.read_exact(&mut len).await?;
+        let mut password = vec![0; len[0] as usize];
+        stream.read_exact(&mut password).await?;
+        let name = String::from_utf8_lossy(&name);
+        user = auth
+            .authenticate_password(client.ip(), &name, &String::from_utf8_lossy(&password))
+            .await;
+        if user.is_none() {
+            warn!("SOCKS5 authentication failed for {}", client);
+            stream.write_all(&[0x01, 0x01]).await?;
+            return Ok(None);
+        }
+        stream.write_all(&[0x01, 0x00]).await?;
+    }
+
+    // VER CMD RSV ATYP
+    let mut request = [0; 4];
+    stream.read_exact(&mut request).await?;
+    if request[1] != CONNECT {
+        reply(stream, COMMAND_NOT_SUPPORTED).await?;
+        return Ok(None);
+    }
+    let host = match request[3] {
+        0x01 => {
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        0x03 => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len).await?;
+            let mut name = vec![0; len[0] as usize];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8(name)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid host name"))?
+        }
+        0x04 => {
+            let mut ip = [0; 16];
+            stream.read_exact(&mut ip).await?;
+            Ipv6Addr::from(ip).to_string()
+        }
+        _ => {
+            reply(stream, ADDRESS_NOT_SUPPORTED).await?;
+            return Ok(None);
+        }
+    };
+    let mut port = [0; 2];
+    stream.read_exact(&mut port).await?;
+    let request = SocksRequest {
+        host,
+        port: u16::from_be_bytes(port),
+        user,
+    };
+    if refuse(&request).await {
+        reply(stream, NOT_ALLOWED).await?;
+        return Ok(None);
+    }
+    reply(stream, SUCCEEDED).await?;
+    Ok(Some(request))
+}
+
+/// Sends a reply to the CONNECT request, with an unspecified bound address.
+async fn reply<S: AsyncWrite + Unpin>(stream: &mut S, code: u8) -> io::Result<()> {
+    stream
+        .write_all(&[VERSION, code, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client() -> SocketAddr {
+        "127.0.0.1:40000".parse().unwrap()
+    }
+
+    async fn refuse_none(_: &SocksRequest) -> bool {
+        false
+    }
+
+    /// Greeting without authentication and a CONNECT to `host:443`.
+    fn request_for(host: &str) -> Vec<u8> {
+        let mut request = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x03, host.len() as u8];
+        request.extend_from_slice(host.as_bytes());
+        request.extend_from_slice(&443u16.to_be_bytes());
+        request
+    }
+
+    #[tokio::test]
+    async fn test_accept_connect_request() {
+        let auth = ProxyAuth::from_users(false, "test", Vec::new()).unwrap();
+        let (mut server, mut socks_client) = tokio::io::duplex(1024);
+        socks_client
+            .write_all(&request_for("example.com"))
+            .await
+            .unwrap();
+
+        let accepted = accept(&mut server, client(), &auth, refuse_none)
+            .await
+            .unwrap();
+        assert_eq!(
+            accepted,
+            Some(SocksRequest {
+                host: "example.com".to_string(),
+                port: 443,
+                user: None,
+            })
+        );
+        let mut replies = [0; 12];
+        socks_client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(&replies[..4], [0x05, 0x00, 0x05, 0x00]);
+
+        // UDP ASSOCIATE is refused
+        let (mut server, mut socks_client) = tokio::io::duplex(1024);
+        let request = [
+            0x05, 0x01, 0x00, 0x05, 0x03, 0x00, 0x01, 127, 0, 0, 1, 0, 53,
+        ];
+        socks_client.write_all(&request).await.unwrap();
+        assert_eq!(
+            accept(&mut server, client(), &auth, refuse_none)
+                .await
+                .unwrap(),
+            None
+        );
+        let mut replies = [0; 4];
+        socks_client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies, [0x05, 0x00, 0x05, COMMAND_NOT_SUPPORTED]);
+
+        // Blocked destinations are refused before the stream starts
+        let (mut server, mut socks_client) = tokio::io::duplex(1024);
+        socks_client
+            .write_all(&request_for("tracker.example"))
+            .await
+            .unwrap();
+        let refuse = async |r: &SocksRequest| r.host == "tracker.example";
+        assert_eq!(
+            accept(&mut server, client(), &auth, refuse).await.unwrap(),
+            None
+        );
+        let mut replies = [0; 4];
+        socks_client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies, [0x05, 0x00, 0x05, NOT_ALLOWED]);
+    }
+
+    #[tokio::test]
+    async fn test_accept_requires_credentials() {
+        let users = vec![(
+            "alice".to_string(),
+            "{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=".to_string(),
+        )];
+        let auth = ProxyAuth::from_users(true, "Lab", users).unwrap();
+
+        // Clients offering only anonymous access are turned away
+        let (mut server, mut socks_client) = tokio::io::duplex(1024);
+        socks_client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        assert_eq!(
+            accept(&mut server, client(), &auth, refuse_none)
+                .await
+                .unwrap(),
+            None
+        );
+        let mut replies = [0; 2];
+        socks_client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies, [0x05, NO_ACCEPTABLE_METHOD]);
+
+        let mut request = vec![0x05, 0x01, 0x02, 0x01, 5];
+        request.extend_from_slice(b"alice");
+        request.push(8);
+        request.extend_from_slice(b"password");
+        request.extend_from_slice(&[0x05, 0x01, 0x00, 0x04]);
+        request.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        request.extend_from_slice(&80u16.to_be_bytes());
+        let (mut server, mut socks_client) = tokio::io::duplex(1024);
+        socks_client.write_all(&request).await.unwrap();
+        let accepted = accept(&mut server, client(), &auth, refuse_none)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(accepted.host, "::1");
+        assert_eq!(accepted.port, 80);
+        assert_eq!(accepted.user.as_deref(), Some("alice"));
+
+        let mut request = vec![0x05, 0x01, 0x02, 0x01, 5];
+        request.extend_from_slice(b"alice");
+        request.push(5);
+        request.extend_from_slice(b"wrong");
+        let (mut server, mut socks_client) = tokio::io::duplex(1024);
+        socks_client.write_all(&request).await.unwrap();
+        assert_eq!(
+            accept(&mut server, client(), &auth, refuse_none)
+                .await
+                .unwrap(),
+            None
+        );
+        let mut replies = [0; 4];
+        socks_client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies, [0x05, USER_PASS, 0x01, 0x01]);
+    }
+}